@@ -0,0 +1,256 @@
+use crate::cli::ImportAppleHealthArgs;
+use crate::export::{Export, ExportOptions, Layout};
+use crate::file_import::{activity_from_name, activity_kind};
+use crate::gpx::{attribute, parse_f64};
+use crate::ingest::{
+    connect_or_create_db, ensure_pg_schema, import_gpx_file, refresh_workout_distance_matview,
+    upsert_workout,
+};
+use crate::types::{SOURCE_APPLE_HEALTH, WorkoutSummary};
+use crate::utils::summary_json;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, SecondsFormat, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::io::{BufReader, Read};
+
+const EXPORT_XML: &str = "export.xml";
+
+/// One `<Workout>` (an `HKWorkout`) of `export.xml`.
+#[derive(Debug, Default)]
+struct HealthWorkout {
+    /// `workoutActivityType` without its `HKWorkoutActivityType` prefix.
+    activity_type: String,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    source_name: Option<String>,
+    indoor: bool,
+    /// Active time, pauses excluded.
+    duration_s: Option<f64>,
+    distance_m: Option<f64>,
+    energy_kcal: Option<f64>,
+    avg_hr: Option<f64>,
+    max_hr: Option<f64>,
+    /// Export-relative path of the route's GPX, if recorded with GPS.
+    route: Option<String>,
+}
+
+/// Import the workouts of an Apple Health export (`export.zip` from the
+/// Health app, or its extracted `apple_health_export/`) with their
+/// `workout-routes/*.gpx`, offline.
+///
+/// Apple Health has no workout ids; each workout is keyed on the app that
+/// recorded it, its activity type and its start time (`external_id`, with
+/// `source = 'apple_health'`), so a newer export updates it. Workouts several
+/// apps recorded at once (the Watch, a phone app, Strava syncing into Health)
+/// stay apart.
+pub fn run(pg_url: &str, export_opts: &ExportOptions, args: &ImportAppleHealthArgs) -> Result<()> {
+    let archive = Export::open_as(&args.archive, export_opts, Layout::AppleHealth)?;
+    let workouts = archive
+        .with_file(EXPORT_XML, |r| read_workouts(r))
+        .with_context(|| format!("reading {EXPORT_XML}"))?;
+    tracing::info!(workouts = workouts.len(), "found apple health workouts");
+
+    let mut pg = connect_or_create_db(pg_url)?;
+    ensure_pg_schema(&mut pg)?;
+
+    let mut workouts_upserted = 0usize;
+    let mut workouts_with_points = 0usize;
+    let mut points_imported = 0usize;
+    let mut routes_missing = 0usize;
+    let mut routes_failed = 0usize;
+
+    for w in workouts {
+        let (Some(start), Some(end)) = (w.start, w.end) else {
+            tracing::warn!(activity = %w.activity_type, "skipping apple health workout without start or end");
+            continue;
+        };
+
+        let mut activity = activity_from_name(&w.activity_type);
+        if w.indoor && activity == "outdoor_running" {
+            activity = "treadmill".to_owned();
+        }
+
+        let route = w.route.filter(|rel| {
+            let found = archive.contains(rel);
+            if !found {
+                tracing::warn!(path = %rel, %start, "workout route is missing from the export");
+                routes_missing += 1;
+            }
+            found
+        });
+
+        let summary_data_json = summary_json(&[
+            ("distanceMeters", w.distance_m, "meters"),
+            ("activeSeconds", w.duration_s, "seconds"),
+            ("caloriesBurnt", w.energy_kcal, "calories_unit"),
+            ("averageHR", w.avg_hr, "bpm"),
+            ("maxHR", w.max_hr, "bpm"),
+        ]);
+        let external_id = format!(
+            "{}|{}|{}",
+            w.source_name.as_deref().unwrap_or_default(),
+            w.activity_type,
+            start.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        );
+
+        let summary = WorkoutSummary {
+            name: w.source_name,
+            activity_kind: activity_kind(&activity),
+            gpx_track_android: route.clone(),
+            summary_data_raw: summary_data_json.as_ref().map(ToString::to_string),
+            summary_data_json,
+            external_id: Some(external_id),
+            ..WorkoutSummary::standalone(SOURCE_APPLE_HEALTH, start, end)
+        };
+
+        let workout_id = upsert_workout(&mut pg, &summary, &activity)?;
+        workouts_upserted += 1;
+
+        if let Some(rel) = route {
+            match import_gpx_file(&mut pg, &archive, &rel, workout_id, Some((start, end))) {
+                Ok(gpx) => {
+                    if gpx.points > 0 {
+                        workouts_with_points += 1;
+                    }
+                    points_imported += gpx.points;
+                }
+                Err(e) => {
+                    tracing::warn!(path = %rel, %start, err = format!("{e:#}"), "importing workout route failed");
+                    routes_failed += 1;
+                }
+            }
+        }
+    }
+
+    refresh_workout_distance_matview(&mut pg)?;
+    tracing::info!(
+        workouts_upserted,
+        workouts_with_points,
+        points_imported,
+        routes_missing,
+        routes_failed,
+        "apple health import done"
+    );
+    Ok(())
+}
+
+/// Stream `export.xml` for its `<Workout>` elements. The file is mostly
+/// `<Record>` samples (often gigabytes of them), which are skipped as they
+/// are read.
+fn read_workouts(r: &mut dyn Read) -> Result<Vec<HealthWorkout>> {
+    let mut xml = Reader::from_reader(BufReader::new(r));
+    let mut buf = Vec::new();
+    let mut out = Vec::new();
+    let mut cur: Option<HealthWorkout> = None;
+    let mut in_route = false;
+
+    loop {
+        buf.clear();
+        let (e, empty) = match xml.read_event_into(&mut buf) {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => (e, false),
+            Ok(Event::Empty(e)) => (e, true),
+            Ok(Event::End(e)) => {
+                match e.local_name().as_ref() {
+                    b"Workout" => out.extend(cur.take()),
+                    b"WorkoutRoute" => in_route = false,
+                    _ => {}
+                }
+                continue;
+            }
+            Err(e) => bail!(
+                "export.xml parse error at byte {}: {e}",
+                xml.buffer_position()
+            ),
+            _ => continue,
+        };
+
+        match (e.local_name().as_ref(), cur.as_mut()) {
+            (b"Workout", _) => {
+                let w = start_workout(&e);
+                if empty {
+                    out.push(w);
+                } else {
+                    cur = Some(w);
+                }
+            }
+            (b"WorkoutRoute", Some(_)) => in_route = !empty,
+            (b"FileReference", Some(w)) if in_route => {
+                w.route = attribute(&e, b"path").map(|p| p.trim_start_matches('/').to_owned());
+            }
+            (b"MetadataEntry", Some(w))
+                if !in_route && attribute(&e, b"key").as_deref() == Some("HKIndoorWorkout") =>
+            {
+                w.indoor = attribute(&e, b"value").as_deref() == Some("1");
+            }
+            (b"WorkoutStatistics", Some(w)) => apply_statistics(w, &e),
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+fn start_workout(e: &BytesStart<'_>) -> HealthWorkout {
+    let activity_type = attribute(e, b"workoutActivityType").unwrap_or_default();
+    HealthWorkout {
+        activity_type: activity_type
+            .strip_prefix("HKWorkoutActivityType")
+            .unwrap_or(&activity_type)
+            .to_owned(),
+        start: attribute(e, b"startDate").and_then(|s| parse_health_date(&s)),
+        end: attribute(e, b"endDate").and_then(|s| parse_health_date(&s)),
+        source_name: attribute(e, b"sourceName"),
+        duration_s: quantity(e, b"duration", b"durationUnit"),
+        // Older exports only; since iOS 16 these are `WorkoutStatistics`.
+        distance_m: quantity(e, b"totalDistance", b"totalDistanceUnit"),
+        energy_kcal: quantity(e, b"totalEnergyBurned", b"totalEnergyBurnedUnit"),
+        ..HealthWorkout::default()
+    }
+}
+
+fn apply_statistics(w: &mut HealthWorkout, e: &BytesStart<'_>) {
+    let Some(kind) = attribute(e, b"type") else {
+        return;
+    };
+    let kind = kind
+        .strip_prefix("HKQuantityTypeIdentifier")
+        .unwrap_or(&kind);
+    match kind {
+        "HeartRate" => {
+            w.avg_hr = attribute(e, b"average").and_then(|s| parse_f64(&s));
+            w.max_hr = attribute(e, b"maximum").and_then(|s| parse_f64(&s));
+        }
+        "ActiveEnergyBurned" => {
+            w.energy_kcal = w.energy_kcal.or_else(|| quantity(e, b"sum", b"unit"));
+        }
+        k if k.starts_with("Distance") => {
+            w.distance_m = w.distance_m.or_else(|| quantity(e, b"sum", b"unit"));
+        }
+        _ => {}
+    }
+}
+
+/// The attribute `value` in meters, seconds or kilocalories, converted from
+/// the unit named by the attribute `unit`.
+fn quantity(e: &BytesStart<'_>, value: &[u8], unit: &[u8]) -> Option<f64> {
+    let v = attribute(e, value).and_then(|s| parse_f64(&s))?;
+    let factor = match attribute(e, unit).as_deref() {
+        Some("km") => 1000.0,
+        Some("mi") => 1609.344,
+        Some("yd") => 0.9144,
+        Some("ft") => 0.3048,
+        Some("min") => 60.0,
+        Some("hr") => 3600.0,
+        Some("kJ") => 1.0 / 4.184,
+        _ => 1.0, // m, s, kcal (Cal)
+    };
+    Some(v * factor)
+}
+
+/// Health dates are local times with their offset: `2024-05-01 07:30:12 +0200`.
+fn parse_health_date(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S %z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
@@ -0,0 +1,287 @@
+use crate::cli::AttachHrArgs;
+use crate::database::{ActivitySample, open_database, read_activity_samples};
+use crate::export::{Export, ExportOptions};
+use crate::ingest::{connect_or_create_db, ensure_pg_schema};
+use crate::stored::{WorkoutFilter, read_points, select_workouts};
+use crate::types::GpxPoint;
+use crate::utils::{haversine_m, interpolate_hr};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use postgres::Client;
+use std::collections::HashMap;
+
+/// Heart rate is interpolated between samples at most this far apart: one
+/// missing minute of Gadgetbridge's usual one-minute samples.
+const MAX_HR_GAP: TimeDelta = TimeDelta::seconds(120);
+
+/// Offsets tried by `--align`, in seconds.
+const ALIGN_STEP_S: usize = 5;
+
+/// Fewer samples with steps than this and `--align` keeps `--offset`.
+const MIN_ALIGN_SAMPLES: usize = 10;
+
+/// Below this correlation between steps and distance `--align` keeps `--offset`.
+const MIN_ALIGN_CORRELATION: f64 = 0.6;
+
+/// Samples read beyond the offsets tried, on each side of a workout.
+const SAMPLE_MARGIN: TimeDelta = TimeDelta::minutes(10);
+
+/// Lay the heart rate a watch measured continuously (Gadgetbridge's sample
+/// tables) onto the points of workouts tracked without it, typically runs
+/// recorded on the phone.
+///
+/// Sample times are shifted by `--offset`, the watch clock's lead on the
+/// phone's; `--align` estimates it per workout instead, from when the watch
+/// counted steps and the track moved. Only workouts whose points have no heart
+/// rate are updated, unless one is named with `--workout`.
+pub fn run(pg_url: &str, export_opts: &ExportOptions, args: &AttachHrArgs) -> Result<()> {
+    let export = Export::open(&args.export, export_opts)?;
+    let Some(conn) = open_database(&export)? else {
+        bail!("{} has no Gadgetbridge database", args.export.display());
+    };
+
+    let mut pg = connect_or_create_db(pg_url)?;
+    ensure_pg_schema(&mut pg)?;
+
+    let filter = WorkoutFilter {
+        workout: args.workout.clone(),
+        since: args.since,
+        ..WorkoutFilter::default()
+    };
+    let workouts = select_workouts(&mut pg, &filter)?;
+
+    let mut workouts_updated = 0usize;
+    let mut workouts_without_samples = 0usize;
+    let mut points_with_hr = 0usize;
+
+    for w in &workouts {
+        let points = read_points(&mut pg, w.id)?;
+        if points.is_empty() || (args.workout.is_none() && points.iter().any(|p| p.hr.is_some())) {
+            continue;
+        }
+
+        let margin = TimeDelta::seconds(args.offset.abs() + args.max_offset.abs()) + SAMPLE_MARGIN;
+        let samples = read_activity_samples(&conn, w.start - margin, w.end + margin)
+            .context("Reading activity samples")?;
+        let Some(device_id) = args.device.or_else(|| busiest_device(&samples)) else {
+            tracing::info!(workout = w.id, start = %w.start, "no heart rate samples during workout");
+            workouts_without_samples += 1;
+            continue;
+        };
+        let samples: Vec<&ActivitySample> = samples
+            .iter()
+            .filter(|s| s.device_id == device_id)
+            .collect();
+
+        let offset_s = if args.align {
+            match estimate_offset(&points, &samples, args.offset, args.max_offset) {
+                Some((offset, r)) => {
+                    tracing::debug!(
+                        workout = w.id,
+                        offset_s = offset,
+                        correlation = r,
+                        "aligned watch clock"
+                    );
+                    offset
+                }
+                None => {
+                    tracing::warn!(
+                        workout = w.id,
+                        start = %w.start,
+                        offset_s = args.offset,
+                        "steps don't match the track, keeping --offset"
+                    );
+                    args.offset
+                }
+            }
+        } else {
+            args.offset
+        };
+
+        let offset = TimeDelta::seconds(offset_s);
+        let series: Vec<(DateTime<Utc>, i32)> = samples
+            .iter()
+            .filter_map(|s| Some((s.t - offset, s.hr?)))
+            .collect();
+        let hr: Vec<Option<i32>> = points
+            .iter()
+            .map(|p| interpolate_hr(&series, p.t, MAX_HR_GAP))
+            .collect();
+        let with_hr = hr.iter().flatten().count();
+        if with_hr == 0 {
+            tracing::info!(workout = w.id, start = %w.start, device_id, "no heart rate samples during workout");
+            workouts_without_samples += 1;
+            continue;
+        }
+
+        update_hr(&mut pg, w.id, &points, &hr)?;
+        tracing::info!(
+            workout = w.id,
+            start = %w.start,
+            device_id,
+            offset_s,
+            points = points.len(),
+            points_with_hr = with_hr,
+            "attached heart rate"
+        );
+        workouts_updated += 1;
+        points_with_hr += with_hr;
+    }
+
+    tracing::info!(
+        workouts_updated,
+        workouts_without_samples,
+        points_with_hr,
+        "attach-hr done"
+    );
+    Ok(())
+}
+
+/// The device with the most heart rate readings among `samples`.
+fn busiest_device(samples: &[ActivitySample]) -> Option<i64> {
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for s in samples.iter().filter(|s| s.hr.is_some()) {
+        *counts.entry(s.device_id).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(device, n)| (n, -device))
+        .map(|(device, _)| device)
+}
+
+/// The clock offset, in seconds within `max_offset` of `around`, at which the
+/// steps of each sample best correlate with the distance the track covered
+/// during it, with that correlation. `None` when too few samples have steps or
+/// the best fit is poor (steady pace all along, a bike ride).
+fn estimate_offset(
+    points: &[GpxPoint],
+    samples: &[&ActivitySample],
+    around: i64,
+    max_offset: i64,
+) -> Option<(i64, f64)> {
+    let track = CumulativeDistance::new(points);
+    let steps: Vec<(DateTime<Utc>, f64)> = samples
+        .iter()
+        .filter_map(|s| Some((s.t, f64::from(s.steps?))))
+        .collect();
+    if steps.len() < MIN_ALIGN_SAMPLES {
+        return None;
+    }
+    let interval = sample_interval(&steps);
+
+    let max_offset = max_offset.abs();
+    (around - max_offset..=around + max_offset)
+        .step_by(ALIGN_STEP_S)
+        .filter_map(|offset| {
+            let shift = TimeDelta::seconds(offset);
+            let distances: Vec<f64> = steps
+                .iter()
+                .map(|&(t, _)| track.between(t - shift, t - shift + interval))
+                .collect();
+            let r = correlation(steps.iter().map(|&(_, s)| s), distances.iter().copied())?;
+            Some((offset, r))
+        })
+        // Ties go to the offset closest to `around`.
+        .max_by(|a, b| {
+            a.1.total_cmp(&b.1)
+                .then_with(|| (b.0 - around).abs().cmp(&(a.0 - around).abs()))
+        })
+        .filter(|&(_, r)| r >= MIN_ALIGN_CORRELATION)
+}
+
+/// The usual time between samples: the median gap, one minute for most
+/// watches.
+fn sample_interval(steps: &[(DateTime<Utc>, f64)]) -> TimeDelta {
+    let mut gaps: Vec<TimeDelta> = steps
+        .windows(2)
+        .map(|w| w[1].0 - w[0].0)
+        .filter(|gap| *gap > TimeDelta::zero())
+        .collect();
+    gaps.sort_unstable();
+    gaps.get(gaps.len() / 2)
+        .copied()
+        .unwrap_or_else(|| TimeDelta::minutes(1))
+}
+
+/// Pearson correlation; `None` if either side is constant.
+fn correlation(xs: impl Iterator<Item = f64>, ys: impl Iterator<Item = f64>) -> Option<f64> {
+    let pairs: Vec<(f64, f64)> = xs.zip(ys).collect();
+    let n = pairs.len() as f64;
+    let (mx, my) = pairs
+        .iter()
+        .fold((0.0, 0.0), |(sx, sy), &(x, y)| (sx + x / n, sy + y / n));
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for &(x, y) in &pairs {
+        sxy += (x - mx) * (y - my);
+        sxx += (x - mx) * (x - mx);
+        syy += (y - my) * (y - my);
+    }
+    (sxx > 0.0 && syy > 0.0).then(|| sxy / (sxx * syy).sqrt())
+}
+
+/// Distance covered along a track by a given time, pauses between segments
+/// left out.
+struct CumulativeDistance {
+    /// `(time, meters since the first point)`, by time.
+    marks: Vec<(DateTime<Utc>, f64)>,
+}
+
+impl CumulativeDistance {
+    fn new(points: &[GpxPoint]) -> Self {
+        let mut sorted: Vec<&GpxPoint> = points.iter().collect();
+        sorted.sort_by_key(|p| (p.t, p.idx));
+
+        let mut marks = Vec::with_capacity(sorted.len());
+        let mut total = 0.0;
+        let mut prev: Option<&GpxPoint> = None;
+        for p in sorted {
+            if let Some(q) = prev
+                && (q.trk, q.seg) == (p.trk, p.seg)
+            {
+                total += haversine_m(q.lat, q.lon, p.lat, p.lon);
+            }
+            marks.push((p.t, total));
+            prev = Some(p);
+        }
+        Self { marks }
+    }
+
+    fn at(&self, t: DateTime<Utc>) -> f64 {
+        let i = self.marks.partition_point(|&(mt, _)| mt <= t);
+        match (i.checked_sub(1).map(|i| self.marks[i]), self.marks.get(i)) {
+            (None, _) => 0.0,
+            (Some((_, d)), None) => d,
+            (Some((t0, d0)), Some(&(t1, d1))) => {
+                let f = (t - t0).as_seconds_f64() / (t1 - t0).as_seconds_f64();
+                (d1 - d0).mul_add(f, d0)
+            }
+        }
+    }
+
+    fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+        self.at(to) - self.at(from)
+    }
+}
+
+/// Set the heart rate of a workout's points where `hr`, in `points` order, has one.
+fn update_hr(
+    pg: &mut Client,
+    workout_id: i64,
+    points: &[GpxPoint],
+    hr: &[Option<i32>],
+) -> Result<()> {
+    // Points the samples don't cover keep whatever heart rate they had.
+    let (idx, hr): (Vec<i32>, Vec<i32>) = points
+        .iter()
+        .zip(hr)
+        .filter_map(|(p, hr)| Some((p.idx, (*hr)?)))
+        .unzip();
+    pg.execute(
+        "UPDATE workout_points p SET hr = u.hr
+         FROM unnest($2::int[], $3::int[]) AS u(idx, hr)
+         WHERE p.workout_id = $1 AND p.idx = u.idx",
+        &[&workout_id, &idx, &hr],
+    )
+    .with_context(|| format!("Updating heart rate of workout {workout_id}"))?;
+    Ok(())
+}
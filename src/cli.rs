@@ -1,4 +1,15 @@
-use clap::{ArgAction, Parser};
+use crate::columnar::TableFormat;
+use crate::export::{
+    DEFAULT_MAX_COMPRESSION_RATIO, DEFAULT_MAX_EXPORT_ENTRIES, DEFAULT_MAX_EXPORT_MIB,
+    ExportLimits, ExportOptions,
+};
+use crate::ingest::IngestOptions;
+use crate::merge::{FieldPrecedence, parse_precedence};
+use crate::stored::{WorkoutFilter, WorkoutRef};
+use crate::tcx_writer::LapMode;
+use crate::utils::parse_datetime_arg;
+use chrono::{DateTime, Utc};
+use clap::{ArgAction, Args, Parser, Subcommand};
 use std::path::PathBuf;
 
 const DEFAULT_EXPORT_ZIP: &str = "/home/mat/docs/personal/GadgetBridge/Gadgetbridge.zip";
@@ -7,19 +18,64 @@ const DEFAULT_PG_URL: &str = "postgres://127.0.0.1:5432/fitness";
 #[derive(Parser, Debug)]
 #[command(
     name = "roudenn",
-    about = "Import workouts from a Gadgetbridge export (ZIP or dir) into PostgreSQL"
+    about = "Import workouts from a Gadgetbridge export (ZIP or dir) into PostgreSQL",
+    args_conflicts_with_subcommands = true
 )]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to the Gadgetbridge export ZIP (or an already-extracted export directory).
     ///
+    /// May also be a tar, tar.gz or tar.zst archive, a bare `Gadgetbridge` SQLite
+    /// database (e.g. pulled from a phone backup; see --files-dir), or `-` to read
+    /// any of these from stdin.
+    ///
+    /// A directory holding several export ZIPs (auto-export, backups) selects the
+    /// newest one, by the timestamp of the database embedded in each ZIP.
+    ///
     /// Default: /home/mat/docs/personal/GadgetBridge/Gadgetbridge.zip
     #[arg(value_name = "EXPORT", default_value = DEFAULT_EXPORT_ZIP)]
     pub export: PathBuf,
 
+    /// When EXPORT is a directory of export ZIPs, ingest all of them (oldest first)
+    /// instead of only the newest.
+    #[arg(long)]
+    pub all_exports: bool,
+
+    /// Directory holding the export's `files/` (GPX tracks, `rawDetails/`), used when
+    /// EXPORT is a bare SQLite database. Without it, tracks and raw details are skipped.
+    #[arg(long, value_name = "DIR")]
+    pub files_dir: Option<PathBuf>,
+
+    /// Also import GPX tracks (and raw details) in the export that no workout in
+    /// the DB references, e.g. after a phone migration. They are stored with
+    /// source = 'orphan_file' and timed by their first and last track points.
+    #[arg(long, global = true)]
+    pub import_orphans: bool,
+
+    /// File containing the password of an encrypted (AES or ZipCrypto) export ZIP.
+    ///
+    /// Without it, $ROUDENN_EXPORT_PASSWORD is used, or the password is prompted for.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub password_file: Option<PathBuf>,
+
     /// PostgreSQL connection URL
-    #[arg(long, default_value = DEFAULT_PG_URL)]
+    #[arg(long, default_value = DEFAULT_PG_URL, global = true)]
     pub pg_url: String,
 
+    /// Refuse export archives that uncompress to more than this many MiB.
+    #[arg(long, default_value_t = DEFAULT_MAX_EXPORT_MIB, global = true)]
+    pub max_export_mib: u64,
+
+    /// Refuse export archives with more entries than this.
+    #[arg(long, default_value_t = DEFAULT_MAX_EXPORT_ENTRIES, global = true)]
+    pub max_export_entries: usize,
+
+    /// Refuse export archives containing an entry compressed better than N:1 (zip bomb guard).
+    #[arg(long, default_value_t = DEFAULT_MAX_COMPRESSION_RATIO, global = true)]
+    pub max_compression_ratio: u64,
+
     /// Increase log verbosity (-v, -vv). Defaults to INFO.
     #[arg(short = 'v', long, action = ArgAction::Count, global = true)]
     pub verbose: u8,
@@ -28,3 +84,285 @@ pub struct Cli {
     #[arg(short = 'q', long, action = ArgAction::Count, global = true)]
     pub quiet: u8,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Watch a directory and ingest new or updated exports as they appear.
+    Watch(WatchArgs),
+    /// Write workouts stored in PostgreSQL back out to files.
+    Export(ExportArgs),
+    /// Import standalone GPX, TCX or FIT files, e.g. recorded by OpenTracks or
+    /// OsmAnd, or exported from another service.
+    ImportFiles(ImportFilesArgs),
+    /// Import a Strava account archive (`activities.csv` and the recorded
+    /// files), as downloaded from "Download your data", ZIP or extracted.
+    ImportStrava(ImportStravaArgs),
+    /// Import the workouts and their routes from an Apple Health export
+    /// (`export.zip` from the Health app), ZIP or extracted.
+    ImportAppleHealth(ImportAppleHealthArgs),
+    /// Merge workouts recorded by several sources (watch and phone, imports of
+    /// the same run) into one, keeping links to the originals.
+    MergeDuplicates(MergeArgs),
+    /// Attach the heart rate a watch logged continuously (Gadgetbridge's
+    /// sample tables) to workouts tracked without it, e.g. phone GPS runs.
+    AttachHr(AttachHrArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ImportStravaArgs {
+    /// The archive's ZIP (export_NNN.zip) or the directory it was extracted to.
+    #[arg(value_name = "ARCHIVE")]
+    pub archive: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportAppleHealthArgs {
+    /// The Health app's export.zip, or the apple_health_export/ directory it
+    /// holds.
+    #[arg(value_name = "EXPORT")]
+    pub archive: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct MergeArgs {
+    /// Only log the duplicates found and what each merge would take from them.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Fraction of the shorter workout two workouts must overlap by in time.
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.5)]
+    pub min_overlap: f64,
+
+    /// Median distance, in meters, between the points two tracks recorded at
+    /// the same moments, above which they are different workouts.
+    #[arg(long, value_name = "METERS", default_value_t = 100.0)]
+    pub max_track_distance: f64,
+
+    /// Sources to take FIELD (gps, hr or summary) from, most preferred first,
+    /// e.g. `gps=imported_file,strava`. Repeat for several fields.
+    ///
+    /// Default: gps from imported_file, strava, apple_health, orphan_file,
+    /// gadgetbridge; hr and summary in the reverse order.
+    #[arg(long = "prefer", value_name = "FIELD=SOURCES", value_parser = parse_precedence)]
+    pub prefer: Vec<FieldPrecedence>,
+}
+
+#[derive(Args, Debug)]
+pub struct AttachHrArgs {
+    /// Gadgetbridge export holding the watch's samples (dir, .zip,
+    /// .tar[.gz|.zst], .db).
+    #[arg(value_name = "EXPORT")]
+    pub export: PathBuf,
+
+    /// A single workout, by id or uuid. Its heart rate is replaced even if it
+    /// has some.
+    #[arg(long, value_name = "ID|UUID")]
+    pub workout: Option<WorkoutRef>,
+
+    /// Workouts starting on or after DATE (YYYY-MM-DD, UTC, or RFC 3339).
+    #[arg(long, value_name = "DATE", value_parser = parse_datetime_arg)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Gadgetbridge device id of the watch.
+    ///
+    /// Default: the device with the most heart rate samples during each workout.
+    #[arg(long, value_name = "ID")]
+    pub device: Option<i64>,
+
+    /// Seconds the watch's clock is ahead of the phone's (negative: behind),
+    /// at most a day either way.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0,
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i64).range(-86_400..=86_400)
+    )]
+    pub offset: i64,
+
+    /// Estimate each workout's clock offset, within --max-offset of --offset,
+    /// by matching the watch's step counts to the distance the track covered.
+    /// Workouts where they don't match keep --offset. The offset is constant
+    /// over a workout: clock drift during it is not corrected.
+    #[arg(long)]
+    pub align: bool,
+
+    /// Largest correction --align makes, in seconds (at most a day).
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(i64).range(0..=86_400)
+    )]
+    pub max_offset: i64,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportFilesArgs {
+    /// GPX, TCX or FIT files, or directories searched recursively for them.
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Activity of every imported workout, e.g. outdoor_running or cycling.
+    ///
+    /// Default: the GPX track's <type>, the TCX Sport or the FIT sport, else
+    /// 'other'.
+    #[arg(long, value_name = "ACTIVITY")]
+    pub activity: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    /// Directory receiving export ZIPs or extracted export directories (e.g. a Syncthing folder).
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Seconds an export must stay unchanged before it is ingested.
+    #[arg(long, default_value_t = 5)]
+    pub settle_secs: u64,
+
+    /// File remembering already processed exports.
+    ///
+    /// Default: DIR/.roudenn-watch.json
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    #[command(subcommand)]
+    pub format: ExportFormat,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ExportFormat {
+    /// GPX 1.1, with elevation, heart rate and cadence when recorded.
+    Gpx(ExportTarget),
+    /// FIT activity files (records, one lap per track segment, session), for
+    /// Garmin-centric tools such as Golden Cheetah.
+    Fit(ExportTarget),
+    /// TCX activities with laps, heart rate, cadence and calories.
+    Tcx(TcxExportArgs),
+    /// A single GeoJSON FeatureCollection, one LineString per workout, for web
+    /// maps.
+    #[command(name = "geojson")]
+    GeoJson(GeoJsonExportArgs),
+    /// `workouts`, `workout_points` and sample tables as CSV or Parquet files
+    /// partitioned by year, for pandas or polars.
+    Tables(TablesExportArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct TablesExportArgs {
+    /// Directory receiving one subdirectory per table (replaced if present),
+    /// each holding `year=YYYY/part-N.<format>` files.
+    #[arg(short, long, value_name = "DIR")]
+    pub output: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = TableFormat::Parquet)]
+    pub format: TableFormat,
+
+    /// Read a Gadgetbridge export (dir, .zip, .tar[.gz|.zst], .db) instead of
+    /// the database.
+    #[arg(long, value_name = "EXPORT")]
+    pub from_export: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct TcxExportArgs {
+    #[command(flatten)]
+    pub target: ExportTarget,
+
+    /// How to split the activity into laps.
+    #[arg(long, value_enum, default_value_t = LapMode::Km)]
+    pub laps: LapMode,
+}
+
+#[derive(Args, Debug)]
+pub struct GeoJsonExportArgs {
+    /// Workouts starting on or after DATE (YYYY-MM-DD, UTC, or RFC 3339).
+    #[arg(long, value_name = "DATE", value_parser = parse_datetime_arg)]
+    pub since: Option<DateTime<Utc>>,
+
+    /// Workouts starting before DATE (YYYY-MM-DD, UTC, or RFC 3339).
+    #[arg(long, value_name = "DATE", value_parser = parse_datetime_arg)]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Only this activity, e.g. outdoor_running. Repeat for several.
+    #[arg(long = "activity", value_name = "ACTIVITY")]
+    pub activities: Vec<String>,
+
+    /// Store tracks as Google encoded polylines (`polyline` property) instead
+    /// of LineString coordinates.
+    #[arg(long)]
+    pub polyline: bool,
+
+    /// Output file (default: stdout).
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+}
+
+impl GeoJsonExportArgs {
+    pub fn filter(&self) -> WorkoutFilter {
+        WorkoutFilter {
+            since: self.since,
+            until: self.until,
+            activities: self.activities.clone(),
+            ..WorkoutFilter::default()
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ExportTarget {
+    #[command(flatten)]
+    pub selection: WorkoutSelection,
+
+    /// Output file for --workout (default: stdout), or directory receiving one
+    /// file per workout for --since (default: current directory).
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct WorkoutSelection {
+    /// A single workout, by id or uuid.
+    #[arg(long, value_name = "ID|UUID")]
+    pub workout: Option<WorkoutRef>,
+
+    /// All workouts starting on or after DATE (YYYY-MM-DD, UTC, or RFC 3339).
+    #[arg(long, value_name = "DATE", value_parser = parse_datetime_arg)]
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl WorkoutSelection {
+    pub fn filter(&self) -> WorkoutFilter {
+        WorkoutFilter {
+            workout: self.workout.clone(),
+            since: self.since,
+            ..WorkoutFilter::default()
+        }
+    }
+}
+
+impl Cli {
+    pub fn export_options(&self) -> ExportOptions {
+        ExportOptions {
+            limits: ExportLimits {
+                max_total_bytes: self.max_export_mib.saturating_mul(1024 * 1024),
+                max_entries: self.max_export_entries,
+                max_compression_ratio: self.max_compression_ratio,
+            },
+            files_dir: self.files_dir.clone(),
+            password_file: self.password_file.clone(),
+            non_interactive: false,
+        }
+    }
+
+    pub const fn ingest_options(&self) -> IngestOptions {
+        IngestOptions {
+            import_orphans: self.import_orphans,
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::{Context, Result, bail};
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Rows buffered before they are written out as one CSV chunk or Parquet row
+/// group.
+const BATCH_ROWS: usize = 65_536;
+
+/// Hive's name for the partition of rows without a value, read back as null by
+/// pyarrow, polars and Spark.
+const NULL_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TableFormat {
+    Csv,
+    #[default]
+    Parquet,
+}
+
+impl TableFormat {
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnKind {
+    Int,
+    Float,
+    Bool,
+    Text,
+    /// UTC, microsecond precision.
+    Timestamp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnKind,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, kind: ColumnKind) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+        }
+    }
+}
+
+/// A cell. Its variant must match its column's [`ColumnKind`], or be `Null`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+    Timestamp(DateTime<Utc>),
+}
+
+impl<T: Into<Self>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Self::Null, Into::into)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Self::Int(i64::from(v))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Self::Float(v)
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Self::Text(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Self::Text(v.to_owned())
+    }
+}
+
+impl From<DateTime<Utc>> for Value {
+    fn from(v: DateTime<Utc>) -> Self {
+        Self::Timestamp(v)
+    }
+}
+
+/// Writes one table as `<dir>/<table>/year=YYYY/part-N.<ext>`, partitioned on
+/// the year of its time column, the layout `pandas.read_parquet`,
+/// `polars.scan_parquet(hive_partitioning=True)` and Spark read as a single
+/// dataset.
+///
+/// Rows should come in time order: a file per year is kept open, and a year
+/// seen again after another one starts a new part file.
+pub struct PartitionedTable {
+    dir: PathBuf,
+    format: TableFormat,
+    columns: Vec<Column>,
+    schema: SchemaRef,
+    time_column: usize,
+    rows: Vec<Vec<Value>>,
+    year: Option<Option<i32>>,
+    sink: Option<Sink>,
+    parts: HashMap<Option<i32>, usize>,
+    rows_written: usize,
+}
+
+enum Sink {
+    Csv(csv::Writer<BufWriter<File>>),
+    Parquet(ArrowWriter<File>),
+}
+
+impl PartitionedTable {
+    /// Replaces whatever `<dir>/<table>` held before. `table` must be a plain
+    /// `[A-Za-z0-9_]` name, so that directory is always a child of `dir`.
+    pub fn create(
+        dir: &Path,
+        table: &str,
+        format: TableFormat,
+        columns: Vec<Column>,
+        time_column: &str,
+    ) -> Result<Self> {
+        if table.is_empty()
+            || !table
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        {
+            bail!("refusing table name {table:?}: not a plain identifier");
+        }
+        let Some(time_index) = columns.iter().position(|c| c.name == time_column) else {
+            bail!("table {table} has no column {time_column}");
+        };
+        if columns[time_index].kind != ColumnKind::Timestamp {
+            bail!("{table}.{time_column} is not a timestamp column");
+        }
+
+        let dir = dir.join(table);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).with_context(|| format!("removing {}", dir.display()))?;
+        }
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+        let fields: Vec<Field> = columns
+            .iter()
+            .map(|c| Field::new(&c.name, arrow_type(c.kind), true))
+            .collect();
+
+        Ok(Self {
+            dir,
+            format,
+            schema: Arc::new(Schema::new(fields)),
+            columns,
+            time_column: time_index,
+            rows: Vec::new(),
+            year: None,
+            sink: None,
+            parts: HashMap::new(),
+            rows_written: 0,
+        })
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) -> Result<()> {
+        debug_assert_eq!(row.len(), self.columns.len());
+        let year = match &row[self.time_column] {
+            Value::Timestamp(t) => Some(t.year()),
+            _ => None,
+        };
+        if self.year != Some(year) {
+            self.flush()?;
+            self.close()?;
+            self.year = Some(year);
+        }
+        self.rows.push(row);
+        if self.rows.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the last rows; returns how many rows the table got.
+    pub fn finish(mut self) -> Result<usize> {
+        self.flush()?;
+        self.close()?;
+        Ok(self.rows_written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        if self.sink.is_none() {
+            self.sink = Some(self.open(self.year.flatten())?);
+        }
+        let rows = std::mem::take(&mut self.rows);
+        match self.sink.as_mut() {
+            Some(Sink::Csv(w)) => {
+                for row in &rows {
+                    w.write_record(row.iter().map(csv_field))?;
+                }
+            }
+            Some(Sink::Parquet(w)) => {
+                w.write(&record_batch(&self.schema, &self.columns, &rows)?)?
+            }
+            None => unreachable!("sink opened above"),
+        }
+        self.rows_written += rows.len();
+        Ok(())
+    }
+
+    fn open(&mut self, year: Option<i32>) -> Result<Sink> {
+        let partition = year.map_or_else(|| NULL_PARTITION.to_owned(), |y| y.to_string());
+        let dir = self.dir.join(format!("year={partition}"));
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+        let part = self.parts.entry(year).or_default();
+        let path = dir.join(format!("part-{part}.{}", self.format.extension()));
+        *part += 1;
+
+        let file = File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+        Ok(match self.format {
+            TableFormat::Csv => {
+                let mut w = csv::Writer::from_writer(BufWriter::new(file));
+                w.write_record(self.columns.iter().map(|c| c.name.as_str()))?;
+                Sink::Csv(w)
+            }
+            TableFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                Sink::Parquet(ArrowWriter::try_new(
+                    file,
+                    self.schema.clone(),
+                    Some(props),
+                )?)
+            }
+        })
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.sink.take() {
+            Some(Sink::Csv(mut w)) => w.flush()?,
+            Some(Sink::Parquet(w)) => {
+                w.close()?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn arrow_type(kind: ColumnKind) -> DataType {
+    match kind {
+        ColumnKind::Int => DataType::Int64,
+        ColumnKind::Float => DataType::Float64,
+        ColumnKind::Bool => DataType::Boolean,
+        ColumnKind::Text => DataType::Utf8,
+        ColumnKind::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+    }
+}
+
+fn record_batch(
+    schema: &SchemaRef,
+    columns: &[Column],
+    rows: &[Vec<Value>],
+) -> Result<RecordBatch> {
+    let arrays: Vec<ArrayRef> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| -> ArrayRef {
+            let cells = rows.iter().map(|r| &r[i]);
+            match c.kind {
+                ColumnKind::Int => Arc::new(
+                    cells
+                        .map(|v| match v {
+                            Value::Int(v) => Some(*v),
+                            _ => None,
+                        })
+                        .collect::<Int64Array>(),
+                ),
+                ColumnKind::Float => Arc::new(
+                    cells
+                        .map(|v| match v {
+                            Value::Float(v) => Some(*v),
+                            _ => None,
+                        })
+                        .collect::<Float64Array>(),
+                ),
+                ColumnKind::Bool => Arc::new(
+                    cells
+                        .map(|v| match v {
+                            Value::Bool(v) => Some(*v),
+                            _ => None,
+                        })
+                        .collect::<BooleanArray>(),
+                ),
+                ColumnKind::Text => Arc::new(
+                    cells
+                        .map(|v| match v {
+                            Value::Text(v) => Some(v.as_str()),
+                            _ => None,
+                        })
+                        .collect::<StringArray>(),
+                ),
+                ColumnKind::Timestamp => Arc::new(
+                    cells
+                        .map(|v| match v {
+                            Value::Timestamp(t) => Some(t.timestamp_micros()),
+                            _ => None,
+                        })
+                        .collect::<TimestampMicrosecondArray>()
+                        .with_timezone("UTC"),
+                ),
+            }
+        })
+        .collect();
+    Ok(RecordBatch::try_new(schema.clone(), arrays)?)
+}
+
+fn csv_field(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::Int(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        Value::Bool(v) => v.to_string(),
+        Value::Text(v) => v.clone(),
+        Value::Timestamp(t) => t.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_directory_stays_below_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let victim = dir.path().join("victim_sample");
+        fs::create_dir_all(&victim).unwrap();
+
+        let columns = || vec![Column::new("timestamp", ColumnKind::Timestamp)];
+        for table in ["../victim_sample", "", "a/b", "."] {
+            assert!(
+                PartitionedTable::create(&out, table, TableFormat::Csv, columns(), "timestamp")
+                    .is_err(),
+                "{table:?}"
+            );
+        }
+        assert!(victim.is_dir());
+
+        PartitionedTable::create(
+            &out,
+            "mi_band_sample",
+            TableFormat::Csv,
+            columns(),
+            "timestamp",
+        )
+        .unwrap();
+        assert!(out.join("mi_band_sample").is_dir());
+    }
+}
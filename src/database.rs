@@ -1,24 +1,35 @@
-use crate::types::WorkoutSummary;
-use crate::{dlog, utils::map_android_raw_details_to_export};
+use crate::dlog;
+use crate::export::Export;
+use crate::types::{SOURCE_GADGETBRIDGE, WorkoutSummary};
+use crate::utils::quote_ident;
 use anyhow::{Context, Result};
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use rusqlite::Connection;
 use serde_json::Value as JsonValue;
-use std::fs;
-use std::path::Path;
-
-pub fn read_base_activity_summary(
-    export_dir: &Path,
-    store_raw_details: bool,
-) -> Result<Vec<WorkoutSummary>> {
-    let db_path = export_dir.join("database").join("Gadgetbridge");
-    if !db_path.exists() {
-        return Ok(Vec::new());
-    }
 
-    let display = db_path.display();
-    let conn =
-        Connection::open(&db_path).with_context(|| format!("Opening SQLite DB: {display}"))?;
+/// Timestamps above this are taken as milliseconds: Gadgetbridge's older sample
+/// tables count seconds, newer ones milliseconds. 1e11 s is in the year 5138.
+pub(crate) const MAX_SECONDS_TIMESTAMP: i64 = 100_000_000_000;
+
+/// A row of a Gadgetbridge sample table: usually one minute of the watch's
+/// continuous tracking.
+#[derive(Debug, Clone)]
+pub struct ActivitySample {
+    pub t: DateTime<Utc>,
+    pub device_id: i64,
+    /// Heart rate, bpm; `None` when the watch didn't measure it.
+    pub hr: Option<i32>,
+    pub steps: Option<i32>,
+}
+
+/// Read every workout from the export DB.
+///
+/// `raw_details` is left empty: the referenced file is resolved against the
+/// export's files by the caller (see [`crate::file_index::FileIndex`]).
+pub fn read_base_activity_summary(export: &Export) -> Result<Vec<WorkoutSummary>> {
+    let Some(conn) = open_database(export)? else {
+        return Ok(Vec::new());
+    };
 
     if !table_exists(&conn, "BASE_ACTIVITY_SUMMARY")? {
         anyhow::bail!("SQLite DB does not contain BASE_ACTIVITY_SUMMARY.");
@@ -87,15 +98,6 @@ pub fn read_base_activity_summary(
             continue;
         };
 
-        let raw_details = if store_raw_details {
-            raw_details_android
-                .as_deref()
-                .and_then(|p| map_android_raw_details_to_export(export_dir, p))
-                .and_then(|p| fs::read(p).ok())
-        } else {
-            None
-        };
-
         out.push(WorkoutSummary {
             name,
             start,
@@ -116,16 +118,156 @@ pub fn read_base_activity_summary(
             summary_data_json,
             raw_summary_data,
 
-            raw_details,
+            raw_details: None,
+
+            source: SOURCE_GADGETBRIDGE,
+            external_id: None,
         });
     }
 
     Ok(out)
 }
 
+/// The export's SQLite DB, if it has one.
+pub fn open_database(export: &Export) -> Result<Option<Connection>> {
+    let Some(db_path) = export.database_path()? else {
+        return Ok(None);
+    };
+    let display = db_path.display();
+    let conn =
+        Connection::open(&db_path).with_context(|| format!("Opening SQLite DB: {display}"))?;
+    Ok(Some(conn))
+}
+
+/// Gadgetbridge's per-device sample tables (`MI_BAND_ACTIVITY_SAMPLE`,
+/// `HUAMI_EXTENDED_ACTIVITY_SAMPLE`, ...), all keyed on a `TIMESTAMP` column.
+///
+/// Names end up in file paths, so tables not named like Gadgetbridge's
+/// (`[A-Za-z0-9_]`) are skipped.
+pub fn sample_tables(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        r"SELECT m.name FROM sqlite_master m
+          WHERE m.type = 'table' AND m.name LIKE '%\_SAMPLE' ESCAPE '\'
+            AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'TIMESTAMP')
+          ORDER BY m.name",
+    )?;
+    let names: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(names
+        .into_iter()
+        .filter(|name| {
+            let plain = name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+            if !plain {
+                tracing::warn!(table = %name, "skipping sample table with an unexpected name");
+            }
+            plain
+        })
+        .collect())
+}
+
+/// Rows from `from` to `to` of every sample table with a `HEART_RATE` column,
+/// by time.
+pub fn read_activity_samples(
+    conn: &Connection,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<ActivitySample>> {
+    let mut out = Vec::new();
+    for table in sample_tables(conn)? {
+        let has_column = |column: &str| -> Result<bool> {
+            Ok(conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                [table.as_str(), column],
+                |r| r.get(0),
+            )?)
+        };
+        if !has_column("HEART_RATE")? {
+            continue;
+        }
+        let device = if has_column("DEVICE_ID")? {
+            "DEVICE_ID"
+        } else {
+            "0"
+        };
+        let steps = if has_column("STEPS")? {
+            "STEPS"
+        } else {
+            "NULL"
+        };
+
+        let ident = quote_ident(&table);
+        let max_timestamp: Option<i64> =
+            conn.query_row(&format!("SELECT max(TIMESTAMP) FROM {ident}"), [], |r| {
+                r.get(0)
+            })?;
+        let millis = max_timestamp.is_some_and(|t| t > MAX_SECONDS_TIMESTAMP);
+        let (lo, hi) = if millis {
+            (from.timestamp_millis(), to.timestamp_millis())
+        } else {
+            (from.timestamp(), to.timestamp())
+        };
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT TIMESTAMP, {device}, HEART_RATE, {steps} FROM {ident}
+             WHERE TIMESTAMP BETWEEN ?1 AND ?2"
+        ))?;
+        let rows = stmt.query_map([lo, hi], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, Option<i64>>(1)?,
+                r.get::<_, Option<i64>>(2)?,
+                r.get::<_, Option<i64>>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (t, device_id, hr, steps) = row?;
+            let t = if millis {
+                Utc.timestamp_millis_opt(t)
+            } else {
+                Utc.timestamp_opt(t, 0)
+            };
+            let Some(t) = t.single() else {
+                continue;
+            };
+            out.push(ActivitySample {
+                t,
+                device_id: device_id.unwrap_or(0),
+                // 0, -1 and 255 mean no reading.
+                hr: hr
+                    .filter(|hr| (1..255).contains(hr))
+                    .and_then(|hr| i32::try_from(hr).ok()),
+                steps: steps
+                    .filter(|s| *s >= 0)
+                    .and_then(|s| i32::try_from(s).ok()),
+            });
+        }
+    }
+    out.sort_by_key(|s| s.t);
+    Ok(out)
+}
+
 fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
     let mut stmt =
         conn.prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name=?1 LIMIT 1")?;
     let mut rows = stmt.query([table])?;
     Ok(rows.next()?.is_some())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_tables_skip_names_unfit_for_paths() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"CREATE TABLE MI_BAND_ACTIVITY_SAMPLE (TIMESTAMP INTEGER, HEART_RATE INTEGER);
+               CREATE TABLE "../../victim_SAMPLE" (TIMESTAMP INTEGER);
+               CREATE TABLE NO_TIME_SAMPLE (VALUE INTEGER);
+               CREATE TABLE DEVICE (TIMESTAMP INTEGER);"#,
+        )
+        .unwrap();
+        assert_eq!(sample_tables(&conn).unwrap(), ["MI_BAND_ACTIVITY_SAMPLE"]);
+    }
+}
@@ -0,0 +1,1250 @@
+use crate::utils::{has_extension, looks_like_export};
+use anyhow::{Context, Result, bail};
+use flate2::read::MultiGzDecoder;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+use tempfile::{NamedTempFile, TempDir};
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+const DB_REL_PATH: &str = "database/Gadgetbridge";
+const PASSWORD_ENV: &str = "ROUDENN_EXPORT_PASSWORD";
+
+/// Default [`ExportLimits`], also the defaults of the matching CLI flags.
+pub const DEFAULT_MAX_EXPORT_MIB: u64 = 4096;
+pub const DEFAULT_MAX_EXPORT_ENTRIES: usize = 100_000;
+pub const DEFAULT_MAX_COMPRESSION_RATIO: u64 = 500;
+
+/// Entries smaller than this are exempt from the compression-ratio check:
+/// tiny, highly repetitive files legitimately compress very well.
+const RATIO_CHECK_MIN_BYTES: u64 = 1024 * 1024;
+
+/// Resource limits applied when reading export archives.
+///
+/// Exports arrive unattended (sync, auto-export), so a corrupted or malicious
+/// archive must not be able to fill the disk or memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ExportLimits {
+    /// Maximum total uncompressed size of all entries, in bytes.
+    pub max_total_bytes: u64,
+    /// Maximum number of entries in the archive.
+    pub max_entries: usize,
+    /// Maximum uncompressed/compressed size ratio of a single entry.
+    pub max_compression_ratio: u64,
+}
+
+impl Default for ExportLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: DEFAULT_MAX_EXPORT_MIB * 1024 * 1024,
+            max_entries: DEFAULT_MAX_EXPORT_ENTRIES,
+            max_compression_ratio: DEFAULT_MAX_COMPRESSION_RATIO,
+        }
+    }
+}
+
+/// How to open an export: resource limits and optional extra locations.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub limits: ExportLimits,
+    /// Directory standing in for the export's `files/` when `EXPORT` is a bare SQLite DB.
+    pub files_dir: Option<PathBuf>,
+    /// File holding the password of encrypted export ZIPs. Falls back to
+    /// `$ROUDENN_EXPORT_PASSWORD`, then to an interactive prompt.
+    pub password_file: Option<PathBuf>,
+    /// Never prompt for a password (watch mode has no one to answer it).
+    pub non_interactive: bool,
+}
+
+/// Read-only view of a Gadgetbridge export.
+///
+/// Files are addressed by their `/`-separated path relative to the export root
+/// (e.g. `files/track.gpx`). ZIP exports are read in place: only the entries we
+/// ask for are decompressed, and only the SQLite DB is copied to a temp file
+/// (SQLite needs a real file to open).
+pub struct Export {
+    location: PathBuf,
+    storage: Storage,
+    db_tmp: OnceCell<NamedTempFile>,
+    limits: ExportLimits,
+    /// Uncompressed bytes read from the archive so far, each entry counted once.
+    bytes_read: Cell<u64>,
+    /// How far each ZIP entry (by index) has been read, so re-reading an
+    /// entry doesn't count against the budget again.
+    entry_bytes_read: RefCell<HashMap<usize, u64>>,
+    _backing: Option<Backing>,
+}
+
+/// Temp storage an export lives in (extracted tar, spooled stdin), removed on drop.
+enum Backing {
+    Dir { _tmp: TempDir },
+    File { _tmp: NamedTempFile },
+}
+
+/// What an archive holds, which tells where its root is when it is wrapped in
+/// a top-level directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `files/`, `database/Gadgetbridge`, `gadgetbridge.json`.
+    Gadgetbridge,
+    /// A Strava account archive: `activities.csv` and `activities/`.
+    Strava,
+    /// An Apple Health export: `export.xml` and `workout-routes/`.
+    AppleHealth,
+}
+
+impl Layout {
+    const fn describe(self) -> &'static str {
+        match self {
+            Self::Gadgetbridge => "a Gadgetbridge export",
+            Self::Strava => "a Strava archive",
+            Self::AppleHealth => "an Apple Health export",
+        }
+    }
+
+    /// Whether `rel`, relative to a candidate root, belongs to an archive of
+    /// this layout.
+    fn is_root_entry(self, rel: &str) -> bool {
+        match self {
+            Self::Gadgetbridge => {
+                rel.starts_with("files/")
+                    || rel.starts_with("database/")
+                    || rel == "gadgetbridge.json"
+            }
+            Self::Strava => rel == "activities.csv",
+            Self::AppleHealth => rel == "export.xml",
+        }
+    }
+
+    fn is_root_dir(self, dir: &Path) -> bool {
+        match self {
+            Self::Gadgetbridge => looks_like_export(dir),
+            Self::Strava => dir.join("activities.csv").is_file(),
+            Self::AppleHealth => dir.join("export.xml").is_file(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zip,
+    Tar(Compression),
+    Sqlite,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+enum Storage {
+    Dir(PathBuf),
+    Zip {
+        archive: RefCell<ZipArchive<File>>,
+        /// Prefix of the export root inside the archive ("" or "Gadgetbridge/").
+        root: String,
+        /// Set when the archive has encrypted (AES or ZipCrypto) entries.
+        password: Option<Vec<u8>>,
+    },
+    /// A bare `Gadgetbridge` SQLite DB (e.g. from a phone backup), optionally
+    /// with a directory holding what would be the export's `files/`.
+    Database {
+        db: PathBuf,
+        files_dir: Option<PathBuf>,
+    },
+}
+
+impl Export {
+    /// Accepts either:
+    /// - a directory containing `files/`, `database/`, etc.
+    /// - a `.zip` file, read without extracting it (subject to `opts.limits`)
+    /// - a tar, tar.gz or tar.zst archive, extracted to a temp dir
+    /// - a bare Gadgetbridge SQLite DB, with files taken from `opts.files_dir`
+    /// - `-`: any of the above files, streamed on stdin
+    ///
+    /// Files are recognized by their content, not their extension.
+    pub fn open(path: &Path, opts: &ExportOptions) -> Result<Self> {
+        Self::open_as(path, opts, Layout::Gadgetbridge)
+    }
+
+    /// [`Self::open`] for an archive of another `layout`, e.g. a Strava
+    /// account archive, with the same limits. Only Gadgetbridge exports can be
+    /// a bare SQLite DB.
+    pub fn open_as(path: &Path, opts: &ExportOptions, layout: Layout) -> Result<Self> {
+        if path == Path::new("-") {
+            let spool = spool(io::stdin().lock(), &opts.limits)?;
+            let file = spool.path().to_path_buf();
+            let backing = Some(Backing::File { _tmp: spool });
+            return Self::open_file(path, &file, backing, opts, layout);
+        }
+
+        if path.is_dir() {
+            // Gadgetbridge directories are taken as they are: partial exports
+            // (only `files/`, say) are still worth ingesting.
+            if layout != Layout::Gadgetbridge && !layout.is_root_dir(path) {
+                bail!(
+                    "directory doesn't look like {}: {}",
+                    layout.describe(),
+                    path.display()
+                );
+            }
+            warn_unused_files_dir(opts);
+            tracing::info!(path = %path.display(), "using export directory");
+            return Ok(Self::new(
+                path,
+                Storage::Dir(path.to_path_buf()),
+                None,
+                opts,
+            ));
+        }
+
+        Self::open_file(path, path, None, opts, layout)
+    }
+
+    /// Open the export stored in `file`; `location` is what the user passed
+    /// (differs for stdin, which is spooled to `spool` first).
+    fn open_file(
+        location: &Path,
+        file: &Path,
+        spool: Option<Backing>,
+        opts: &ExportOptions,
+        layout: Layout,
+    ) -> Result<Self> {
+        let format = sniff_format(file)
+            .with_context(|| format!("reading export: {}", location.display()))?;
+        if format != Format::Sqlite {
+            warn_unused_files_dir(opts);
+        }
+
+        match format {
+            Format::Sqlite if layout != Layout::Gadgetbridge => {
+                bail!(
+                    "{} is a SQLite database, not {}",
+                    location.display(),
+                    layout.describe()
+                )
+            }
+            Format::Sqlite => {
+                let files_dir = opts.files_dir.clone();
+                if let Some(dir) = &files_dir
+                    && !dir.is_dir()
+                {
+                    bail!("--files-dir is not a directory: {}", dir.display());
+                }
+                tracing::info!(
+                    db = %location.display(),
+                    files_dir = ?files_dir.as_deref().map(Path::display),
+                    "using bare Gadgetbridge database"
+                );
+                let storage = Storage::Database {
+                    db: file.to_path_buf(),
+                    files_dir,
+                };
+                Ok(Self::new(location, storage, spool, opts))
+            }
+            Format::Zip => {
+                let zip_file = File::open(file)
+                    .with_context(|| format!("opening zip: {}", location.display()))?;
+                let mut zip = ZipArchive::new(zip_file)
+                    .with_context(|| format!("reading zip: {}", location.display()))?;
+                check_zip_limits(&mut zip, &opts.limits)
+                    .with_context(|| format!("refusing export zip: {}", location.display()))?;
+
+                let Some(root) = zip_export_root(&zip, layout) else {
+                    bail!(
+                        "ZIP doesn't look like {}: {}",
+                        layout.describe(),
+                        location.display()
+                    );
+                };
+
+                let password = if zip_is_encrypted(&mut zip)? {
+                    let pw = export_password(opts, location)?;
+                    check_zip_password(&mut zip, &pw).with_context(|| {
+                        format!("opening encrypted zip: {}", location.display())
+                    })?;
+                    Some(pw)
+                } else {
+                    None
+                };
+
+                tracing::info!(
+                    zip = %location.display(),
+                    root = %root,
+                    entries = zip.len(),
+                    encrypted = password.is_some(),
+                    "using export zip"
+                );
+
+                let storage = Storage::Zip {
+                    archive: RefCell::new(zip),
+                    root,
+                    password,
+                };
+                Ok(Self::new(location, storage, spool, opts))
+            }
+            Format::Tar(compression) => {
+                let tmp = extract_tar(file, compression, &opts.limits)
+                    .with_context(|| format!("extracting tar: {}", location.display()))?;
+
+                let Some(root) = extracted_export_root(tmp.path(), layout)? else {
+                    bail!(
+                        "tar extracted but doesn't look like {}: {}",
+                        layout.describe(),
+                        location.display()
+                    );
+                };
+                tracing::info!(export_root = %root.display(), "export ready");
+
+                // The spooled stdin copy is no longer needed once extracted.
+                Ok(Self::new(
+                    location,
+                    Storage::Dir(root),
+                    Some(Backing::Dir { _tmp: tmp }),
+                    opts,
+                ))
+            }
+            Format::Unknown => bail!(
+                "Export must be a directory, a .zip or tar(.gz/.zst) archive, or a Gadgetbridge \
+                 SQLite DB: {}",
+                location.display()
+            ),
+        }
+    }
+
+    fn new(
+        location: &Path,
+        storage: Storage,
+        backing: Option<Backing>,
+        opts: &ExportOptions,
+    ) -> Self {
+        Self {
+            location: location.to_path_buf(),
+            storage,
+            db_tmp: OnceCell::new(),
+            limits: opts.limits,
+            bytes_read: Cell::new(0),
+            entry_bytes_read: RefCell::new(HashMap::new()),
+            _backing: backing,
+        }
+    }
+
+    /// Path the export was opened from (directory, ZIP file or SQLite DB).
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Whether files (GPX tracks, raw details) can be read at all.
+    ///
+    /// False for a bare SQLite DB opened without `--files-dir`.
+    pub const fn has_files(&self) -> bool {
+        !matches!(
+            self.storage,
+            Storage::Database {
+                files_dir: None,
+                ..
+            }
+        )
+    }
+
+    /// Export-relative paths of every file in the export.
+    pub fn file_names(&self) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+        match &self.storage {
+            Storage::Zip { archive, root, .. } => {
+                out.extend(
+                    archive
+                        .borrow()
+                        .file_names()
+                        .filter(|n| !n.ends_with('/'))
+                        .filter_map(|n| n.strip_prefix(root.as_str()))
+                        .map(str::to_owned),
+                );
+            }
+            Storage::Dir(dir) => list_files(dir, "", &mut out)?,
+            Storage::Database {
+                files_dir: Some(dir),
+                ..
+            } => list_files(dir, "files/", &mut out)?,
+            Storage::Database {
+                files_dir: None, ..
+            } => {}
+        }
+        Ok(out)
+    }
+
+    /// Whether the export contains the file `rel`.
+    pub fn contains(&self, rel: &str) -> bool {
+        match &self.storage {
+            Storage::Zip { archive, root, .. } => archive
+                .borrow()
+                .index_for_name(&format!("{root}{rel}"))
+                .is_some(),
+            _ => self.local_path(rel).is_some_and(|p| p.is_file()),
+        }
+    }
+
+    /// Stream the file `rel` through `f`, without loading it into memory.
+    pub fn with_file<T>(&self, rel: &str, f: impl FnOnce(&mut dyn Read) -> Result<T>) -> Result<T> {
+        match &self.storage {
+            Storage::Zip {
+                archive,
+                root,
+                password,
+            } => {
+                // Entries are only ever read by name, never written to disk under
+                // it, so hostile entry paths (Zip Slip) cannot escape anywhere.
+                let mut archive = archive.borrow_mut();
+                let name = format!("{root}{rel}");
+                let Some(index) = archive.index_for_name(&name) else {
+                    bail!("reading zip entry: {name}: not found in archive");
+                };
+                let entry = match password {
+                    Some(pw) => archive.by_index_decrypt(index, pw),
+                    None => archive.by_index(index),
+                }
+                .with_context(|| format!("reading zip entry: {name}"))?;
+
+                // Declared sizes were checked on open, but they can lie: enforce
+                // them (and the total budget) on the bytes actually produced.
+                let declared = entry.size();
+                let counted = self
+                    .entry_bytes_read
+                    .borrow()
+                    .get(&index)
+                    .copied()
+                    .unwrap_or(0);
+                let mut limited = LimitedReader {
+                    inner: entry,
+                    entry_remaining: declared,
+                    position: 0,
+                    already_counted: counted,
+                    total_read: &self.bytes_read,
+                    max_total: self.limits.max_total_bytes,
+                };
+                let result = f(&mut limited);
+                if limited.position > counted {
+                    self.entry_bytes_read
+                        .borrow_mut()
+                        .insert(index, limited.position);
+                }
+                result
+            }
+            _ => {
+                let Some(path) = self.local_path(rel) else {
+                    bail!("{rel} is not available: export is a bare database without --files-dir");
+                };
+                let mut file =
+                    File::open(&path).with_context(|| format!("opening: {}", path.display()))?;
+                f(&mut file)
+            }
+        }
+    }
+
+    /// Filesystem location of `rel` for directory-backed storage.
+    fn local_path(&self, rel: &str) -> Option<PathBuf> {
+        match &self.storage {
+            Storage::Dir(dir) => Some(dir.join(rel)),
+            Storage::Database {
+                files_dir: Some(files_dir),
+                ..
+            } => rel.strip_prefix("files/").map(|r| files_dir.join(r)),
+            Storage::Database {
+                files_dir: None, ..
+            }
+            | Storage::Zip { .. } => None,
+        }
+    }
+
+    /// Read the whole file `rel` into memory.
+    pub fn read_file(&self, rel: &str) -> Result<Vec<u8>> {
+        self.with_file(rel, |r| {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    /// Filesystem path of the Gadgetbridge SQLite DB, if the export has one.
+    ///
+    /// For ZIP exports the DB entry is copied to a temp file on first use; it
+    /// lives as long as this `Export`.
+    pub fn database_path(&self) -> Result<Option<PathBuf>> {
+        match &self.storage {
+            Storage::Dir(dir) => {
+                let p = dir.join(DB_REL_PATH);
+                Ok(p.exists().then_some(p))
+            }
+            Storage::Database { db, .. } => Ok(Some(db.clone())),
+            Storage::Zip { .. } => {
+                if let Some(tmp) = self.db_tmp.get() {
+                    return Ok(Some(tmp.path().to_path_buf()));
+                }
+                if !self.contains(DB_REL_PATH) {
+                    return Ok(None);
+                }
+
+                let mut tmp = NamedTempFile::new().context("creating temp file for export DB")?;
+                self.with_file(DB_REL_PATH, |r| {
+                    io::copy(r, tmp.as_file_mut()).context("extracting export DB")?;
+                    Ok(())
+                })?;
+                tracing::debug!(tmp = %tmp.path().display(), "extracted export DB");
+
+                Ok(Some(self.db_tmp.get_or_init(|| tmp).path().to_path_buf()))
+            }
+        }
+    }
+}
+
+/// Recursively collect the files below `dir` as `prefix`-prefixed relative paths.
+fn list_files(dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<()> {
+    for e in fs::read_dir(dir).with_context(|| format!("reading dir: {}", dir.display()))? {
+        let e = e?;
+        let name = e.file_name().to_string_lossy().into_owned();
+        let rel = format!("{prefix}{name}");
+        if e.file_type()?.is_dir() {
+            list_files(&e.path(), &format!("{rel}/"), out)?;
+        } else {
+            out.push(rel);
+        }
+    }
+    Ok(())
+}
+
+fn warn_unused_files_dir(opts: &ExportOptions) {
+    if opts.files_dir.is_some() {
+        tracing::warn!("--files-dir only applies to a bare SQLite DB; ignoring it");
+    }
+}
+
+/// Recognize the export format from the file's magic bytes.
+fn sniff_format(path: &Path) -> Result<Format> {
+    let mut head = Vec::with_capacity(512);
+    File::open(path)?.take(512).read_to_end(&mut head)?;
+
+    let format = if head.starts_with(b"SQLite format 3\0") {
+        Format::Sqlite
+    } else if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        Format::Zip
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        Format::Tar(Compression::Gzip)
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Format::Tar(Compression::Zstd)
+    } else if head.get(257..262) == Some(b"ustar") || has_extension(path, "tar") {
+        Format::Tar(Compression::None)
+    } else {
+        Format::Unknown
+    };
+    Ok(format)
+}
+
+/// Whether `path` is named like an archive `Export::open` can read.
+pub fn is_archive_path(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|s| s.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    [".zip", ".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Copy stdin (`input`) to a temp file: ZIP and SQLite need random access.
+fn spool(input: impl Read, limits: &ExportLimits) -> Result<NamedTempFile> {
+    let mut spool = NamedTempFile::new().context("creating temp file for stdin")?;
+    let copied = io::copy(
+        &mut input.take(limits.max_total_bytes + 1),
+        spool.as_file_mut(),
+    )
+    .context("reading export from stdin")?;
+
+    if copied > limits.max_total_bytes {
+        bail!(
+            "export on stdin is larger than {} MiB (--max-export-mib)",
+            limits.max_total_bytes / (1024 * 1024)
+        );
+    }
+    tracing::info!(bytes = copied, "read export from stdin");
+    Ok(spool)
+}
+
+/// Extract a (possibly compressed) tar archive to a temp dir, enforcing `limits`.
+///
+/// Only regular files and directories are extracted, and only to paths that
+/// stay inside the temp dir (the same guarantee zip's `enclosed_name` gives).
+fn extract_tar(path: &Path, compression: Compression, limits: &ExportLimits) -> Result<TempDir> {
+    let compressed = Cell::new(0);
+    let input = CountingReader {
+        inner: BufReader::new(File::open(path)?),
+        count: &compressed,
+    };
+    let reader: Box<dyn Read + '_> = match compression {
+        Compression::None => Box::new(input),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(input)?),
+    };
+
+    let tmp = tempfile::tempdir().context("creating tempdir for export archive")?;
+    tracing::info!(
+        archive = %path.display(),
+        tmp = %tmp.path().display(),
+        "extracting export archive"
+    );
+
+    let mut archive = tar::Archive::new(reader);
+    let extracted = Cell::new(0);
+    let mut entries = 0usize;
+
+    for entry in archive.entries().context("reading tar archive")? {
+        let mut entry = entry.context("reading tar entry")?;
+
+        entries += 1;
+        if entries > limits.max_entries {
+            bail!(
+                "archive has more than {} entries (--max-export-entries)",
+                limits.max_entries
+            );
+        }
+
+        let name = entry.path().context("reading tar entry path")?.into_owned();
+        let Some(rel) = enclosed_path(&name) else {
+            tracing::warn!(name = %name.display(), "skipping unsafe tar entry path");
+            continue;
+        };
+        let out_path = tmp.path().join(&rel);
+
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                fs::create_dir_all(&out_path)
+                    .with_context(|| format!("creating dir: {}", out_path.display()))?;
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("creating dir: {}", parent.display()))?;
+                }
+
+                let declared = entry.size();
+                let mut limited = LimitedReader {
+                    inner: &mut entry,
+                    entry_remaining: declared,
+                    position: 0,
+                    already_counted: 0,
+                    total_read: &extracted,
+                    max_total: limits.max_total_bytes,
+                };
+                let mut out = File::create(&out_path)
+                    .with_context(|| format!("creating file: {}", out_path.display()))?;
+                io::copy(&mut limited, &mut out)
+                    .with_context(|| format!("extracting file: {}", out_path.display()))?;
+            }
+            other => {
+                tracing::warn!(name = %name.display(), kind = ?other, "skipping non-regular tar entry");
+                continue;
+            }
+        }
+
+        let written = extracted.get();
+        if compression != Compression::None && written >= RATIO_CHECK_MIN_BYTES {
+            let ratio = written / compressed.get().max(1);
+            if ratio > limits.max_compression_ratio {
+                bail!(
+                    "archive decompresses at {ratio}:1, more than the limit of {}:1 \
+                     (--max-compression-ratio); possible zip bomb",
+                    limits.max_compression_ratio
+                );
+            }
+        }
+    }
+
+    Ok(tmp)
+}
+
+/// `path` as a relative path without `..`, root or prefix components.
+fn enclosed_path(path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(p) => out.push(p),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!out.as_os_str().is_empty()).then_some(out)
+}
+
+/// Find the export root in an extracted archive: the dir itself, or its
+/// single top-level directory.
+fn extracted_export_root(dir: &Path, layout: Layout) -> Result<Option<PathBuf>> {
+    if layout.is_root_dir(dir) {
+        return Ok(Some(dir.to_path_buf()));
+    }
+
+    let mut dirs = Vec::new();
+    for e in fs::read_dir(dir).context("reading extracted root dir")? {
+        let e = e?;
+        if e.file_type()?.is_dir() {
+            dirs.push(e.path());
+        }
+    }
+
+    Ok(match dirs.as_slice() {
+        [only] if layout.is_root_dir(only) => Some(only.clone()),
+        _ => None,
+    })
+}
+
+/// Find the export root inside a ZIP: either the archive root, or a single
+/// top-level directory (the common case for Gadgetbridge exports).
+fn zip_export_root(zip: &ZipArchive<File>, layout: Layout) -> Option<String> {
+    let looks_like_root = |prefix: &str| {
+        zip.file_names().any(|n| {
+            n.strip_prefix(prefix)
+                .is_some_and(|rel| layout.is_root_entry(rel))
+        })
+    };
+
+    if looks_like_root("") {
+        return Some(String::new());
+    }
+
+    let mut top_dirs: Vec<&str> = zip
+        .file_names()
+        .filter_map(|n| n.split_once('/').map(|(top, _)| top))
+        .collect();
+    top_dirs.sort_unstable();
+    top_dirs.dedup();
+
+    match top_dirs.as_slice() {
+        [top] => {
+            let prefix = format!("{top}/");
+            looks_like_root(&prefix).then_some(prefix)
+        }
+        _ => None,
+    }
+}
+
+fn zip_is_encrypted(zip: &mut ZipArchive<File>) -> Result<bool> {
+    for i in 0..zip.len() {
+        if zip
+            .by_index_raw(i)
+            .context("reading zip entry")?
+            .encrypted()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Password for an encrypted export: `--password-file`, then
+/// `$ROUDENN_EXPORT_PASSWORD`, then an interactive prompt unless
+/// `non_interactive` is set.
+fn export_password(opts: &ExportOptions, location: &Path) -> Result<Vec<u8>> {
+    if let Some(file) = &opts.password_file {
+        let text = fs::read_to_string(file)
+            .with_context(|| format!("reading password file: {}", file.display()))?;
+        return Ok(text.trim_end_matches(['\r', '\n']).as_bytes().to_vec());
+    }
+
+    if let Ok(pw) = env::var(PASSWORD_ENV) {
+        return Ok(pw.into_bytes());
+    }
+
+    if opts.non_interactive {
+        bail!(
+            "export {} is encrypted and no password was given \
+             (use --password-file or ${PASSWORD_ENV})",
+            location.display()
+        );
+    }
+
+    let pw = rpassword::prompt_password(format!("Password for {}: ", location.display()))
+        .with_context(|| {
+            format!(
+                "export {} is encrypted and no password was given \
+                 (use --password-file or ${PASSWORD_ENV})",
+                location.display()
+            )
+        })?;
+    Ok(pw.into_bytes())
+}
+
+/// Decrypt the smallest encrypted entry end to end, so a wrong password fails
+/// here with a clear message rather than halfway through the ingest.
+///
+/// Reading to the end matters for ZipCrypto: its header check only catches
+/// ~255/256 wrong passwords, the CRC at the end catches the rest.
+fn check_zip_password(zip: &mut ZipArchive<File>, password: &[u8]) -> Result<()> {
+    let mut smallest: Option<(usize, u64)> = None;
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).context("reading zip entry")?;
+        if entry.encrypted() && smallest.is_none_or(|(_, size)| entry.size() < size) {
+            smallest = Some((i, entry.size()));
+        }
+    }
+    let Some((index, _)) = smallest else {
+        return Ok(());
+    };
+
+    let wrong_password = || anyhow::anyhow!("wrong password for encrypted export");
+    let mut entry = match zip.by_index_decrypt(index, password) {
+        Ok(entry) => entry,
+        Err(ZipError::InvalidPassword) => return Err(wrong_password()),
+        Err(e) => return Err(e).context("reading encrypted zip entry"),
+    };
+    match io::copy(&mut entry, &mut io::sink()) {
+        Ok(_) => Ok(()),
+        Err(e) if is_wrong_password_error(&e) => Err(wrong_password()),
+        Err(e) => Err(e).context("reading encrypted zip entry"),
+    }
+}
+
+/// Whether a read error of a decrypted entry is the CRC (ZipCrypto) or
+/// authentication code (AES) mismatch a wrong password causes.
+///
+/// zip reports both as `InvalidData`, while a corrupt deflate stream is
+/// `InvalidInput`, so the kind is enough to tell them apart.
+fn is_wrong_password_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidData
+}
+
+/// Check entry count, declared total size and per-entry compression ratio
+/// before anything is decompressed.
+fn check_zip_limits(zip: &mut ZipArchive<File>, limits: &ExportLimits) -> Result<()> {
+    if zip.len() > limits.max_entries {
+        bail!(
+            "archive has {} entries, more than the limit of {} (--max-export-entries)",
+            zip.len(),
+            limits.max_entries
+        );
+    }
+
+    let mut total: u64 = 0;
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).context("reading zip entry")?;
+        let size = entry.size();
+        total = total.saturating_add(size);
+
+        if size >= RATIO_CHECK_MIN_BYTES {
+            let ratio = size / entry.compressed_size().max(1);
+            if ratio > limits.max_compression_ratio {
+                bail!(
+                    "entry {} has a compression ratio of {ratio}:1, more than the limit of {}:1 \
+                     (--max-compression-ratio); possible zip bomb",
+                    entry.name(),
+                    limits.max_compression_ratio
+                );
+            }
+        }
+    }
+
+    if total > limits.max_total_bytes {
+        bail!(
+            "archive uncompresses to {} MiB, more than the limit of {} MiB (--max-export-mib)",
+            total / (1024 * 1024),
+            limits.max_total_bytes / (1024 * 1024)
+        );
+    }
+
+    Ok(())
+}
+
+/// Reader counting the bytes that pass through it.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Reader that fails once an entry produces more than its declared size, or
+/// the export as a whole more than `max_total` bytes.
+struct LimitedReader<'a, R> {
+    inner: R,
+    entry_remaining: u64,
+    /// Bytes of the entry read so far.
+    position: u64,
+    /// Leading bytes of the entry already added to `total_read` by an earlier read.
+    already_counted: u64,
+    total_read: &'a Cell<u64>,
+    max_total: u64,
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let n_u64 = n as u64;
+
+        if n_u64 > self.entry_remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "zip entry is larger than its declared size; corrupted or malicious archive",
+            ));
+        }
+        self.entry_remaining -= n_u64;
+
+        let end = self.position + n_u64;
+        let new_bytes = end.saturating_sub(self.position.max(self.already_counted));
+        self.position = end;
+
+        let total = self.total_read.get() + new_bytes;
+        if total > self.max_total {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "export produced more than {} MiB of uncompressed data (--max-export-mib)",
+                    self.max_total / (1024 * 1024)
+                ),
+            ));
+        }
+        self.total_read.set(total);
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::AesMode;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn zip_export_is_read_in_place_below_its_top_level_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        write_zip(
+            &path,
+            &[
+                ("Gadgetbridge/gadgetbridge.json", b"{}"),
+                ("Gadgetbridge/files/track.gpx", b"<gpx/>"),
+                ("Gadgetbridge/database/Gadgetbridge", b"not really sqlite"),
+            ],
+        );
+
+        let export = Export::open(&path, &ExportOptions::default()).unwrap();
+        let mut names = export.file_names().unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "database/Gadgetbridge",
+                "files/track.gpx",
+                "gadgetbridge.json"
+            ]
+        );
+        assert!(export.contains("files/track.gpx"));
+        assert!(!export.contains("Gadgetbridge/files/track.gpx"));
+        assert_eq!(export.read_file("files/track.gpx").unwrap(), b"<gpx/>");
+        assert!(export.read_file("files/missing.gpx").is_err());
+
+        let db = export.database_path().unwrap().unwrap();
+        assert_eq!(fs::read(&db).unwrap(), b"not really sqlite");
+        assert_eq!(export.database_path().unwrap(), Some(db));
+    }
+
+    fn limited<'a>(
+        data: &'a [u8],
+        declared: u64,
+        total: &'a Cell<u64>,
+    ) -> LimitedReader<'a, &'a [u8]> {
+        LimitedReader {
+            inner: data,
+            entry_remaining: declared,
+            position: 0,
+            already_counted: 0,
+            total_read: total,
+            max_total: 8,
+        }
+    }
+
+    #[test]
+    fn zip_limits_are_checked_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        write_zip(
+            &path,
+            &[
+                ("files/zeros.bin", &vec![0; 2 * 1024 * 1024]),
+                ("gadgetbridge.json", b"{}"),
+            ],
+        );
+        let open = |limits| {
+            let opts = ExportOptions {
+                limits,
+                ..ExportOptions::default()
+            };
+            Export::open(&path, &opts).map(|_| ())
+        };
+        // 2 MiB of zeros deflate far better than the default 500:1.
+        let defaults = ExportLimits {
+            max_compression_ratio: 100_000,
+            ..ExportLimits::default()
+        };
+
+        assert!(open(defaults).is_ok());
+        assert!(
+            open(ExportLimits {
+                max_entries: 1,
+                ..defaults
+            })
+            .is_err()
+        );
+        assert!(
+            open(ExportLimits {
+                max_total_bytes: 1024 * 1024,
+                ..defaults
+            })
+            .is_err()
+        );
+        assert!(
+            open(ExportLimits {
+                max_compression_ratio: 10,
+                ..defaults
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn rereading_a_zip_entry_counts_it_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        write_zip(
+            &path,
+            &[("files/a.txt", b"0123456789"), ("gadgetbridge.json", b"{}")],
+        );
+        let opts = ExportOptions {
+            limits: ExportLimits {
+                max_total_bytes: 15,
+                ..ExportLimits::default()
+            },
+            ..ExportOptions::default()
+        };
+
+        let export = Export::open(&path, &opts).unwrap();
+        for _ in 0..3 {
+            assert_eq!(export.read_file("files/a.txt").unwrap(), b"0123456789");
+        }
+        assert_eq!(export.bytes_read.get(), 10);
+        // The budget still applies across entries: 10 + 2 bytes are fine.
+        export.read_file("gadgetbridge.json").unwrap();
+        assert_eq!(export.bytes_read.get(), 12);
+    }
+
+    #[test]
+    fn entry_larger_than_declared_is_refused() {
+        let total = Cell::new(0);
+        let mut buf = Vec::new();
+        assert!(limited(b"abcdef", 3, &total).read_to_end(&mut buf).is_err());
+    }
+
+    #[test]
+    fn total_budget_is_enforced_while_reading() {
+        let total = Cell::new(0);
+        let mut buf = Vec::new();
+        limited(b"abcdef", 6, &total).read_to_end(&mut buf).unwrap();
+        assert_eq!(total.get(), 6);
+        assert!(limited(b"ghi", 3, &total).read_to_end(&mut buf).is_err());
+    }
+
+    fn write_sqlite(path: &Path) {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        conn.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+    }
+
+    #[test]
+    fn bare_database_without_files_dir_has_no_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("Gadgetbridge");
+        write_sqlite(&db);
+
+        let export = Export::open(&db, &ExportOptions::default()).unwrap();
+        assert!(!export.has_files());
+        assert!(export.file_names().unwrap().is_empty());
+        assert!(!export.contains("files/track.gpx"));
+        assert!(export.read_file("files/track.gpx").is_err());
+        assert_eq!(export.database_path().unwrap(), Some(db));
+    }
+
+    #[test]
+    fn bare_database_files_dir_stands_in_for_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("Gadgetbridge");
+        write_sqlite(&db);
+        let files = dir.path().join("tracks");
+        fs::create_dir_all(files.join("sub")).unwrap();
+        fs::write(files.join("sub/track.gpx"), b"<gpx/>").unwrap();
+
+        let opts = ExportOptions {
+            files_dir: Some(files.clone()),
+            ..ExportOptions::default()
+        };
+        let export = Export::open(&db, &opts).unwrap();
+        assert!(export.has_files());
+        assert_eq!(export.file_names().unwrap(), ["files/sub/track.gpx"]);
+        assert!(export.contains("files/sub/track.gpx"));
+        assert!(!export.contains("sub/track.gpx"));
+        assert_eq!(export.read_file("files/sub/track.gpx").unwrap(), b"<gpx/>");
+
+        let opts = ExportOptions {
+            files_dir: Some(files.join("sub/track.gpx")),
+            ..ExportOptions::default()
+        };
+        assert!(Export::open(&db, &opts).is_err());
+    }
+
+    #[test]
+    fn bare_database_is_only_a_gadgetbridge_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("Gadgetbridge");
+        write_sqlite(&db);
+        assert!(Export::open_as(&db, &ExportOptions::default(), Layout::Strava).is_err());
+    }
+
+    fn tar_export() -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, data) in [
+            ("Gadgetbridge/gadgetbridge.json", &b"{}"[..]),
+            ("Gadgetbridge/files/track.gpx", b"<gpx/>"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn tar_exports_are_extracted_whatever_the_compression() {
+        let tar = tar_export();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+        let zst = zstd::encode_all(&tar[..], 0).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        // Names don't matter: the format is sniffed from the content.
+        for (name, data) in [("a.tar", &tar), ("b.bin", &gz), ("c.bin", &zst)] {
+            let path = dir.path().join(name);
+            fs::write(&path, data).unwrap();
+            let export = Export::open(&path, &ExportOptions::default()).unwrap();
+            assert_eq!(export.location(), path);
+            let mut names = export.file_names().unwrap();
+            names.sort();
+            assert_eq!(names, ["files/track.gpx", "gadgetbridge.json"], "{name}");
+            assert_eq!(export.read_file("files/track.gpx").unwrap(), b"<gpx/>");
+        }
+    }
+
+    #[test]
+    fn tar_entry_count_is_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.tar");
+        fs::write(&path, tar_export()).unwrap();
+        let opts = ExportOptions {
+            limits: ExportLimits {
+                max_entries: 1,
+                ..ExportLimits::default()
+            },
+            ..ExportOptions::default()
+        };
+        assert!(Export::open(&path, &opts).is_err());
+    }
+
+    #[test]
+    fn tar_paths_must_stay_enclosed() {
+        assert_eq!(
+            enclosed_path(Path::new("./a/b.gpx")),
+            Some(PathBuf::from("a/b.gpx"))
+        );
+        assert_eq!(enclosed_path(Path::new("a/../../b")), None);
+        assert_eq!(enclosed_path(Path::new("/etc/passwd")), None);
+        assert_eq!(enclosed_path(Path::new(".")), None);
+    }
+
+    #[test]
+    fn stdin_is_spooled_up_to_the_size_limit() {
+        let limits = ExportLimits {
+            max_total_bytes: 4,
+            ..ExportLimits::default()
+        };
+        let tmp = spool(&b"1234"[..], &limits).unwrap();
+        assert_eq!(fs::read(tmp.path()).unwrap(), b"1234");
+        assert!(spool(&b"12345"[..], &limits).is_err());
+    }
+
+    #[test]
+    fn encrypted_zip_needs_the_right_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().with_aes_encryption(AesMode::Aes256, "secret");
+        zip.start_file("files/track.gpx", options).unwrap();
+        zip.write_all(b"<gpx/>").unwrap();
+        zip.start_file("gadgetbridge.json", options).unwrap();
+        zip.write_all(b"{}").unwrap();
+        zip.finish().unwrap();
+
+        let open = |password: &str| {
+            let password_file = dir.path().join("password");
+            fs::write(&password_file, format!("{password}\n")).unwrap();
+            let opts = ExportOptions {
+                password_file: Some(password_file),
+                non_interactive: true,
+                ..ExportOptions::default()
+            };
+            Export::open(&path, &opts)
+        };
+
+        let export = open("secret").unwrap();
+        assert_eq!(export.read_file("files/track.gpx").unwrap(), b"<gpx/>");
+
+        let err = open("wrong").err().unwrap();
+        assert!(format!("{err:#}").contains("wrong password"), "{err:#}");
+    }
+
+    #[test]
+    fn only_data_errors_mean_a_wrong_password() {
+        let err = |kind| io::Error::new(kind, "x");
+        assert!(is_wrong_password_error(&err(io::ErrorKind::InvalidData)));
+        assert!(!is_wrong_password_error(&err(io::ErrorKind::InvalidInput)));
+        assert!(!is_wrong_password_error(&err(io::ErrorKind::UnexpectedEof)));
+    }
+
+    #[test]
+    fn zip_without_an_export_layout_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.zip");
+        write_zip(&path, &[("a/notes.txt", b"x"), ("b/notes.txt", b"y")]);
+        assert!(Export::open(&path, &ExportOptions::default()).is_err());
+    }
+}
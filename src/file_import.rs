@@ -0,0 +1,316 @@
+use crate::cli::ImportFilesArgs;
+use crate::fit::parse_fit;
+use crate::gpx::scan_track;
+use crate::ingest::{
+    connect_or_create_db, ensure_pg_schema, import_gpx, import_records,
+    refresh_workout_distance_matview, upsert_workout,
+};
+use crate::tcx::parse_tcx;
+use crate::types::{
+    ACTIVITY_KIND_OUTDOOR_RUNNING, ACTIVITY_KIND_TREADMILL, ACTIVITY_KIND_UNKNOWN,
+    SOURCE_IMPORTED_FILE, WorkoutSummary,
+};
+use crate::utils::{degrees_to_e7, has_extension};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use postgres::Client;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Extensions `import-files` picks up when walking a directory.
+const EXTENSIONS: [&str; 3] = ["gpx", "tcx", "fit"];
+
+/// What one file added.
+struct FileImported {
+    activity: String,
+    start: DateTime<Utc>,
+    points: usize,
+    samples: usize,
+}
+
+/// Import standalone GPX, TCX and FIT files (phone apps such as OpenTracks or
+/// OsmAnd, other services' exports) as workouts with
+/// `source = 'imported_file'`, timed by their own points.
+///
+/// Re-importing a file updates its workout. Unreadable files are logged and
+/// skipped.
+pub fn run(pg_url: &str, args: &ImportFilesArgs) -> Result<()> {
+    let files = collect_files(&args.paths)?;
+    tracing::info!(files = files.len(), "found files to import");
+
+    let mut pg = connect_or_create_db(pg_url)?;
+    ensure_pg_schema(&mut pg)?;
+
+    let mut workouts_upserted = 0usize;
+    let mut points_imported = 0usize;
+    let mut samples_imported = 0usize;
+    let mut files_skipped = 0usize;
+    let mut files_failed = 0usize;
+
+    for path in &files {
+        match import_file(&mut pg, path, args.activity.as_deref()) {
+            Ok(Some(imported)) => {
+                tracing::info!(
+                    path = %path.display(),
+                    activity = %imported.activity,
+                    start = %imported.start,
+                    points = imported.points,
+                    samples = imported.samples,
+                    "imported file"
+                );
+                workouts_upserted += 1;
+                points_imported += imported.points;
+                samples_imported += imported.samples;
+            }
+            Ok(None) => {
+                tracing::debug!(path = %path.display(), "skipping fit file that is not an activity");
+                files_skipped += 1;
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), err = format!("{e:#}"), "importing file failed");
+                files_failed += 1;
+            }
+        }
+    }
+
+    refresh_workout_distance_matview(&mut pg)?;
+    tracing::info!(
+        workouts_upserted,
+        points_imported,
+        samples_imported,
+        files_skipped,
+        files_failed,
+        "import done"
+    );
+    Ok(())
+}
+
+/// The files named on the command line, and the GPX, TCX and FIT files below
+/// the directories, in path order. Paths are made absolute, as they are stored.
+fn collect_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    for path in paths {
+        let path = fs::canonicalize(path).with_context(|| format!("{}", path.display()))?;
+        if path.is_dir() {
+            let mut found = Vec::new();
+            walk_dir(&path, &mut found)?;
+            found.sort();
+            out.extend(found);
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(out)
+}
+
+fn walk_dir(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for e in fs::read_dir(dir).with_context(|| format!("reading dir: {}", dir.display()))? {
+        let path = e?.path();
+        if path.is_dir() {
+            walk_dir(&path, out)?;
+        } else if EXTENSIONS.iter().any(|ext| has_extension(&path, ext)) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `None` for FIT files that aren't activities.
+fn import_file(
+    pg: &mut Client,
+    path: &Path,
+    activity: Option<&str>,
+) -> Result<Option<FileImported>> {
+    let path_str = path.to_string_lossy().into_owned();
+    let open = || {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("opening {}", path.display()))
+    };
+
+    if has_extension(path, "gpx") {
+        let Some(scan) = scan_track(open()?)? else {
+            bail!("no timestamped track points");
+        };
+        let activity = activity
+            .or(scan.track_type.as_deref())
+            .map_or_else(|| "other".to_owned(), activity_from_name);
+
+        let mut summary = file_summary(path, scan.start, scan.end, &activity);
+        summary.name = scan.track_name.or(summary.name);
+        summary.base_longitude_e7 = Some(degrees_to_e7(scan.first_lon));
+        summary.base_latitude_e7 = Some(degrees_to_e7(scan.first_lat));
+        summary.gpx_track_android = Some(path_str.clone());
+
+        let workout_id = upsert_workout(pg, &summary, &activity)?;
+        let gpx = import_gpx(pg, &mut open()?, &path_str, workout_id, None)?;
+        return Ok(Some(FileImported {
+            activity,
+            start: summary.start,
+            points: gpx.points,
+            samples: 0,
+        }));
+    }
+
+    let (summary, records, activity) = if has_extension(path, "tcx") {
+        let tcx = parse_tcx(open()?)?;
+        let activity = match (activity, tcx.sport.as_deref()) {
+            (Some(a), _) => activity_from_name(a),
+            (None, Some(sport)) => {
+                let a = activity_from_name(sport);
+                // TCX has no treadmill sport; a run without positions is one.
+                if a == "outdoor_running" && tcx.records.points.is_empty() {
+                    "treadmill".to_owned()
+                } else {
+                    a
+                }
+            }
+            (None, None) => "other".to_owned(),
+        };
+
+        let mut summary = file_summary(path, tcx.start, tcx.end, &activity);
+        summary.name = tcx.notes.or(summary.name);
+        let base = tcx.records.points.first();
+        summary.base_longitude_e7 = base.map(|p| degrees_to_e7(p.lon));
+        summary.base_latitude_e7 = base.map(|p| degrees_to_e7(p.lat));
+        summary.summary_data_raw = tcx.summary_data_json.as_ref().map(ToString::to_string);
+        summary.summary_data_json = tcx.summary_data_json;
+        (summary, tcx.records, activity)
+    } else if has_extension(path, "fit") {
+        let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let Some(fit) = parse_fit(&bytes, SOURCE_IMPORTED_FILE)? else {
+            return Ok(None);
+        };
+
+        let mut summary = fit.summary;
+        let activity = match activity {
+            Some(a) => {
+                let a = activity_from_name(a);
+                summary.activity_kind = activity_kind(&a);
+                a
+            }
+            None => fit.activity.to_owned(),
+        };
+        summary.name = file_name(path);
+        summary.external_id = Some(path_str.clone());
+        summary.raw_details_android = Some(path_str);
+        summary.raw_details = Some(bytes);
+        (summary, fit.records, activity)
+    } else {
+        bail!("not a GPX, TCX or FIT file");
+    };
+
+    let workout_id = upsert_workout(pg, &summary, &activity)?;
+    let imported = import_records(pg, workout_id, &records, true)?;
+    Ok(Some(FileImported {
+        activity,
+        start: summary.start,
+        points: imported.points,
+        samples: imported.samples,
+    }))
+}
+
+/// A workout named after the file, with nothing but its times and activity.
+/// It is keyed on the file's canonical path, so re-imports update it.
+fn file_summary(
+    path: &Path,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    activity: &str,
+) -> WorkoutSummary {
+    WorkoutSummary {
+        name: file_name(path),
+        activity_kind: activity_kind(activity),
+        external_id: Some(path.to_string_lossy().into_owned()),
+        ..WorkoutSummary::standalone(SOURCE_IMPORTED_FILE, start, end)
+    }
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    path.file_stem().and_then(|s| s.to_str()).map(str::to_owned)
+}
+
+/// `workouts.activity` for a free-form activity name: a GPX `<type>`, a TCX
+/// `Sport`, a Strava activity type or `--activity`. Common spellings of the
+/// activities Gadgetbridge records are folded together; anything else is
+/// kept, in snake case (`WeightTraining`, `Weight Training` -> `weight_training`),
+/// if that leaves only `[a-z0-9_]`, and is `other` otherwise.
+pub(crate) fn activity_from_name(name: &str) -> String {
+    let key = snake_case(name.trim());
+    match key.as_str() {
+        // Numbers are the activity codes of Strava's older GPX exports.
+        "running" | "run" | "outdoor_running" | "trail_running" | "trail_run" | "9" => {
+            "outdoor_running"
+        }
+        "treadmill" | "treadmill_running" | "indoor_running" | "virtual_run" => "treadmill",
+        "cycling" | "biking" | "bike" | "ride" | "1" => "cycling",
+        "walking" | "walk" | "10" => "walking",
+        "hiking" | "hike" | "4" => "hiking",
+        "swimming" | "swim" => "swimming",
+        _ if !key.is_empty()
+            && key
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_') =>
+        {
+            return key;
+        }
+        _ => "other",
+    }
+    .to_owned()
+}
+
+fn snake_case(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 4);
+    let mut prev_lower = false;
+    for c in s.chars() {
+        if c == ' ' || c == '-' {
+            out.push('_');
+            prev_lower = false;
+            continue;
+        }
+        if c.is_uppercase() && prev_lower {
+            out.push('_');
+        }
+        out.extend(c.to_lowercase());
+        prev_lower = c.is_lowercase() || c.is_ascii_digit();
+    }
+    out
+}
+
+/// Gadgetbridge `ACTIVITY_KIND` of a `workouts.activity`.
+pub(crate) fn activity_kind(activity: &str) -> i32 {
+    match activity {
+        "outdoor_running" => ACTIVITY_KIND_OUTDOOR_RUNNING,
+        "treadmill" => ACTIVITY_KIND_TREADMILL,
+        _ => ACTIVITY_KIND_UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snake_case_splits_words_and_camel_case() {
+        assert_eq!(snake_case("WeightTraining"), "weight_training");
+        assert_eq!(snake_case("Weight Training"), "weight_training");
+        assert_eq!(snake_case("trail-run"), "trail_run");
+        assert_eq!(snake_case("E-Bike Ride"), "e_bike_ride");
+        assert_eq!(snake_case("HIIT"), "hiit");
+    }
+
+    #[test]
+    fn activity_names_fold_to_labels() {
+        assert_eq!(activity_from_name("Running"), "outdoor_running");
+        assert_eq!(activity_from_name(" Trail Run "), "outdoor_running");
+        assert_eq!(activity_from_name("VirtualRun"), "treadmill");
+        assert_eq!(activity_from_name("Biking"), "cycling");
+        assert_eq!(activity_from_name("9"), "outdoor_running");
+        assert_eq!(activity_from_name("WeightTraining"), "weight_training");
+        assert_eq!(activity_from_name(""), "other");
+        assert_eq!(activity_from_name("../../etc"), "other");
+        assert_eq!(activity_from_name("Ski/Snowboard"), "other");
+        assert_eq!(activity_from_name("Course à pied"), "other");
+    }
+}
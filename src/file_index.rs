@@ -0,0 +1,170 @@
+use crate::export::Export;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Index of every file in an export, used to resolve the Android paths the DB
+/// stores (`GPX_TRACK`, `RAW_DETAILS_PATH`) to files in the export.
+///
+/// Exports don't always mirror the phone's layout: files may sit in
+/// per-device subdirectories, be renamed by sync or archiving tools (case,
+/// `:` in timestamps), or share a basename across devices. Paths are therefore
+/// matched on their normalized basename first, then on progressively longer
+/// suffixes until a single file remains.
+pub struct FileIndex {
+    /// Normalized basename -> export-relative paths.
+    by_name: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Found(String),
+    Missing,
+    /// Several files match every suffix of the Android path.
+    Ambiguous(Vec<String>),
+}
+
+impl FileIndex {
+    pub fn build(export: &Export) -> Result<Self> {
+        let mut by_name: HashMap<String, Vec<String>> = HashMap::new();
+        for rel in export.file_names()? {
+            if let Some(name) = components(&rel).last() {
+                by_name.entry(normalize(name)).or_default().push(rel);
+            }
+        }
+        Ok(Self { by_name })
+    }
+
+    /// Every indexed file, in no particular order.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.by_name.values().flatten().map(String::as_str)
+    }
+
+    pub fn resolve(&self, android_path: &str) -> Resolution {
+        let wanted: Vec<String> = components(android_path).map(normalize).collect();
+        let Some(name) = wanted.last() else {
+            return Resolution::Missing;
+        };
+        let Some(candidates) = self.by_name.get(name) else {
+            return Resolution::Missing;
+        };
+
+        let mut remaining: Vec<&String> = candidates.iter().collect();
+        for len in 2..=wanted.len() {
+            if remaining.len() <= 1 {
+                break;
+            }
+            let suffix = &wanted[wanted.len() - len..];
+            let narrowed: Vec<&String> = remaining
+                .iter()
+                .copied()
+                .filter(|c| ends_with_components(c, suffix))
+                .collect();
+            if narrowed.is_empty() {
+                break; // the export's layout diverges from the phone's here
+            }
+            remaining = narrowed;
+        }
+
+        match remaining.as_slice() {
+            [only] => Resolution::Found((*only).clone()),
+            _ => {
+                let mut all: Vec<String> = remaining.into_iter().cloned().collect();
+                all.sort();
+                Resolution::Ambiguous(all)
+            }
+        }
+    }
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split(['/', '\\']).filter(|c| !c.is_empty())
+}
+
+fn ends_with_components(rel: &str, suffix: &[String]) -> bool {
+    let have: Vec<String> = components(rel).map(normalize).collect();
+    have.ends_with(suffix)
+}
+
+/// Case-insensitive, and lenient about characters that file systems or
+/// archivers commonly replace (e.g. `:` in timestamps becoming `_`).
+fn normalize(component: &str) -> String {
+    component
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-') {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::ExportOptions;
+    use std::fs;
+
+    fn index(files: &[&str]) -> FileIndex {
+        let dir = tempfile::tempdir().unwrap();
+        for rel in files {
+            let path = dir.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let export = Export::open(dir.path(), &ExportOptions::default()).unwrap();
+        FileIndex::build(&export).unwrap()
+    }
+
+    const PHONE: &str =
+        "/storage/emulated/0/Android/data/nodomain.freeyourgadget.gadgetbridge/files";
+
+    #[test]
+    fn unique_basename_is_found_anywhere() {
+        let idx = index(&["files/device-a/track.gpx"]);
+        assert_eq!(
+            idx.resolve(&format!("{PHONE}/track.gpx")),
+            Resolution::Found("files/device-a/track.gpx".into())
+        );
+        assert_eq!(
+            idx.resolve(&format!("{PHONE}/other.gpx")),
+            Resolution::Missing
+        );
+        assert_eq!(idx.resolve(""), Resolution::Missing);
+    }
+
+    #[test]
+    fn names_are_matched_after_normalization() {
+        let idx = index(&["files/2024-05-01T07_30_00.GPX"]);
+        assert_eq!(
+            idx.resolve(&format!("{PHONE}/2024-05-01T07:30:00.gpx")),
+            Resolution::Found("files/2024-05-01T07_30_00.GPX".into())
+        );
+    }
+
+    #[test]
+    fn longer_suffixes_narrow_shared_basenames() {
+        let idx = index(&["files/watch/track.gpx", "files/band/track.gpx"]);
+        assert_eq!(
+            idx.resolve(&format!("{PHONE}/band/track.gpx")),
+            Resolution::Found("files/band/track.gpx".into())
+        );
+        assert_eq!(
+            idx.resolve("C:\\Export\\files\\watch\\track.gpx"),
+            Resolution::Found("files/watch/track.gpx".into())
+        );
+    }
+
+    #[test]
+    fn unresolvable_shared_basenames_are_ambiguous() {
+        let idx = index(&["files/watch/track.gpx", "files/band/track.gpx"]);
+        assert_eq!(
+            idx.resolve(&format!("{PHONE}/ring/track.gpx")),
+            Resolution::Ambiguous(vec![
+                "files/band/track.gpx".into(),
+                "files/watch/track.gpx".into(),
+            ])
+        );
+    }
+}
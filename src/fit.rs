@@ -0,0 +1,730 @@
+use crate::file_import::activity_kind;
+use crate::fit_writer::crc;
+use crate::types::{GpxPoint, WorkoutRecords, WorkoutSample, WorkoutSummary};
+use crate::utils::{self, degrees_to_e7};
+use anyhow::{Result, bail};
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use serde_json::Value as JsonValue;
+
+/// FIT timestamps count seconds from 1989-12-31T00:00:00Z.
+const FIT_EPOCH_UNIX: i64 = 631_065_600;
+
+// Global message numbers.
+const MESG_FILE_ID: u16 = 0;
+const MESG_SPORT: u16 = 12;
+const MESG_SESSION: u16 = 18;
+const MESG_RECORD: u16 = 20;
+const MESG_EVENT: u16 = 21;
+
+const FILE_TYPE_ACTIVITY: i64 = 4;
+const EVENT_TIMER: i64 = 0;
+const EVENT_TYPE_STOP: i64 = 1;
+const EVENT_TYPE_STOP_ALL: i64 = 4;
+
+const FIELD_TIMESTAMP: u8 = 253;
+
+/// What an activity FIT file holds, as roudenn stores it.
+#[derive(Debug, Clone)]
+pub struct FitActivity {
+    /// Timed by the session, or by the records when the file has none (a
+    /// recording cut short).
+    pub summary: WorkoutSummary,
+    pub records: WorkoutRecords,
+    /// `workouts.activity` of the session's sport.
+    pub activity: &'static str,
+}
+
+/// Whether `bytes` look like a FIT file (`.FIT` signature in the header).
+pub fn is_fit(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[8..12] == b".FIT"
+}
+
+/// Decode a FIT file. Returns `None` for FIT files that aren't activities
+/// (settings, courses, daily monitoring, ...).
+///
+/// Truncated files and bad CRCs are decoded as far as possible, with a
+/// warning: watches write them when they crash or run out of battery.
+pub fn parse_fit(bytes: &[u8], source: &'static str) -> Result<Option<FitActivity>> {
+    let mut activity = ActivityBuilder::default();
+    let mut offset = 0;
+
+    // A file may chain several FIT files one after the other.
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        if !is_fit(rest) {
+            if offset == 0 {
+                bail!("not a FIT file");
+            }
+            tracing::warn!(offset, "trailing bytes after FIT data; ignoring them");
+            break;
+        }
+        let header_size = usize::from(rest[0]);
+        let data_size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        if header_size < 12 || header_size > rest.len() {
+            bail!("bad FIT header size {header_size}");
+        }
+
+        let data_end = header_size + data_size;
+        let data = if data_end > rest.len() {
+            tracing::warn!(
+                expected = data_size,
+                found = rest.len() - header_size,
+                "truncated FIT file"
+            );
+            &rest[header_size..]
+        } else {
+            if let Some(stored) = rest.get(data_end..data_end + 2) {
+                let stored = u16::from_le_bytes([stored[0], stored[1]]);
+                if stored != 0 && crc(0, &rest[..data_end]) != stored {
+                    tracing::warn!("FIT file CRC mismatch; decoding anyway");
+                }
+            }
+            &rest[header_size..data_end]
+        };
+
+        decode_messages(data, &mut activity)?;
+        offset += data_end + 2;
+    }
+
+    activity.finish(source)
+}
+
+/// A definition message: how to read the data messages of one local type.
+#[derive(Debug, Clone)]
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    fields: Vec<FieldDef>,
+    /// Total size of developer fields, which are skipped.
+    developer_size: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FieldDef {
+    num: u8,
+    size: u8,
+    base_type: u8,
+}
+
+/// Walk the records of one FIT file's data section, handing each data message
+/// to `activity` with its integer fields.
+fn decode_messages(data: &[u8], activity: &mut ActivityBuilder) -> Result<()> {
+    let mut defs: [Option<Definition>; 16] = Default::default();
+    let mut last_timestamp: Option<u32> = None;
+    let mut fields: Vec<(u8, i64)> = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let header = data[pos];
+        pos += 1;
+
+        let (local, compressed_time) = if header & 0x80 != 0 {
+            // Compressed timestamp header: 5 bits of seconds since the last timestamp.
+            let local = usize::from((header >> 5) & 0x03);
+            let offset = u32::from(header & 0x1F);
+            // Like the full timestamps it counts from, this rolls over at 2^32.
+            let t = last_timestamp.map(|last| {
+                let base = last & !0x1F;
+                if offset >= last & 0x1F {
+                    base | offset
+                } else {
+                    (base | offset).wrapping_add(0x20)
+                }
+            });
+            (local, t)
+        } else if header & 0x40 != 0 {
+            let Some(def) = read_definition(data, &mut pos, header & 0x20 != 0) else {
+                tracing::warn!(offset = pos, "truncated FIT definition message");
+                return Ok(());
+            };
+            defs[usize::from(header & 0x0F)] = Some(def);
+            continue;
+        } else {
+            (usize::from(header & 0x0F), None)
+        };
+
+        let Some(def) = &defs[local] else {
+            bail!("FIT data message for undefined local type {local} at byte {pos}");
+        };
+        let size: usize = def
+            .fields
+            .iter()
+            .map(|f| usize::from(f.size))
+            .sum::<usize>()
+            + def.developer_size;
+        let Some(body) = data.get(pos..pos + size) else {
+            tracing::warn!(offset = pos, "truncated FIT data message");
+            return Ok(());
+        };
+        pos += size;
+
+        fields.clear();
+        let mut at = 0;
+        for f in &def.fields {
+            let raw = &body[at..at + usize::from(f.size)];
+            at += usize::from(f.size);
+            if let Some(v) = read_integer(raw, f.base_type, def.big_endian) {
+                fields.push((f.num, v));
+            }
+        }
+
+        let timestamp = field(&fields, FIELD_TIMESTAMP)
+            .and_then(|v| u32::try_from(v).ok())
+            .or(compressed_time);
+        if let Some(t) = timestamp {
+            last_timestamp = Some(t);
+        }
+        activity.message(def.global, &fields, timestamp);
+    }
+    Ok(())
+}
+
+fn read_definition(data: &[u8], pos: &mut usize, developer: bool) -> Option<Definition> {
+    let fixed = data.get(*pos..*pos + 5)?;
+    let big_endian = fixed[1] == 1;
+    let global = if big_endian {
+        u16::from_be_bytes([fixed[2], fixed[3]])
+    } else {
+        u16::from_le_bytes([fixed[2], fixed[3]])
+    };
+    let count = usize::from(fixed[4]);
+    *pos += 5;
+
+    let raw = data.get(*pos..*pos + count * 3)?;
+    *pos += count * 3;
+    let fields = raw
+        .chunks_exact(3)
+        .map(|f| FieldDef {
+            num: f[0],
+            size: f[1],
+            base_type: f[2],
+        })
+        .collect();
+
+    let mut developer_size = 0;
+    if developer {
+        let count = usize::from(*data.get(*pos)?);
+        *pos += 1;
+        let raw = data.get(*pos..*pos + count * 3)?;
+        *pos += count * 3;
+        developer_size = raw.chunks_exact(3).map(|f| usize::from(f[1])).sum();
+    }
+
+    Some(Definition {
+        global,
+        big_endian,
+        fields,
+        developer_size,
+    })
+}
+
+/// The value of an integer field, or `None` if it holds its base type's
+/// invalid value. Strings, floats and arrays aren't needed and read as `None`.
+fn read_integer(raw: &[u8], base_type: u8, big_endian: bool) -> Option<i64> {
+    let unsigned = |width: usize| -> Option<u64> {
+        if raw.len() != width {
+            return None;
+        }
+        let mut buf = [0u8; 8];
+        if big_endian {
+            buf[8 - width..].copy_from_slice(raw);
+            Some(u64::from_be_bytes(buf))
+        } else {
+            buf[..width].copy_from_slice(raw);
+            Some(u64::from_le_bytes(buf))
+        }
+    };
+
+    match base_type & 0x1F {
+        // enum, uint8, byte
+        0x00 | 0x02 | 0x0D => unsigned(1).filter(|v| *v != 0xFF).map(|v| v as i64),
+        // uint8z, uint16z, uint32z, uint64z
+        0x0A => unsigned(1).filter(|v| *v != 0).map(|v| v as i64),
+        0x0B => unsigned(2).filter(|v| *v != 0).map(|v| v as i64),
+        0x0C => unsigned(4).filter(|v| *v != 0).map(|v| v as i64),
+        0x10 => unsigned(8)
+            .filter(|v| *v != 0)
+            .and_then(|v| i64::try_from(v).ok()),
+        0x01 => unsigned(1)
+            .map(|v| i64::from(v as u8 as i8))
+            .filter(|v| *v != 0x7F),
+        0x03 => unsigned(2)
+            .map(|v| i64::from(v as u16 as i16))
+            .filter(|v| *v != 0x7FFF),
+        0x04 => unsigned(2).filter(|v| *v != 0xFFFF).map(|v| v as i64),
+        0x05 => unsigned(4)
+            .map(|v| i64::from(v as u32 as i32))
+            .filter(|v| *v != 0x7FFF_FFFF),
+        0x06 => unsigned(4).filter(|v| *v != 0xFFFF_FFFF).map(|v| v as i64),
+        0x0E => unsigned(8).map(|v| v as i64).filter(|v| *v != i64::MAX),
+        0x0F => unsigned(8)
+            .filter(|v| *v != u64::MAX)
+            .and_then(|v| i64::try_from(v).ok()),
+        _ => None,
+    }
+}
+
+fn field(fields: &[(u8, i64)], num: u8) -> Option<i64> {
+    fields.iter().find(|(n, _)| *n == num).map(|(_, v)| *v)
+}
+
+fn fit_time(t: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(t + FIT_EPOCH_UNIX, 0).single()
+}
+
+fn degrees(semicircles: i64) -> f64 {
+    semicircles as f64 * (180.0 / f64::from(1u32 << 31))
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    start: Option<DateTime<Utc>>,
+    elapsed_s: Option<f64>,
+    timer_s: Option<f64>,
+    distance_m: Option<f64>,
+    calories: Option<i64>,
+    avg_hr: Option<i64>,
+    max_hr: Option<i64>,
+    sport: Option<i64>,
+    sub_sport: Option<i64>,
+    start_position: Option<(f64, f64)>,
+}
+
+/// Collects the messages roudenn uses, in file order.
+#[derive(Default)]
+struct ActivityBuilder {
+    file_type: Option<i64>,
+    sessions: Vec<Session>,
+    /// `sport` message, used when the file has no session.
+    sport: Option<(Option<i64>, Option<i64>)>,
+    records: WorkoutRecords,
+    seg: i32,
+    /// A timer stop was seen: the next point starts a new segment.
+    paused: bool,
+    first_record: Option<DateTime<Utc>>,
+    last_record: Option<DateTime<Utc>>,
+}
+
+impl ActivityBuilder {
+    fn message(&mut self, global: u16, fields: &[(u8, i64)], timestamp: Option<u32>) {
+        let get = |num| field(fields, num);
+        let timestamp = timestamp.and_then(|t| fit_time(i64::from(t)));
+        match global {
+            MESG_FILE_ID => self.file_type = get(0),
+            MESG_SPORT => self.sport = Some((get(0), get(1))),
+            MESG_SESSION => self.sessions.push(Session {
+                start: get(2).and_then(fit_time),
+                elapsed_s: get(7).map(|v| v as f64 / 1000.0),
+                timer_s: get(8).map(|v| v as f64 / 1000.0),
+                distance_m: get(9).map(|v| v as f64 / 100.0),
+                calories: get(11),
+                avg_hr: get(16),
+                max_hr: get(17),
+                sport: get(5),
+                sub_sport: get(6),
+                start_position: get(3)
+                    .zip(get(4))
+                    .map(|(lat, lon)| (degrees(lat), degrees(lon))),
+            }),
+            MESG_EVENT
+                if get(0) == Some(EVENT_TIMER)
+                    && matches!(get(1), Some(EVENT_TYPE_STOP | EVENT_TYPE_STOP_ALL)) =>
+            {
+                self.paused = true;
+            }
+            MESG_RECORD => {
+                if let Some(t) = timestamp {
+                    self.record(fields, t);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record(&mut self, fields: &[(u8, i64)], t: DateTime<Utc>) {
+        let get = |num| field(fields, num);
+        self.first_record.get_or_insert(t);
+        self.last_record = Some(t);
+
+        // Scale 5, offset 500 m.
+        let ele = get(78).or_else(|| get(2)).map(|v| (v - 2500) as f64 / 5.0);
+        let speed = get(73).or_else(|| get(6)).map(|v| v as f64 / 1000.0);
+        let hr = get(3).and_then(|v| i32::try_from(v).ok());
+        let cad = get(4).and_then(|v| i32::try_from(v).ok());
+        let atemp = get(13).map(|v| v as f64);
+
+        let idx = i32::try_from(self.records.samples.len()).unwrap_or(i32::MAX);
+        self.records.samples.push(WorkoutSample {
+            idx,
+            t,
+            hr,
+            cad,
+            speed,
+            distance_m: get(5).map(|v| v as f64 / 100.0),
+            ele,
+            power: get(7).and_then(|v| i32::try_from(v).ok()),
+            atemp,
+        });
+
+        let (Some(lat), Some(lon)) = (get(0), get(1)) else {
+            return;
+        };
+        if self.paused && !self.records.points.is_empty() {
+            self.seg += 1;
+        }
+        self.paused = false;
+        let idx = i32::try_from(self.records.points.len()).unwrap_or(i32::MAX);
+        self.records.points.push(GpxPoint {
+            idx,
+            trk: 0,
+            seg: self.seg,
+            t,
+            lat: degrees(lat),
+            lon: degrees(lon),
+            ele,
+            hr,
+            cad,
+            atemp,
+            speed,
+            course: None,
+        });
+    }
+
+    fn finish(self, source: &'static str) -> Result<Option<FitActivity>> {
+        if self.file_type.is_some_and(|t| t != FILE_TYPE_ACTIVITY) {
+            return Ok(None);
+        }
+
+        let first = self.sessions.first();
+        let start = self
+            .sessions
+            .iter()
+            .filter_map(|s| s.start)
+            .min()
+            .or(self.first_record);
+        let session_end = self
+            .sessions
+            .iter()
+            .filter_map(|s| {
+                let elapsed = TimeDelta::milliseconds((s.elapsed_s? * 1000.0) as i64);
+                Some(s.start? + elapsed)
+            })
+            .max();
+        let end = session_end.max(self.last_record);
+        let (Some(start), Some(end)) = (start, end) else {
+            bail!("FIT activity without timestamps");
+        };
+
+        let (sport, sub_sport) = match first {
+            Some(s) => (s.sport, s.sub_sport),
+            None => self.sport.unwrap_or_default(),
+        };
+
+        let base = first
+            .and_then(|s| s.start_position)
+            .or_else(|| self.records.points.first().map(|p| (p.lat, p.lon)));
+
+        let activity = fit_activity(sport, sub_sport);
+        let summary_data_json = session_summary_json(&self.sessions);
+        Ok(Some(FitActivity {
+            summary: WorkoutSummary {
+                activity_kind: activity_kind(activity),
+                base_longitude_e7: base.map(|(_, lon)| degrees_to_e7(lon)),
+                base_latitude_e7: base.map(|(lat, _)| degrees_to_e7(lat)),
+                summary_data_raw: summary_data_json.as_ref().map(ToString::to_string),
+                summary_data_json,
+                ..WorkoutSummary::standalone(source, start, end)
+            },
+            records: self.records,
+            activity,
+        }))
+    }
+}
+
+/// `workouts.activity` of a FIT `(sport, sub_sport)`, using the labels of
+/// [`activity_from_name`](crate::file_import::activity_from_name): running is
+/// an outdoor run unless on a treadmill (1) or indoors (45).
+fn fit_activity(sport: Option<i64>, sub_sport: Option<i64>) -> &'static str {
+    match (sport, sub_sport) {
+        (Some(1), Some(1 | 45)) => "treadmill",
+        (Some(1), _) => "outdoor_running",
+        (Some(2), _) => "cycling",
+        (Some(5), _) => "swimming",
+        (Some(11), _) => "walking",
+        (Some(17), _) => "hiking",
+        _ => "other",
+    }
+}
+
+/// Session totals, see [`utils::summary_json`].
+fn session_summary_json(sessions: &[Session]) -> Option<JsonValue> {
+    let sum_f = |f: fn(&Session) -> Option<f64>| -> Option<f64> {
+        sessions.iter().filter_map(f).reduce(|a, b| a + b)
+    };
+    let entries = [
+        ("distanceMeters", sum_f(|s| s.distance_m), "meters"),
+        ("activeSeconds", sum_f(|s| s.timer_s), "seconds"),
+        (
+            "caloriesBurnt",
+            sum_f(|s| s.calories.map(|v| v as f64)),
+            "calories_unit",
+        ),
+        (
+            "averageHR",
+            sessions.first().and_then(|s| s.avg_hr).map(|v| v as f64),
+            "bpm",
+        ),
+        (
+            "maxHR",
+            sessions
+                .iter()
+                .filter_map(|s| s.max_hr)
+                .max()
+                .map(|v| v as f64),
+            "bpm",
+        ),
+    ];
+    utils::summary_json(&entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_writer::write_fit;
+    use crate::stored::StoredWorkout;
+    use crate::types::SOURCE_IMPORTED_FILE;
+    use serde_json::json;
+
+    fn time(s: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_750_000_000 + s, 0).unwrap()
+    }
+
+    fn point(idx: i32, seg: i32, s: i64, hr: Option<i32>) -> GpxPoint {
+        GpxPoint {
+            idx,
+            trk: 0,
+            seg,
+            t: time(s),
+            lat: 48.0 + f64::from(idx) * 0.0005,
+            lon: -4.0,
+            ele: (idx % 2 == 0).then_some(12.4),
+            hr,
+            cad: Some(85),
+            atemp: None,
+            speed: Some(3.2),
+            course: None,
+        }
+    }
+
+    fn workout(activity: &str) -> StoredWorkout {
+        StoredWorkout {
+            id: 7,
+            uuid: "00000000-0000-0000-0000-000000000007".to_owned(),
+            name: None,
+            activity: activity.to_owned(),
+            start: time(0),
+            end: time(120),
+            duration_s: 120,
+            summary_data_json: Some(json!({
+                "caloriesBurnt": {"value": 95, "unit": "calories_unit"},
+                "averageHR": {"value": 150, "unit": "bpm"},
+            })),
+            source: SOURCE_IMPORTED_FILE.to_owned(),
+        }
+    }
+
+    /// A FIT file around `data`, with a valid header and CRC.
+    fn fit_file(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![14, 0x20, 0x54, 0x08];
+        out.extend(u32::try_from(data.len()).unwrap().to_le_bytes());
+        out.extend(b".FIT");
+        let header_crc = crc(0, &out);
+        out.extend(header_crc.to_le_bytes());
+        out.extend(data);
+        let file_crc = crc(0, &out);
+        out.extend(file_crc.to_le_bytes());
+        out
+    }
+
+    fn round_trip_file() -> Vec<u8> {
+        let points = [
+            point(0, 0, 0, Some(140)),
+            point(1, 0, 10, None),
+            point(2, 0, 20, Some(150)),
+            point(3, 1, 60, Some(160)),
+            point(4, 1, 70, Some(162)),
+        ];
+        let mut bytes = Vec::new();
+        write_fit(&mut bytes, &workout("cycling"), &points, &[]).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip_keeps_points_segments_and_totals() {
+        let fit = parse_fit(&round_trip_file(), SOURCE_IMPORTED_FILE)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(fit.activity, "cycling");
+        assert_eq!(fit.summary.start, time(0));
+        assert_eq!(fit.summary.end, time(120));
+
+        let points = &fit.records.points;
+        assert_eq!(points.len(), 5);
+        assert_eq!(
+            points.iter().map(|p| p.seg).collect::<Vec<_>>(),
+            [0, 0, 0, 1, 1]
+        );
+        assert!((points[2].lat - 48.001).abs() < 1e-6);
+        assert!((points[2].lon + 4.0).abs() < 1e-6);
+        assert_eq!(points[3].t, time(60));
+        assert!((points[0].ele.unwrap() - 12.4).abs() < 0.2);
+        assert_eq!(points[0].cad, Some(85));
+        assert!((points[0].speed.unwrap() - 3.2).abs() < 1e-3);
+
+        let summary = fit.summary.summary_data_json.unwrap();
+        assert_eq!(summary["caloriesBurnt"]["value"], 95.0);
+        assert_eq!(summary["maxHR"]["value"], 162.0);
+    }
+
+    #[test]
+    fn invalid_values_read_as_none() {
+        let fit = parse_fit(&round_trip_file(), SOURCE_IMPORTED_FILE)
+            .unwrap()
+            .unwrap();
+        let p = &fit.records.points[1];
+        assert_eq!(p.hr, None);
+        assert_eq!(p.ele, None);
+        assert_eq!(p.atemp, None);
+        assert_eq!(fit.records.samples[1].power, None);
+    }
+
+    #[test]
+    fn samples_are_written_when_there_are_no_points() {
+        let samples: Vec<WorkoutSample> = (0..4)
+            .map(|i| WorkoutSample {
+                idx: i,
+                t: time(i64::from(i) * 30),
+                hr: Some(120 + i),
+                cad: None,
+                speed: Some(2.5),
+                distance_m: Some(f64::from(i) * 75.0),
+                ele: None,
+                power: Some(200),
+                atemp: None,
+            })
+            .collect();
+        let mut bytes = Vec::new();
+        write_fit(&mut bytes, &workout("treadmill"), &[], &samples).unwrap();
+
+        let fit = parse_fit(&bytes, SOURCE_IMPORTED_FILE).unwrap().unwrap();
+        assert_eq!(fit.activity, "treadmill");
+        assert!(fit.records.points.is_empty());
+        assert_eq!(fit.records.samples.len(), 4);
+        assert_eq!(fit.records.samples[3].hr, Some(123));
+        assert_eq!(fit.records.samples[3].distance_m, Some(225.0));
+        assert_eq!(fit.records.samples[3].power, Some(200));
+        let summary = fit.summary.summary_data_json.unwrap();
+        assert_eq!(summary["distanceMeters"]["value"], 225.0);
+        assert_eq!(summary["averageHR"]["value"], 121.0);
+    }
+
+    #[test]
+    fn truncated_file_keeps_the_records_before_the_cut() {
+        let bytes = round_trip_file();
+        let full = parse_fit(&bytes, SOURCE_IMPORTED_FILE).unwrap().unwrap();
+
+        // Cut in the middle of the records, before the session.
+        let cut = parse_fit(&bytes[..bytes.len() / 3], SOURCE_IMPORTED_FILE)
+            .unwrap()
+            .unwrap();
+        assert!(!cut.records.samples.is_empty());
+        assert!(cut.records.samples.len() < full.records.samples.len());
+        assert_eq!(cut.summary.start, time(0));
+        assert!(cut.summary.summary_data_json.is_none());
+    }
+
+    #[test]
+    fn crc_mismatch_still_decodes() {
+        let mut bytes = round_trip_file();
+        let n = bytes.len();
+        bytes[n - 1] ^= 0xFF;
+        let fit = parse_fit(&bytes, SOURCE_IMPORTED_FILE).unwrap().unwrap();
+        assert_eq!(fit.records.points.len(), 5);
+    }
+
+    #[test]
+    fn compressed_timestamps_count_from_the_last_timestamp() {
+        let mut data = Vec::new();
+        // Local 0: record with timestamp and heart rate.
+        data.extend([0x40, 0, 0, 20, 0, 2, 253, 4, 0x86, 3, 1, 0x02]);
+        // Local 1: record with heart rate only, for compressed headers.
+        data.extend([0x41, 0, 0, 20, 0, 1, 3, 1, 0x02]);
+
+        let t0: u32 = 1_000_000_008; // low 5 bits: 8
+        data.push(0x00);
+        data.extend(t0.to_le_bytes());
+        data.push(100);
+        // Offset 10: 2 s later.
+        data.extend([0x80 | (1 << 5) | 10, 101]);
+        // Offset 5 is below the last one (10): the 5 bits rolled over.
+        data.extend([0x80 | (1 << 5) | 5, 102]);
+
+        let fit = parse_fit(&fit_file(&data), SOURCE_IMPORTED_FILE)
+            .unwrap()
+            .unwrap();
+        let at = |t: u32| fit_time(i64::from(t)).unwrap();
+        let samples = &fit.records.samples;
+        assert_eq!(
+            samples.iter().map(|s| (s.t, s.hr)).collect::<Vec<_>>(),
+            [
+                (at(t0), Some(100)),
+                (at(t0 + 2), Some(101)),
+                (at(t0 - 8 + 32 + 5), Some(102)),
+            ]
+        );
+        assert_eq!(fit.activity, "other");
+    }
+
+    #[test]
+    fn compressed_timestamp_rolls_over_at_the_end_of_the_u32_range() {
+        let mut data = Vec::new();
+        data.extend([0x40, 0, 0, 20, 0, 2, 253, 4, 0x86, 3, 1, 0x02]);
+        data.extend([0x41, 0, 0, 20, 0, 1, 3, 1, 0x02]);
+
+        data.push(0x00);
+        data.extend(0xFFFF_FFFEu32.to_le_bytes());
+        data.push(100);
+        data.extend([0x80 | (1 << 5) | 1, 101]);
+
+        let fit = parse_fit(&fit_file(&data), SOURCE_IMPORTED_FILE)
+            .unwrap()
+            .unwrap();
+        let hr: Vec<_> = fit.records.samples.iter().map(|s| s.hr).collect();
+        assert_eq!(hr, [Some(100), Some(101)]);
+    }
+
+    #[test]
+    fn sports_map_to_activity_labels() {
+        assert_eq!(fit_activity(Some(1), Some(0)), "outdoor_running");
+        assert_eq!(fit_activity(Some(1), Some(1)), "treadmill");
+        assert_eq!(fit_activity(Some(1), Some(45)), "treadmill");
+        assert_eq!(fit_activity(Some(2), Some(6)), "cycling");
+        assert_eq!(fit_activity(Some(11), None), "walking");
+        assert_eq!(fit_activity(Some(17), None), "hiking");
+        assert_eq!(fit_activity(Some(53), None), "other");
+        assert_eq!(fit_activity(None, None), "other");
+    }
+
+    #[test]
+    fn non_activity_files_are_skipped() {
+        // file_id with type 2 (settings).
+        let data = [0x40, 0, 0, 0, 0, 1, 0, 1, 0x00, 0x00, 2];
+        assert!(
+            parse_fit(&fit_file(&data), SOURCE_IMPORTED_FILE)
+                .unwrap()
+                .is_none()
+        );
+        assert!(parse_fit(b"not a fit file at all", SOURCE_IMPORTED_FILE).is_err());
+    }
+}
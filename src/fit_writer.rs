@@ -0,0 +1,529 @@
+use crate::stored::StoredWorkout;
+use crate::types::{GpxPoint, WorkoutSample};
+use crate::utils::haversine_m;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::io::Write;
+
+/// FIT profile version the messages below follow (21.32).
+const PROFILE_VERSION: u16 = 2132;
+/// FIT timestamps count seconds from 1989-12-31T00:00:00Z.
+const FIT_EPOCH_UNIX: i64 = 631_065_600;
+
+// Global message numbers.
+const MESG_FILE_ID: u16 = 0;
+const MESG_SESSION: u16 = 18;
+const MESG_LAP: u16 = 19;
+const MESG_RECORD: u16 = 20;
+const MESG_EVENT: u16 = 21;
+const MESG_ACTIVITY: u16 = 34;
+
+// Local message types, one per message kind we write.
+const LOCAL_FILE_ID: u8 = 0;
+const LOCAL_EVENT: u8 = 1;
+const LOCAL_RECORD: u8 = 2;
+const LOCAL_LAP: u8 = 3;
+const LOCAL_SESSION: u8 = 4;
+const LOCAL_ACTIVITY: u8 = 5;
+
+// `event` and `event_type` enum values.
+const EVENT_TIMER: u8 = 0;
+const EVENT_SESSION: u8 = 8;
+const EVENT_LAP: u8 = 9;
+const EVENT_ACTIVITY: u8 = 26;
+const EVENT_TYPE_START: u8 = 0;
+const EVENT_TYPE_STOP: u8 = 1;
+const EVENT_TYPE_STOP_ALL: u8 = 4;
+
+const FIELD_TIMESTAMP: u8 = 253;
+const FIELD_MESSAGE_INDEX: u8 = 254;
+
+/// Encode a stored workout as a FIT activity file.
+///
+/// Track points become `record` messages. Each stored track segment becomes a
+/// lap, with the pause between segments recorded as timer stop/start events, so
+/// the timer time excludes it. A workout without points has its samples (a
+/// treadmill run) written as records of a single lap, or else gets one empty
+/// lap spanning its start and end.
+///
+/// Calories, and the distance and heart rate the records don't give, come from
+/// the workout's summary.
+pub fn write_fit<W: Write + ?Sized>(
+    w: &mut W,
+    workout: &StoredWorkout,
+    points: &[GpxPoint],
+    samples: &[WorkoutSample],
+) -> Result<()> {
+    let (sport, sub_sport) = fit_sport(&workout.activity);
+    let mut enc = FitEncoder::default();
+
+    enc.message(
+        LOCAL_FILE_ID,
+        MESG_FILE_ID,
+        &[
+            (0, Value::Enum(Some(4))),                                // type: activity
+            (1, Value::U16(Some(255))),                               // manufacturer: development
+            (2, Value::U16(Some(0))),                                 // product
+            (3, Value::U32z(u32::try_from(workout.id).unwrap_or(0))), // serial_number
+            (4, Value::U32(fit_time(workout.start))),                 // time_created
+        ],
+    );
+
+    let segments: Vec<&[GpxPoint]> = points
+        .chunk_by(|a, b| a.trk == b.trk && a.seg == b.seg)
+        .collect();
+
+    let mut laps: Vec<Stats> = Vec::new();
+    let mut distance_m = 0.0;
+    for (i, seg) in segments.iter().enumerate() {
+        let (Some(first), Some(last)) = (seg.first(), seg.last()) else {
+            continue;
+        };
+        timer_event(&mut enc, first.t, EVENT_TYPE_START);
+
+        let mut lap = Stats::new(first.t, Some((first.lat, first.lon)));
+        let mut prev: Option<&GpxPoint> = None;
+        for p in *seg {
+            if let Some(prev) = prev {
+                let d = haversine_m(prev.lat, prev.lon, p.lat, p.lon);
+                distance_m += d;
+                lap.distance_m += d;
+            }
+            prev = Some(p);
+            lap.add(p.t, p.hr);
+            write_record(&mut enc, &Record::from_point(p, distance_m));
+        }
+        lap.end = last.t;
+
+        timer_event(
+            &mut enc,
+            last.t,
+            if i + 1 == segments.len() {
+                EVENT_TYPE_STOP_ALL
+            } else {
+                EVENT_TYPE_STOP
+            },
+        );
+        laps.push(lap);
+    }
+
+    if laps.is_empty()
+        && let (Some(first), Some(last)) = (samples.first(), samples.last())
+    {
+        timer_event(&mut enc, first.t, EVENT_TYPE_START);
+        let mut lap = Stats::new(first.t, None);
+        lap.distance_m = f64::NAN; // unless the samples carry it
+        for s in samples {
+            lap.add(s.t, s.hr);
+            if let Some(d) = s.distance_m {
+                lap.distance_m = d;
+            }
+            write_record(&mut enc, &Record::from_sample(s));
+        }
+        lap.end = last.t;
+        timer_event(&mut enc, last.t, EVENT_TYPE_STOP_ALL);
+        laps.push(lap);
+    }
+
+    if laps.is_empty() {
+        let mut lap = Stats::new(workout.start, None);
+        lap.end = workout.end;
+        lap.timer_s = f64::from(workout.duration_s);
+        lap.distance_m = f64::NAN; // unknown, written as invalid
+        timer_event(&mut enc, workout.start, EVENT_TYPE_START);
+        timer_event(&mut enc, workout.end, EVENT_TYPE_STOP_ALL);
+        laps.push(lap);
+    }
+
+    let mut session = Stats::merge(&laps, workout.end);
+    if !session.distance_m.is_finite()
+        && let Some(d) = workout.summary_value("distanceMeters")
+    {
+        session.distance_m = d;
+        if let [lap] = laps.as_mut_slice() {
+            lap.distance_m = d;
+        }
+    }
+    let summary_avg_hr = workout.summary_value("averageHR").and_then(scaled_u8);
+    let summary_max_hr = workout.summary_value("maxHR").and_then(scaled_u8);
+    let calories = workout.summary_value("caloriesBurnt");
+
+    let single_lap = laps.len() == 1;
+    for (index, lap) in laps.iter().enumerate() {
+        // Calories are shared out by timer time, as the TCX writer does.
+        let lap_calories = calories.map(|c| {
+            if session.timer_s > 0.0 {
+                c * lap.timer_s / session.timer_s
+            } else {
+                c
+            }
+        });
+        let (avg_hr, max_hr) = if single_lap {
+            (
+                lap.avg_hr().or(summary_avg_hr),
+                lap.max_hr.or(summary_max_hr),
+            )
+        } else {
+            (lap.avg_hr(), lap.max_hr)
+        };
+        write_lap(&mut enc, lap, index, sport, lap_calories, avg_hr, max_hr);
+    }
+
+    enc.message(
+        LOCAL_SESSION,
+        MESG_SESSION,
+        &[
+            (FIELD_TIMESTAMP, Value::U32(fit_time(session.end))),
+            (FIELD_MESSAGE_INDEX, Value::U16(Some(0))),
+            (0, Value::Enum(Some(EVENT_SESSION))),
+            (1, Value::Enum(Some(EVENT_TYPE_STOP))),
+            (2, Value::U32(fit_time(workout.start))),
+            (3, Value::I32(session.start_pos.map(|p| semicircles(p.0)))),
+            (4, Value::I32(session.start_pos.map(|p| semicircles(p.1)))),
+            (5, Value::Enum(Some(sport))),
+            (6, Value::Enum(Some(sub_sport))),
+            (7, Value::U32(millis(session.end - workout.start))),
+            (8, Value::U32(millis_f(session.timer_s))),
+            (9, Value::U32(centimeters(session.distance_m))),
+            (11, Value::U16(calories.and_then(scaled_u16))),
+            (16, Value::U8(session.avg_hr().or(summary_avg_hr))),
+            (17, Value::U8(session.max_hr.or(summary_max_hr))),
+            (25, Value::U16(Some(0))), // first_lap_index
+            (26, Value::U16(u16::try_from(laps.len()).ok())),
+        ],
+    );
+
+    enc.message(
+        LOCAL_ACTIVITY,
+        MESG_ACTIVITY,
+        &[
+            (FIELD_TIMESTAMP, Value::U32(fit_time(session.end))),
+            (0, Value::U32(millis_f(session.timer_s))),
+            (1, Value::U16(Some(1))),  // num_sessions
+            (2, Value::Enum(Some(0))), // type: manual
+            (3, Value::Enum(Some(EVENT_ACTIVITY))),
+            (4, Value::Enum(Some(EVENT_TYPE_STOP))),
+        ],
+    );
+
+    enc.finish(w)
+}
+
+fn timer_event(enc: &mut FitEncoder, t: DateTime<Utc>, event_type: u8) {
+    enc.message(
+        LOCAL_EVENT,
+        MESG_EVENT,
+        &[
+            (FIELD_TIMESTAMP, Value::U32(fit_time(t))),
+            (0, Value::Enum(Some(EVENT_TIMER))),
+            (1, Value::Enum(Some(event_type))),
+        ],
+    );
+}
+
+/// The fields of a `record` message, from a track point or a sample.
+struct Record {
+    t: DateTime<Utc>,
+    pos: Option<(f64, f64)>,
+    ele: Option<f64>,
+    hr: Option<i32>,
+    cad: Option<i32>,
+    distance_m: Option<f64>,
+    speed: Option<f64>,
+    power: Option<i32>,
+    atemp: Option<f64>,
+}
+
+impl Record {
+    const fn from_point(p: &GpxPoint, distance_m: f64) -> Self {
+        Self {
+            t: p.t,
+            pos: Some((p.lat, p.lon)),
+            ele: p.ele,
+            hr: p.hr,
+            cad: p.cad,
+            distance_m: Some(distance_m),
+            speed: p.speed,
+            power: None,
+            atemp: p.atemp,
+        }
+    }
+
+    const fn from_sample(s: &WorkoutSample) -> Self {
+        Self {
+            t: s.t,
+            pos: None,
+            ele: s.ele,
+            hr: s.hr,
+            cad: s.cad,
+            distance_m: s.distance_m,
+            speed: s.speed,
+            power: s.power,
+            atemp: s.atemp,
+        }
+    }
+}
+
+fn write_record(enc: &mut FitEncoder, r: &Record) {
+    enc.message(
+        LOCAL_RECORD,
+        MESG_RECORD,
+        &[
+            (FIELD_TIMESTAMP, Value::U32(fit_time(r.t))),
+            (0, Value::I32(r.pos.map(|p| semicircles(p.0)))),
+            (1, Value::I32(r.pos.map(|p| semicircles(p.1)))),
+            // altitude: scale 5, offset 500 m
+            (
+                2,
+                Value::U16(r.ele.and_then(|e| scaled_u16((e + 500.0) * 5.0))),
+            ),
+            (3, Value::U8(r.hr.and_then(|v| u8::try_from(v).ok()))),
+            (4, Value::U8(r.cad.and_then(|v| u8::try_from(v).ok()))),
+            (5, Value::U32(r.distance_m.and_then(centimeters))),
+            // speed: scale 1000, m/s
+            (6, Value::U16(r.speed.and_then(|v| scaled_u16(v * 1000.0)))),
+            (7, Value::U16(r.power.and_then(|v| u16::try_from(v).ok()))),
+            (
+                13,
+                Value::I8(r.atemp.and_then(|v| i8::try_from(v.round() as i64).ok())),
+            ),
+        ],
+    );
+}
+
+fn write_lap(
+    enc: &mut FitEncoder,
+    lap: &Stats,
+    index: usize,
+    sport: u8,
+    calories: Option<f64>,
+    avg_hr: Option<u8>,
+    max_hr: Option<u8>,
+) {
+    enc.message(
+        LOCAL_LAP,
+        MESG_LAP,
+        &[
+            (FIELD_TIMESTAMP, Value::U32(fit_time(lap.end))),
+            (FIELD_MESSAGE_INDEX, Value::U16(u16::try_from(index).ok())),
+            (0, Value::Enum(Some(EVENT_LAP))),
+            (1, Value::Enum(Some(EVENT_TYPE_STOP))),
+            (2, Value::U32(fit_time(lap.start))),
+            (3, Value::I32(lap.start_pos.map(|p| semicircles(p.0)))),
+            (4, Value::I32(lap.start_pos.map(|p| semicircles(p.1)))),
+            (7, Value::U32(millis(lap.end - lap.start))),
+            (8, Value::U32(millis_f(lap.timer_s))),
+            (9, Value::U32(centimeters(lap.distance_m))),
+            (11, Value::U16(calories.and_then(scaled_u16))),
+            (15, Value::U8(avg_hr)),
+            (16, Value::U8(max_hr)),
+            (25, Value::Enum(Some(sport))),
+        ],
+    );
+}
+
+/// Totals of a lap or session.
+struct Stats {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    start_pos: Option<(f64, f64)>,
+    timer_s: f64,
+    distance_m: f64,
+    hr_sum: u64,
+    hr_count: u64,
+    max_hr: Option<u8>,
+}
+
+impl Stats {
+    const fn new(start: DateTime<Utc>, start_pos: Option<(f64, f64)>) -> Self {
+        Self {
+            start,
+            end: start,
+            start_pos,
+            timer_s: 0.0,
+            distance_m: 0.0,
+            hr_sum: 0,
+            hr_count: 0,
+            max_hr: None,
+        }
+    }
+
+    fn add(&mut self, t: DateTime<Utc>, hr: Option<i32>) {
+        self.timer_s = (t - self.start).num_milliseconds() as f64 / 1000.0;
+        if let Some(hr) = hr.and_then(|v| u8::try_from(v).ok()) {
+            self.hr_sum += u64::from(hr);
+            self.hr_count += 1;
+            self.max_hr = self.max_hr.max(Some(hr));
+        }
+    }
+
+    fn merge(laps: &[Self], end: DateTime<Utc>) -> Self {
+        let mut out = Self::new(laps[0].start, laps[0].start_pos);
+        out.end = laps.iter().map(|l| l.end).max().unwrap_or(end).max(end);
+        for lap in laps {
+            out.timer_s += lap.timer_s;
+            out.distance_m += lap.distance_m;
+            out.hr_sum += lap.hr_sum;
+            out.hr_count += lap.hr_count;
+            out.max_hr = out.max_hr.max(lap.max_hr);
+        }
+        out
+    }
+
+    fn avg_hr(&self) -> Option<u8> {
+        (self.hr_count > 0)
+            .then(|| u8::try_from(self.hr_sum / self.hr_count).ok())
+            .flatten()
+    }
+}
+
+/// `(sport, sub_sport)` for a stored activity.
+fn fit_sport(activity: &str) -> (u8, u8) {
+    match activity {
+        "outdoor_running" => (1, 0), // running, generic
+        "treadmill" => (1, 1),       // running, treadmill
+        "cycling" => (2, 0),
+        "swimming" => (5, 0),
+        "walking" => (11, 0),
+        "hiking" => (17, 0),
+        _ => (0, 0), // generic
+    }
+}
+
+fn fit_time(t: DateTime<Utc>) -> Option<u32> {
+    u32::try_from(t.timestamp() - FIT_EPOCH_UNIX).ok()
+}
+
+fn semicircles(deg: f64) -> i32 {
+    (deg * (f64::from(1u32 << 31) / 180.0)).round() as i32
+}
+
+fn millis(d: chrono::TimeDelta) -> Option<u32> {
+    u32::try_from(d.num_milliseconds()).ok()
+}
+
+fn millis_f(secs: f64) -> Option<u32> {
+    scaled_u32(secs * 1000.0)
+}
+
+fn centimeters(m: f64) -> Option<u32> {
+    scaled_u32(m * 100.0)
+}
+
+fn scaled_u8(v: f64) -> Option<u8> {
+    (v.is_finite() && (0.0..f64::from(u8::MAX)).contains(&v)).then(|| v.round() as u8)
+}
+
+fn scaled_u16(v: f64) -> Option<u16> {
+    (v.is_finite() && (0.0..f64::from(u16::MAX)).contains(&v)).then(|| v.round() as u16)
+}
+
+fn scaled_u32(v: f64) -> Option<u32> {
+    (v.is_finite() && (0.0..f64::from(u32::MAX)).contains(&v)).then(|| v.round() as u32)
+}
+
+/// A field value; `None` is written as the base type's invalid value.
+#[derive(Clone, Copy)]
+enum Value {
+    Enum(Option<u8>),
+    U8(Option<u8>),
+    I8(Option<i8>),
+    U16(Option<u16>),
+    U32(Option<u32>),
+    U32z(u32),
+    I32(Option<i32>),
+}
+
+impl Value {
+    /// `(size, base type)` as written in definition messages.
+    const fn base_type(self) -> (u8, u8) {
+        match self {
+            Self::Enum(_) => (1, 0x00),
+            Self::I8(_) => (1, 0x01),
+            Self::U8(_) => (1, 0x02),
+            Self::U16(_) => (2, 0x84),
+            Self::I32(_) => (4, 0x85),
+            Self::U32(_) => (4, 0x86),
+            Self::U32z(_) => (4, 0x8C),
+        }
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        match self {
+            Self::Enum(v) | Self::U8(v) => out.push(v.unwrap_or(u8::MAX)),
+            Self::I8(v) => out.extend(v.unwrap_or(i8::MAX).to_le_bytes()),
+            Self::U16(v) => out.extend(v.unwrap_or(u16::MAX).to_le_bytes()),
+            Self::U32(v) => out.extend(v.unwrap_or(u32::MAX).to_le_bytes()),
+            Self::U32z(v) => out.extend(v.to_le_bytes()),
+            Self::I32(v) => out.extend(v.unwrap_or(i32::MAX).to_le_bytes()),
+        }
+    }
+}
+
+/// Buffers the data records (the header needs their size) and writes a
+/// definition message the first time each local message type is used.
+#[derive(Default)]
+struct FitEncoder {
+    data: Vec<u8>,
+    defined: [bool; 16],
+}
+
+impl FitEncoder {
+    fn message(&mut self, local: u8, global: u16, fields: &[(u8, Value)]) {
+        let slot = usize::from(local);
+        if !self.defined[slot] {
+            self.defined[slot] = true;
+            self.data.push(0x40 | local); // definition message header
+            self.data.push(0); // reserved
+            self.data.push(0); // little-endian
+            self.data.extend(global.to_le_bytes());
+            self.data
+                .push(u8::try_from(fields.len()).unwrap_or(u8::MAX));
+            for (num, value) in fields {
+                let (size, base) = value.base_type();
+                self.data.extend([*num, size, base]);
+            }
+        }
+
+        self.data.push(local); // data message header
+        for (_, value) in fields {
+            value.write(&mut self.data);
+        }
+    }
+
+    fn finish<W: Write + ?Sized>(self, w: &mut W) -> Result<()> {
+        let data_size = u32::try_from(self.data.len())
+            .map_err(|_| anyhow::anyhow!("FIT data too large: {} bytes", self.data.len()))?;
+
+        let mut header = Vec::with_capacity(14);
+        header.push(14); // header size
+        header.push(0x20); // protocol version 2.0
+        header.extend(PROFILE_VERSION.to_le_bytes());
+        header.extend(data_size.to_le_bytes());
+        header.extend(b".FIT");
+        let header_crc = crc(0, &header);
+        header.extend(header_crc.to_le_bytes());
+
+        let file_crc = crc(crc(0, &header), &self.data);
+        w.write_all(&header)?;
+        w.write_all(&self.data)?;
+        w.write_all(&file_crc.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/// The FIT SDK's CRC-16.
+pub(crate) fn crc(mut crc: u16, bytes: &[u8]) -> u16 {
+    const TABLE: [u16; 16] = [
+        0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800,
+        0xB401, 0x5000, 0x9C01, 0x8801, 0x4400,
+    ];
+    for &byte in bytes {
+        for nibble in [byte & 0x0F, byte >> 4] {
+            let tmp = TABLE[usize::from(crc & 0x0F)];
+            crc = (crc >> 4) & 0x0FFF;
+            crc = crc ^ tmp ^ TABLE[usize::from(nibble)];
+        }
+    }
+    crc
+}
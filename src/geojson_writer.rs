@@ -0,0 +1,117 @@
+use crate::gpx_writer::workout_name;
+use crate::stored::StoredWorkout;
+use crate::types::GpxPoint;
+use crate::utils::haversine_m;
+use anyhow::Result;
+use chrono::SecondsFormat;
+use serde_json::{Value as JsonValue, json};
+use std::io::Write;
+
+/// Writes a GeoJSON FeatureCollection one feature at a time, so a whole history
+/// never has to be held in memory.
+pub struct GeoJsonWriter<'a, W: Write + ?Sized> {
+    w: &'a mut W,
+    polyline: bool,
+    features: usize,
+}
+
+impl<'a, W: Write + ?Sized> GeoJsonWriter<'a, W> {
+    /// With `polyline`, each track is stored as a Google encoded polyline in the
+    /// `polyline` property and the feature geometry is left null.
+    pub fn new(w: &'a mut W, polyline: bool) -> Result<Self> {
+        w.write_all(br#"{"type":"FeatureCollection","features":["#)?;
+        Ok(Self {
+            w,
+            polyline,
+            features: 0,
+        })
+    }
+
+    /// One feature per workout: a LineString through all its track points, in
+    /// order. Segment breaks are not kept.
+    pub fn write_workout(&mut self, workout: &StoredWorkout, points: &[GpxPoint]) -> Result<()> {
+        let distance_m = workout
+            .summary_value("distanceMeters")
+            .unwrap_or_else(|| track_distance_m(points));
+
+        let mut properties = json!({
+            "id": workout.id,
+            "uuid": workout.uuid,
+            "name": workout_name(workout),
+            "activity": workout.activity,
+            "source": workout.source,
+            "start_time": workout.start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "end_time": workout.end.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "duration_s": workout.duration_s,
+            "distance_m": (distance_m * 10.0).round() / 10.0,
+        });
+
+        let geometry = if self.polyline {
+            properties["polyline"] = JsonValue::String(encode_polyline(points));
+            JsonValue::Null
+        } else {
+            let coordinates: Vec<JsonValue> = points.iter().map(position).collect();
+            json!({ "type": "LineString", "coordinates": coordinates })
+        };
+
+        if self.features > 0 {
+            self.w.write_all(b",")?;
+        }
+        self.w.write_all(b"\n")?;
+        serde_json::to_writer(
+            &mut *self.w,
+            &json!({ "type": "Feature", "geometry": geometry, "properties": properties }),
+        )?;
+        self.features += 1;
+        Ok(())
+    }
+
+    /// Close the collection; returns the number of features written.
+    pub fn finish(self) -> Result<usize> {
+        self.w.write_all(b"\n]}\n")?;
+        Ok(self.features)
+    }
+}
+
+/// `[lon, lat]` or `[lon, lat, ele]`, rounded to about 10 cm as RFC 7946
+/// suggests.
+fn position(p: &GpxPoint) -> JsonValue {
+    let round6 = |v: f64| (v * 1e6).round() / 1e6;
+    match p.ele {
+        Some(ele) => json!([round6(p.lon), round6(p.lat), (ele * 10.0).round() / 10.0]),
+        None => json!([round6(p.lon), round6(p.lat)]),
+    }
+}
+
+/// Length of the track, not counting jumps between segments.
+fn track_distance_m(points: &[GpxPoint]) -> f64 {
+    points
+        .windows(2)
+        .filter(|pair| pair[0].trk == pair[1].trk && pair[0].seg == pair[1].seg)
+        .map(|pair| haversine_m(pair[0].lat, pair[0].lon, pair[1].lat, pair[1].lon))
+        .sum()
+}
+
+/// Google's encoded polyline format with precision 5, as decoded by
+/// Leaflet.encoded, Mapbox's polyline and the Google Maps API.
+fn encode_polyline(points: &[GpxPoint]) -> String {
+    let mut out = String::new();
+    let (mut prev_lat, mut prev_lon) = (0i64, 0i64);
+    for p in points {
+        let lat = (p.lat * 1e5).round() as i64;
+        let lon = (p.lon * 1e5).round() as i64;
+        encode_value(&mut out, lat - prev_lat);
+        encode_value(&mut out, lon - prev_lon);
+        (prev_lat, prev_lon) = (lat, lon);
+    }
+    out
+}
+
+fn encode_value(out: &mut String, delta: i64) {
+    let mut v = if delta < 0 { !(delta << 1) } else { delta << 1 };
+    while v >= 0x20 {
+        out.push(char::from((0x20 | (v & 0x1f)) as u8 + 63));
+        v >>= 5;
+    }
+    out.push(char::from(v as u8 + 63));
+}
@@ -1,129 +1,569 @@
-use crate::types::GpxPoint;
+use crate::types::{GpxData, GpxPoint, GpxRoute, GpxWaypoint};
 use anyhow::Result;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use quick_xml::escape::resolve_predefined_entity;
 use quick_xml::events::{BytesEnd, BytesStart, Event};
 use quick_xml::reader::Reader;
-use std::fs;
-use std::io::{BufReader, Cursor};
-use std::path::Path;
+use std::collections::VecDeque;
+use std::io::BufRead;
 
-pub fn parse_gpx_points(path: &Path) -> Result<Vec<GpxPoint>> {
-    let bytes = fs::read(path)?;
-    if bytes.is_empty() {
-        return Ok(Vec::new());
+/// Track points only; see [`parse_gpx`] for waypoints and routes.
+pub fn parse_gpx_points<R: BufRead>(reader: R) -> Result<Vec<GpxPoint>> {
+    Ok(parse_gpx(reader, None)?.points)
+}
+
+/// Parse a whole GPX file into memory; see [`GpxReader`] to stream it instead.
+pub fn parse_gpx<R: BufRead>(
+    reader: R,
+    workout: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<GpxData> {
+    let mut gpx = GpxReader::new(reader, workout);
+    let points = gpx.by_ref().collect::<Result<Vec<_>>>()?;
+    let mut data = gpx.finish();
+    data.points = points;
+    Ok(data)
+}
+
+/// Streaming GPX parser yielding track points as they are read.
+///
+/// Memory stays bounded by the longest run of consecutive untimed points, which
+/// are held back until their time can be interpolated between the timed points
+/// around them. Untimed points before the first or after the last timed point
+/// are placed between those and `workout` (the start and end of the workout the
+/// track belongs to), or dropped if unknown.
+///
+/// Waypoints, routes and repair counts are available from [`Self::finish`] once
+/// the iterator is exhausted.
+pub struct GpxReader<R: BufRead> {
+    xml: Reader<R>,
+    buf: Vec<u8>,
+    st: GpxState,
+    out: GpxData,
+    workout: Option<(DateTime<Utc>, DateTime<Utc>)>,
+
+    /// Points ready to be yielded, already timed and numbered.
+    ready: VecDeque<GpxPoint>,
+    /// Untimed points waiting for the next timed point (or the end of the file).
+    untimed: Vec<GpxPoint>,
+    last_time: Option<DateTime<Utc>>,
+    next_idx: i32,
+    done: bool,
+}
+
+impl<R: BufRead> GpxReader<R> {
+    pub fn new(reader: R, workout: Option<(DateTime<Utc>, DateTime<Utc>)>) -> Self {
+        Self {
+            xml: Reader::from_reader(reader),
+            buf: Vec::new(),
+            st: GpxState::default(),
+            out: GpxData::default(),
+            workout,
+            ready: VecDeque::new(),
+            untimed: Vec::new(),
+            last_time: None,
+            next_idx: 0,
+            done: false,
+        }
     }
 
-    let cursor = Cursor::new(bytes);
-    let reader = BufReader::new(cursor);
-    let mut xml = Reader::from_reader(reader);
-    xml.config_mut().trim_text(true);
+    /// Waypoints, routes and repair counts; `points` is left empty, as those
+    /// were yielded. Call after the iterator returned `None`.
+    pub fn finish(self) -> GpxData {
+        self.out
+    }
 
-    let mut buf = Vec::new();
+    /// Read XML events until a track point is complete or the file ends.
+    fn read_next_point(&mut self) -> Result<Option<(Option<DateTime<Utc>>, GpxPoint)>> {
+        let (st, out) = (&mut self.st, &mut self.out);
+        loop {
+            self.buf.clear();
+            match self.xml.read_event_into(&mut self.buf) {
+                Ok(Event::Eof) => return Ok(None),
+                Ok(Event::Start(e)) => handle_gpx_start(st, &e),
+                Ok(Event::Empty(e)) => {
+                    handle_gpx_start(st, &e);
+                    handle_gpx_end(st, &e.to_end(), out);
+                }
+                Ok(Event::End(e)) => handle_gpx_end(st, &e, out),
+                Ok(Event::Text(e)) if st.field.is_some() => {
+                    if let Ok(s) = e.xml_content() {
+                        st.text.push_str(&s);
+                    }
+                }
+                Ok(Event::CData(e)) if st.field.is_some() => {
+                    if let Ok(s) = e.decode() {
+                        st.text.push_str(&s);
+                    }
+                }
+                Ok(Event::GeneralRef(e)) if st.field.is_some() => handle_gpx_ref(st, &e),
+                Err(e) => anyhow::bail!("GPX XML parse error: {e}"),
+                _ => {}
+            }
+            if let Some(point) = st.completed.take() {
+                return Ok(Some(point));
+            }
+        }
+    }
+
+    fn accept(&mut self, time: Option<DateTime<Utc>>, p: GpxPoint) {
+        let Some(t) = time else {
+            self.untimed.push(p);
+            return;
+        };
+
+        let run = std::mem::take(&mut self.untimed);
+        if !run.is_empty() {
+            let m = run.len();
+            match (self.last_time, self.workout) {
+                // Between two timed points.
+                (Some(ta), _) => {
+                    for (k, q) in (1..).zip(run) {
+                        self.emit(q, lerp(ta, t, k, m + 1), true);
+                    }
+                }
+                // Before the first timed point: from the workout start.
+                (None, Some((start, _))) => {
+                    let start = start.min(t);
+                    for (k, q) in (0..).zip(run) {
+                        self.emit(q, lerp(start, t, k, m), true);
+                    }
+                }
+                (None, None) => self.out.dropped_points += m,
+            }
+        }
+
+        self.emit(p, t, false);
+        self.last_time = Some(t);
+    }
+
+    /// Time the untimed points left at the end of the file.
+    fn flush_untimed(&mut self) {
+        let run = std::mem::take(&mut self.untimed);
+        if run.is_empty() {
+            return;
+        }
+        let m = run.len();
+        match (self.last_time, self.workout) {
+            // After the last timed point: up to the workout end.
+            (Some(ta), Some((_, end))) => {
+                let end = end.max(ta);
+                for (k, q) in (1..).zip(run) {
+                    self.emit(q, lerp(ta, end, k, m), true);
+                }
+            }
+            // No timed point at all: spread over the workout.
+            (None, Some((start, end))) => {
+                let end = end.max(start);
+                for (k, q) in (0..).zip(run) {
+                    self.emit(q, lerp(start, end, k, (m - 1).max(1)), true);
+                }
+            }
+            (_, None) => self.out.dropped_points += m,
+        }
+    }
+
+    fn emit(&mut self, mut p: GpxPoint, t: DateTime<Utc>, interpolated: bool) {
+        p.t = t;
+        p.idx = self.next_idx;
+        self.next_idx = self.next_idx.saturating_add(1);
+        if interpolated {
+            self.out.interpolated_points += 1;
+        }
+        self.ready.push_back(p);
+    }
+}
+
+impl<R: BufRead> Iterator for GpxReader<R> {
+    type Item = Result<GpxPoint>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(p) = self.ready.pop_front() {
+                return Some(Ok(p));
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_next_point() {
+                Ok(Some((time, p))) => self.accept(time, p),
+                Ok(None) => {
+                    self.done = true;
+                    self.flush_untimed();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
 
-    let mut st = GpxState::default();
-    let mut out: Vec<GpxPoint> = Vec::new();
+/// What a first pass over a track learns, without keeping its points.
+pub(crate) struct TrackScan {
+    pub(crate) start: DateTime<Utc>,
+    pub(crate) end: DateTime<Utc>,
+    pub(crate) first_lat: f64,
+    pub(crate) first_lon: f64,
+    pub(crate) points: usize,
+    pub(crate) track_name: Option<String>,
+    pub(crate) track_type: Option<String>,
+}
 
-    loop {
-        match xml.read_event_into(&mut buf) {
-            Ok(Event::Eof) => break,
-            Ok(Event::Start(e)) => handle_gpx_start(&mut st, &e),
-            Ok(Event::End(e)) => handle_gpx_end(&mut st, &e, &mut out),
-            Ok(Event::Text(e)) => {
-                handle_gpx_text(&mut st, &e);
+/// `None` if the track has no timestamped points.
+pub(crate) fn scan_track<R: BufRead>(reader: R) -> Result<Option<TrackScan>> {
+    let mut gpx = GpxReader::new(reader, None);
+    let mut scan: Option<TrackScan> = None;
+    for p in gpx.by_ref() {
+        let p = p?;
+        match scan.as_mut() {
+            Some(s) => {
+                s.start = s.start.min(p.t);
+                s.end = s.end.max(p.t);
+                s.points += 1;
+            }
+            None => {
+                scan = Some(TrackScan {
+                    start: p.t,
+                    end: p.t,
+                    first_lat: p.lat,
+                    first_lon: p.lon,
+                    points: 1,
+                    track_name: None,
+                    track_type: None,
+                });
             }
-            Err(e) => anyhow::bail!("GPX XML parse error: {e}"),
-            _ => {}
         }
-        buf.clear();
     }
+    let data = gpx.finish();
+    Ok(scan.map(|s| TrackScan {
+        track_name: data.track_name,
+        track_type: data.track_type,
+        ..s
+    }))
+}
 
-    Ok(out)
+/// Log what [`parse_gpx`] had to repair or drop in `path`.
+pub fn log_time_repairs(path: &str, gpx: &GpxData) {
+    if gpx.interpolated_points > 0 || gpx.dropped_points > 0 {
+        tracing::info!(
+            path = %path,
+            interpolated = gpx.interpolated_points,
+            dropped = gpx.dropped_points,
+            "gpx track points without usable time or position"
+        );
+    }
+}
+
+/// The point-like element (`wptType` in the GPX schema) being read.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PointKind {
+    Trkpt,
+    Wpt,
+    Rtept,
 }
 
 #[derive(Default)]
 struct GpxState {
-    in_trkpt: bool,
-    in_time: bool,
-    in_ele: bool,
+    point: Option<PointKind>,
+    in_rte: bool,
+    in_trk: bool,
+    /// Child element whose text we are collecting into `text`.
+    field: Option<Field>,
+    text: String,
+
+    cur: CurPoint,
+    route: Option<GpxRoute>,
+    /// Track point just closed, with its time if it had a usable one.
+    completed: Option<(Option<DateTime<Utc>>, GpxPoint)>,
+
+    /// Index of the current `<trk>`/`<trkseg>`, or of the next one once closed.
+    trk: i32,
+    seg: i32,
+}
 
-    cur_lat: Option<f64>,
-    cur_lon: Option<f64>,
-    cur_time: Option<DateTime<Utc>>,
-    cur_ele: Option<f64>,
+#[derive(Default)]
+struct CurPoint {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    time: Option<DateTime<Utc>>,
+    ele: Option<f64>,
+    hr: Option<i32>,
+    cad: Option<i32>,
+    atemp: Option<f64>,
+    speed: Option<f64>,
+    course: Option<f64>,
+    name: Option<String>,
+    desc: Option<String>,
+    sym: Option<String>,
+}
 
-    idx: i32,
+#[derive(Clone, Copy)]
+enum Field {
+    Time,
+    Ele,
+    Hr,
+    Cad,
+    Atemp,
+    Speed,
+    Course,
+    Name,
+    Desc,
+    Sym,
+    Type,
+}
+
+impl Field {
+    /// Elements are matched on their local name, so `gpxtpx:hr`, `ns3:hr` or an
+    /// unprefixed `hr` in a default namespace are all read.
+    fn from_local_name(name: &[u8]) -> Option<Self> {
+        Some(match name {
+            b"time" => Self::Time,
+            b"ele" => Self::Ele,
+            b"hr" => Self::Hr,
+            b"cad" => Self::Cad,
+            b"atemp" => Self::Atemp,
+            b"speed" => Self::Speed,
+            b"course" => Self::Course,
+            b"name" => Self::Name,
+            b"desc" => Self::Desc,
+            b"sym" => Self::Sym,
+            b"type" => Self::Type,
+            _ => return None,
+        })
+    }
 }
 
 fn handle_gpx_start(st: &mut GpxState, e: &BytesStart<'_>) {
-    match e.name().as_ref() {
-        b"trkpt" => {
-            st.in_trkpt = true;
-            st.in_time = false;
-            st.in_ele = false;
+    let name = e.local_name();
+    let kind = match name.as_ref() {
+        b"trkpt" => Some(PointKind::Trkpt),
+        b"wpt" => Some(PointKind::Wpt),
+        b"rtept" if st.in_rte => Some(PointKind::Rtept),
+        b"rte" => {
+            st.in_rte = true;
+            st.route = Some(GpxRoute::default());
+            return;
+        }
+        b"trk" => {
+            st.in_trk = true;
+            return;
+        }
+        _ => None,
+    };
+
+    if let Some(kind) = kind {
+        st.point = Some(kind);
+        st.field = None;
+        st.cur = CurPoint::default();
 
-            st.cur_lat = None;
-            st.cur_lon = None;
-            st.cur_time = None;
-            st.cur_ele = None;
+        let (lat, lon) = parse_trkpt_lat_lon(e);
+        st.cur.lat = lat;
+        st.cur.lon = lon;
+    } else if st.point.is_some() {
+        st.field = Field::from_local_name(name.as_ref());
+        st.text.clear();
+    } else if st.in_rte {
+        // A route's own name and description, outside its points.
+        st.field = match Field::from_local_name(name.as_ref()) {
+            Some(f @ (Field::Name | Field::Desc)) => Some(f),
+            _ => None,
+        };
+        st.text.clear();
+    } else if st.in_trk {
+        // The track's own name and activity type, outside its points.
+        st.field = match Field::from_local_name(name.as_ref()) {
+            Some(f @ (Field::Name | Field::Type)) => Some(f),
+            _ => None,
+        };
+        st.text.clear();
+    }
+}
 
-            let (lat, lon) = parse_trkpt_lat_lon(e);
-            st.cur_lat = lat;
-            st.cur_lon = lon;
+fn handle_gpx_end(st: &mut GpxState, e: &BytesEnd<'_>, out: &mut GpxData) {
+    match e.local_name().as_ref() {
+        b"trkpt" if st.point == Some(PointKind::Trkpt) => {
+            st.point = None;
+            push_track_point(st, out);
+        }
+        b"wpt" if st.point == Some(PointKind::Wpt) => {
+            st.point = None;
+            if let Some(wpt) = take_waypoint(st) {
+                out.waypoints.push(wpt);
+            }
         }
-        b"time" if st.in_trkpt => {
-            st.in_time = true;
+        b"rtept" if st.point == Some(PointKind::Rtept) => {
+            st.point = None;
+            if let Some(wpt) = take_waypoint(st)
+                && let Some(route) = st.route.as_mut()
+            {
+                route.points.push(wpt);
+            }
+        }
+        b"rte" => {
+            st.in_rte = false;
+            if let Some(route) = st.route.take() {
+                out.routes.push(route);
+            }
+        }
+        b"trkseg" => st.seg = st.seg.saturating_add(1),
+        b"trk" => {
+            st.in_trk = false;
+            st.trk = st.trk.saturating_add(1);
+            st.seg = 0;
         }
-        b"ele" if st.in_trkpt => {
-            st.in_ele = true;
+        _ => {
+            if let Some(field) = st.field.take() {
+                let text = std::mem::take(&mut st.text);
+                apply_field(st, out, field, text.trim());
+            }
         }
-        _ => {}
     }
 }
 
-fn handle_gpx_end(st: &mut GpxState, e: &BytesEnd<'_>, out: &mut Vec<GpxPoint>) {
-    match e.name().as_ref() {
-        b"time" => st.in_time = false,
-        b"ele" => st.in_ele = false,
-        b"trkpt" => {
-            st.in_trkpt = false;
+fn push_track_point(st: &mut GpxState, out: &mut GpxData) {
+    let cur = std::mem::take(&mut st.cur);
+
+    let (Some(lat), Some(lon)) = (cur.lat, cur.lon) else {
+        out.dropped_points += 1;
+        return;
+    };
 
-            let Some(lat) = st.cur_lat else {
-                return;
-            };
-            let Some(lon) = st.cur_lon else {
-                return;
-            };
-            let Some(t) = st.cur_time else {
-                return;
-            };
+    st.completed = Some((
+        cur.time,
+        GpxPoint {
+            idx: 0, // assigned once its time is known
+            trk: st.trk,
+            seg: st.seg,
+            t: DateTime::<Utc>::MIN_UTC,
+            lat,
+            lon,
+            ele: cur.ele,
+            hr: cur.hr,
+            cad: cur.cad,
+            atemp: cur.atemp,
+            speed: cur.speed,
+            course: cur.course,
+        },
+    ));
+}
 
-            out.push(GpxPoint {
-                idx: st.idx,
-                t,
-                lat,
-                lon,
-                ele: st.cur_ele,
-            });
-            st.idx = st.idx.saturating_add(1);
+fn lerp(from: DateTime<Utc>, to: DateTime<Utc>, k: usize, n: usize) -> DateTime<Utc> {
+    let span_ms = (to - from).num_milliseconds() as f64;
+    from + TimeDelta::milliseconds((span_ms * k as f64 / n as f64).round() as i64)
+}
+
+fn take_waypoint(st: &mut GpxState) -> Option<GpxWaypoint> {
+    let cur = std::mem::take(&mut st.cur);
+    Some(GpxWaypoint {
+        lat: cur.lat?,
+        lon: cur.lon?,
+        ele: cur.ele,
+        t: cur.time,
+        name: cur.name,
+        desc: cur.desc,
+        sym: cur.sym,
+    })
+}
+
+fn apply_field(st: &mut GpxState, out: &mut GpxData, field: Field, s: &str) {
+    if st.point.is_none() {
+        if let Some(route) = st.route.as_mut() {
+            match field {
+                Field::Name => route.name = non_empty(s),
+                Field::Desc => route.desc = non_empty(s),
+                _ => {}
+            }
+        } else if st.in_trk && st.trk == 0 {
+            match field {
+                Field::Name if out.track_name.is_none() => out.track_name = non_empty(s),
+                Field::Type if out.track_type.is_none() => out.track_type = non_empty(s),
+                _ => {}
+            }
         }
-        _ => {}
+        return;
+    }
+
+    let cur = &mut st.cur;
+    match field {
+        Field::Time => cur.time = parse_gpx_time(s),
+        Field::Ele => cur.ele = parse_f64(s),
+        Field::Hr => cur.hr = parse_i32(s),
+        Field::Cad => cur.cad = parse_i32(s),
+        Field::Atemp => cur.atemp = parse_f64(s),
+        Field::Speed => cur.speed = parse_f64(s),
+        Field::Course => cur.course = parse_f64(s),
+        Field::Name => cur.name = non_empty(s),
+        Field::Desc => cur.desc = non_empty(s),
+        Field::Sym => cur.sym = non_empty(s),
+        Field::Type => {}
     }
 }
 
-fn handle_gpx_text(st: &mut GpxState, e: &quick_xml::events::BytesText<'_>) {
-    if st.in_time
-        && let Ok(s) = e.decode()
-        && let Ok(dt_fixed) = DateTime::parse_from_rfc3339(s.as_ref())
+/// `&amp;`, `&#233;` etc. inside text are reported as separate events.
+fn handle_gpx_ref(st: &mut GpxState, e: &quick_xml::events::BytesRef<'_>) {
+    if let Ok(Some(c)) = e.resolve_char_ref() {
+        st.text.push(c);
+    } else if let Ok(name) = e.decode()
+        && let Some(s) = resolve_predefined_entity(&name)
     {
-        st.cur_time = Some(dt_fixed.with_timezone(&Utc));
-    } else if st.in_ele
-        && let Ok(s) = e.decode()
-        && let Ok(v) = s.parse::<f64>()
-    {
-        st.cur_ele = Some(v);
+        st.text.push_str(s);
     }
 }
 
+/// GPX requires RFC 3339 UTC times, but exporters also write numeric offsets
+/// without a colon, omit the offset (read as UTC, as the spec intends), use a
+/// space instead of `T` or drop the seconds.
+pub(crate) fn parse_gpx_time(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%d %H:%M:%S%.f%z"] {
+        if let Ok(dt) = DateTime::parse_from_str(s, fmt) {
+            return Some(dt.with_timezone(&Utc));
+        }
+    }
+
+    let naive = s.strip_suffix(['Z', 'z']).unwrap_or(s);
+    [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|fmt| NaiveDateTime::parse_from_str(naive, fmt).ok())
+    .map(|dt| dt.and_utc())
+}
+
+/// Trimmed value of the attribute `key` (matched on its local name), unless
+/// empty.
+pub(crate) fn attribute(e: &BytesStart<'_>, key: &[u8]) -> Option<String> {
+    e.attributes()
+        .with_checks(false)
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == key)
+        .and_then(|a| a.unescape_value().ok())
+        .and_then(|v| non_empty(v.trim()))
+}
+
+pub(crate) fn non_empty(s: &str) -> Option<String> {
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+pub(crate) fn parse_f64(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// Integer fields are sometimes written as decimals (`142.0`) by converters.
+pub(crate) fn parse_i32(s: &str) -> Option<i32> {
+    s.parse::<i32>()
+        .ok()
+        .or_else(|| parse_f64(s).map(|v| v.round() as i32))
+}
+
+/// Coordinates outside ±90/±180 (or NaN, inf) count as missing, so the point
+/// is dropped rather than stored somewhere off the map.
 fn parse_trkpt_lat_lon(e: &BytesStart<'_>) -> (Option<f64>, Option<f64>) {
     let mut lat: Option<f64> = None;
     let mut lon: Option<f64> = None;
@@ -133,13 +573,299 @@ fn parse_trkpt_lat_lon(e: &BytesStart<'_>) -> (Option<f64>, Option<f64>) {
         if key == b"lat"
             && let Ok(v) = a.unescape_value()
         {
-            lat = v.parse::<f64>().ok();
+            lat = parse_f64(v.trim()).filter(|v| v.abs() <= 90.0);
         } else if key == b"lon"
             && let Ok(v) = a.unescape_value()
         {
-            lon = v.parse::<f64>().ok();
+            lon = parse_f64(v.trim()).filter(|v| v.abs() <= 180.0);
         }
     }
 
     (lat, lon)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> GpxData {
+        parse_gpx(xml.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn track_point_extension_fields_are_read_whatever_the_prefix() {
+        let gpx = parse(
+            r#"<gpx xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2"
+                    xmlns:ns3="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+              <trk><trkseg>
+                <trkpt lat="48.1" lon="-1.6"><ele>42.5</ele><time>2024-05-01T07:30:00Z</time>
+                  <extensions><gpxtpx:TrackPointExtension>
+                    <gpxtpx:hr>142</gpxtpx:hr><gpxtpx:cad>88</gpxtpx:cad>
+                    <gpxtpx:atemp>18.5</gpxtpx:atemp><gpxtpx:speed>3.2</gpxtpx:speed>
+                    <gpxtpx:course>271.0</gpxtpx:course>
+                  </gpxtpx:TrackPointExtension></extensions>
+                </trkpt>
+                <trkpt lat="48.2" lon="-1.7"><time>2024-05-01T07:30:01Z</time>
+                  <extensions><ns3:TrackPointExtension><ns3:hr>143.0</ns3:hr></ns3:TrackPointExtension></extensions>
+                </trkpt>
+                <trkpt lat="48.3" lon="-1.8"><time>2024-05-01T07:30:02Z</time></trkpt>
+              </trkseg></trk>
+            </gpx>"#,
+        );
+
+        let [a, b, c] = gpx.points.as_slice() else {
+            panic!("expected 3 points, got {}", gpx.points.len());
+        };
+        assert_eq!((a.lat, a.lon, a.ele), (48.1, -1.6, Some(42.5)));
+        assert_eq!((a.hr, a.cad), (Some(142), Some(88)));
+        assert_eq!(
+            (a.atemp, a.speed, a.course),
+            (Some(18.5), Some(3.2), Some(271.0))
+        );
+        assert_eq!((b.hr, b.cad, b.atemp), (Some(143), None, None));
+        assert_eq!((c.ele, c.hr, c.speed), (None, None, None));
+    }
+
+    #[test]
+    fn points_keep_their_track_and_segment() {
+        let gpx = parse(
+            r#"<gpx>
+              <trk><name>Morning run</name><type>running</type>
+                <trkseg>
+                  <trkpt lat="48.0" lon="-1.0"><time>2024-05-01T07:00:00Z</time></trkpt>
+                  <trkpt lat="48.0001" lon="-1.0"><time>2024-05-01T07:00:05Z</time></trkpt>
+                </trkseg>
+                <trkseg>
+                  <trkpt lat="48.1" lon="-1.0"><time>2024-05-01T07:30:00Z</time></trkpt>
+                  <trkpt lat="48.1001" lon="-1.0"><time>2024-05-01T07:30:05Z</time></trkpt>
+                </trkseg>
+              </trk>
+              <trk><name>Cool down</name><trkseg>
+                <trkpt lat="48.2" lon="-1.0"><time>2024-05-01T08:00:00Z</time></trkpt>
+              </trkseg></trk>
+            </gpx>"#,
+        );
+
+        let ids: Vec<_> = gpx.points.iter().map(|p| (p.idx, p.trk, p.seg)).collect();
+        assert_eq!(ids, [(0, 0, 0), (1, 0, 0), (2, 0, 1), (3, 0, 1), (4, 1, 0)]);
+        assert_eq!(gpx.track_name.as_deref(), Some("Morning run"));
+        assert_eq!(gpx.track_type.as_deref(), Some("running"));
+
+        // Summing only within segments, as workout_distance_m does, leaves the
+        // ~11 km jumps across the pause and to the next track out.
+        let distance: f64 = gpx
+            .points
+            .windows(2)
+            .filter(|w| (w[0].trk, w[0].seg) == (w[1].trk, w[1].seg))
+            .map(|w| crate::utils::haversine_m(w[0].lat, w[0].lon, w[1].lat, w[1].lon))
+            .sum();
+        assert!((distance - 22.2).abs() < 0.5, "{distance}");
+    }
+
+    #[test]
+    fn waypoints_and_routes_are_read_beside_the_track() {
+        let gpx = parse(
+            r#"<gpx>
+              <wpt lat="48.1" lon="-1.6"><ele>30</ele><time>2024-05-01T07:45:00Z</time>
+                <name>Lap 1</name><desc>Fish &amp; chips</desc><sym>Flag</sym></wpt>
+              <wpt lat="48.2"><name>no longitude</name></wpt>
+              <rte><name>Coast loop</name><desc><![CDATA[Plan <b>B</b>]]></desc>
+                <rtept lat="48.3" lon="-1.5"><name>Start</name></rtept>
+                <rtept lat="48.4" lon="-1.4"/>
+              </rte>
+              <trk><trkseg>
+                <trkpt lat="48.1" lon="-1.6"><time>2024-05-01T07:30:00Z</time></trkpt>
+              </trkseg></trk>
+            </gpx>"#,
+        );
+
+        assert_eq!(gpx.points.len(), 1);
+        assert_eq!(gpx.track_name, None);
+
+        let [wpt] = gpx.waypoints.as_slice() else {
+            panic!("expected 1 waypoint, got {}", gpx.waypoints.len());
+        };
+        assert_eq!((wpt.lat, wpt.lon, wpt.ele), (48.1, -1.6, Some(30.0)));
+        assert_eq!(wpt.t, parse_gpx_time("2024-05-01T07:45:00Z"));
+        assert_eq!(wpt.name.as_deref(), Some("Lap 1"));
+        assert_eq!(wpt.desc.as_deref(), Some("Fish & chips"));
+        assert_eq!(wpt.sym.as_deref(), Some("Flag"));
+
+        let [route] = gpx.routes.as_slice() else {
+            panic!("expected 1 route, got {}", gpx.routes.len());
+        };
+        assert_eq!(route.name.as_deref(), Some("Coast loop"));
+        assert_eq!(route.desc.as_deref(), Some("Plan <b>B</b>"));
+        let points: Vec<_> = route
+            .points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.name.as_deref()))
+            .collect();
+        assert_eq!(points, [(48.3, -1.5, Some("Start")), (48.4, -1.4, None)]);
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn lenient_time_forms_are_accepted() {
+        let t = utc("2024-05-01T07:30:00Z");
+        for s in [
+            "2024-05-01T07:30:00Z",
+            "2024-05-01T09:30:00+02:00",
+            "2024-05-01T09:30:00+0200",
+            "2024-05-01 09:30:00+0200",
+            "2024-05-01T07:30:00",
+            "2024-05-01 07:30:00",
+            "2024-05-01T07:30",
+            "2024-05-01 07:30Z",
+        ] {
+            assert_eq!(parse_gpx_time(s), Some(t), "{s}");
+        }
+        assert_eq!(
+            parse_gpx_time("2024-05-01T07:30:00.250Z"),
+            Some(t + TimeDelta::milliseconds(250))
+        );
+        assert_eq!(parse_gpx_time("yesterday"), None);
+    }
+
+    #[test]
+    fn invalid_coordinates_drop_the_point() {
+        let gpx = parse(
+            r#"<gpx><trk><trkseg>
+              <trkpt lat="NaN" lon="-1.6"><time>2024-05-01T07:30:00Z</time></trkpt>
+              <trkpt lat="48.1" lon="inf"><time>2024-05-01T07:30:01Z</time></trkpt>
+              <trkpt lat="91" lon="-1.6"><time>2024-05-01T07:30:02Z</time></trkpt>
+              <trkpt lat="48.1" lon="-180.5"><time>2024-05-01T07:30:03Z</time></trkpt>
+              <trkpt lat="48.1" lon="-1.6"><time>2024-05-01T07:30:04Z</time></trkpt>
+              <trkpt lat=" -90 " lon="180"><time>2024-05-01T07:30:05Z</time></trkpt>
+            </trkseg></trk></gpx>"#,
+        );
+        let coords: Vec<_> = gpx.points.iter().map(|p| (p.lat, p.lon)).collect();
+        assert_eq!(coords, [(48.1, -1.6), (-90.0, 180.0)]);
+        assert_eq!(gpx.dropped_points, 4);
+    }
+
+    const UNTIMED: &str = r#"<gpx><trk><trkseg>
+      <trkpt lat="48.0" lon="-1.0"/>
+      <trkpt lat="48.1" lon="-1.0"><time>2024-05-01T07:00:10Z</time></trkpt>
+      <trkpt lat="48.2" lon="-1.0"><time>not a time</time></trkpt>
+      <trkpt lat="48.3" lon="-1.0"/>
+      <trkpt lat="48.4" lon="-1.0"><time>2024-05-01T07:00:40Z</time></trkpt>
+      <trkpt lat="48.5" lon="-1.0"/>
+    </trkseg></trk></gpx>"#;
+
+    #[test]
+    fn untimed_points_are_interpolated_between_neighbours() {
+        let gpx = parse(UNTIMED);
+        let times: Vec<_> = gpx.points.iter().map(|p| (p.lat, p.t)).collect();
+        assert_eq!(
+            times,
+            [
+                (48.1, utc("2024-05-01T07:00:10Z")),
+                (48.2, utc("2024-05-01T07:00:20Z")),
+                (48.3, utc("2024-05-01T07:00:30Z")),
+                (48.4, utc("2024-05-01T07:00:40Z")),
+            ]
+        );
+        // Nothing to interpolate the first and last points from.
+        assert_eq!((gpx.interpolated_points, gpx.dropped_points), (2, 2));
+    }
+
+    #[test]
+    fn untimed_ends_are_placed_within_the_workout() {
+        let workout = (utc("2024-05-01T07:00:00Z"), utc("2024-05-01T07:01:00Z"));
+        let gpx = parse_gpx(UNTIMED.as_bytes(), Some(workout)).unwrap();
+        let times: Vec<_> = gpx.points.iter().map(|p| p.t).collect();
+        assert_eq!(times.first(), Some(&workout.0));
+        assert_eq!(times.last(), Some(&workout.1));
+        assert!(times.is_sorted());
+        let idx: Vec<_> = gpx.points.iter().map(|p| p.idx).collect();
+        assert_eq!(idx, [0, 1, 2, 3, 4, 5]);
+        assert_eq!((gpx.interpolated_points, gpx.dropped_points), (4, 0));
+    }
+
+    #[test]
+    fn track_without_times_is_spread_over_the_workout() {
+        let workout = (utc("2024-05-01T07:00:00Z"), utc("2024-05-01T07:00:20Z"));
+        let xml = r#"<gpx><trk><trkseg>
+          <trkpt lat="48.0" lon="-1.0"/><trkpt lat="48.1" lon="-1.0"/><trkpt lat="48.2" lon="-1.0"/>
+        </trkseg></trk></gpx>"#;
+        let gpx = parse_gpx(xml.as_bytes(), Some(workout)).unwrap();
+        let times: Vec<_> = gpx.points.iter().map(|p| p.t).collect();
+        assert_eq!(
+            times,
+            [
+                workout.0,
+                utc("2024-05-01T07:00:10Z"),
+                utc("2024-05-01T07:00:20Z")
+            ]
+        );
+        assert!(parse(xml).points.is_empty());
+    }
+
+    /// Fails every read: stands for the part of a file not reached yet.
+    struct Unreachable;
+
+    impl std::io::Read for Unreachable {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("read past the first points"))
+        }
+    }
+
+    #[test]
+    fn reader_yields_points_before_the_file_is_read() {
+        use std::io::{BufReader, Read};
+
+        let head = r#"<gpx><trk><trkseg>
+          <trkpt lat="48.0" lon="-1.0"><time>2024-05-01T07:00:00Z</time></trkpt>
+          <trkpt lat="48.1" lon="-1.0"><time>2024-05-01T07:00:01Z</time></trkpt>
+        "#;
+        let input = BufReader::with_capacity(16, head.as_bytes().chain(Unreachable));
+        let mut gpx = GpxReader::new(input, None);
+
+        let first = gpx.next().unwrap().unwrap();
+        assert_eq!((first.idx, first.lat), (0, 48.0));
+        let second = gpx.next().unwrap().unwrap();
+        assert_eq!((second.idx, second.lat), (1, 48.1));
+        assert!(gpx.next().unwrap().is_err());
+        assert!(gpx.next().is_none());
+    }
+
+    #[test]
+    fn reader_hands_out_waypoints_once_exhausted() {
+        let xml = r#"<gpx>
+          <trk><trkseg>
+            <trkpt lat="48.0" lon="-1.0"><time>2024-05-01T07:00:00Z</time></trkpt>
+          </trkseg></trk>
+          <wpt lat="48.5" lon="-1.5"><name>End</name></wpt>
+        </gpx>"#;
+        let mut gpx = GpxReader::new(xml.as_bytes(), None);
+        assert_eq!(gpx.by_ref().map(Result::unwrap).count(), 1);
+        let data = gpx.finish();
+        assert!(data.points.is_empty());
+        assert_eq!(data.waypoints.len(), 1);
+    }
+
+    #[test]
+    fn scan_reports_bounds_without_keeping_points() {
+        let xml = r#"<gpx><trk><name>Ride</name><type>cycling</type><trkseg>
+          <trkpt lat="48.0" lon="-1.0"><time>2024-05-01T07:10:00Z</time></trkpt>
+          <trkpt lat="48.1" lon="-1.1"><time>2024-05-01T07:00:00Z</time></trkpt>
+          <trkpt lat="48.2" lon="-1.2"><time>2024-05-01T07:20:00Z</time></trkpt>
+        </trkseg></trk></gpx>"#;
+        let scan = scan_track(xml.as_bytes()).unwrap().unwrap();
+        assert_eq!(scan.start, utc("2024-05-01T07:00:00Z"));
+        assert_eq!(scan.end, utc("2024-05-01T07:20:00Z"));
+        assert_eq!(
+            (scan.first_lat, scan.first_lon, scan.points),
+            (48.0, -1.0, 3)
+        );
+        assert_eq!(scan.track_name.as_deref(), Some("Ride"));
+        assert_eq!(scan.track_type.as_deref(), Some("cycling"));
+
+        let untimed = r#"<gpx><trk><trkseg><trkpt lat="48.0" lon="-1.0"/></trkseg></trk></gpx>"#;
+        assert!(scan_track(untimed.as_bytes()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::stored::StoredWorkout;
+use crate::types::{GpxPoint, GpxWaypoint};
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use quick_xml::escape::escape;
+use std::io::Write;
+
+const GPX_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="roudenn"
+  xmlns="http://www.topografix.com/GPX/1/1"
+  xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v2"
+  xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"
+  xsi:schemaLocation="http://www.topografix.com/GPX/1/1 http://www.topografix.com/GPX/1/1/gpx.xsd http://www.garmin.com/xmlschemas/TrackPointExtension/v2 https://www8.garmin.com/xmlschemas/TrackPointExtensionv2.xsd">
+"#;
+
+/// Write a stored workout as a GPX 1.1 document: its waypoints, then one `<trk>`
+/// per stored track with one `<trkseg>` per stored segment.
+pub fn write_gpx<W: Write + ?Sized>(
+    w: &mut W,
+    workout: &StoredWorkout,
+    points: &[GpxPoint],
+    waypoints: &[GpxWaypoint],
+) -> Result<()> {
+    let name = workout_name(workout);
+    let gpx_type = gpx_activity_type(&workout.activity);
+
+    w.write_all(GPX_HEADER.as_bytes())?;
+    writeln!(w, "  <metadata>")?;
+    writeln!(w, "    <name>{}</name>", escape(&name))?;
+    writeln!(w, "    <desc>{}</desc>", escape(&workout.activity))?;
+    writeln!(w, "    <time>{}</time>", gpx_time(workout.start))?;
+    writeln!(w, "    <keywords>{}</keywords>", escape(&workout.activity))?;
+    writeln!(w, "  </metadata>")?;
+
+    for wpt in waypoints {
+        write_waypoint(w, wpt)?;
+    }
+
+    let mut current: Option<(i32, i32)> = None;
+    for p in points {
+        match current {
+            Some((trk, seg)) if trk == p.trk && seg == p.seg => {}
+            Some((trk, _)) if trk == p.trk => {
+                writeln!(w, "    </trkseg>")?;
+                writeln!(w, "    <trkseg>")?;
+            }
+            _ => {
+                if current.is_some() {
+                    writeln!(w, "    </trkseg>")?;
+                    writeln!(w, "  </trk>")?;
+                }
+                writeln!(w, "  <trk>")?;
+                writeln!(w, "    <name>{}</name>", escape(&name))?;
+                writeln!(w, "    <type>{}</type>", escape(gpx_type))?;
+                writeln!(w, "    <trkseg>")?;
+            }
+        }
+        current = Some((p.trk, p.seg));
+        write_track_point(w, p)?;
+    }
+    if current.is_some() {
+        writeln!(w, "    </trkseg>")?;
+        writeln!(w, "  </trk>")?;
+    }
+
+    writeln!(w, "</gpx>")?;
+    Ok(())
+}
+
+fn write_track_point<W: Write + ?Sized>(w: &mut W, p: &GpxPoint) -> Result<()> {
+    writeln!(w, r#"      <trkpt lat="{:.7}" lon="{:.7}">"#, p.lat, p.lon)?;
+    if let Some(ele) = p.ele {
+        writeln!(w, "        <ele>{ele:.1}</ele>")?;
+    }
+    writeln!(w, "        <time>{}</time>", gpx_time(p.t))?;
+
+    // TrackPointExtension v2 fields, in schema order. hr and cad are unsignedByte.
+    let hr = p.hr.filter(|v| (0..=255).contains(v));
+    let cad = p.cad.filter(|v| (0..=255).contains(v));
+    if p.atemp.is_some() || hr.is_some() || cad.is_some() || p.speed.is_some() || p.course.is_some()
+    {
+        writeln!(w, "        <extensions>")?;
+        writeln!(w, "          <gpxtpx:TrackPointExtension>")?;
+        if let Some(v) = p.atemp {
+            writeln!(w, "            <gpxtpx:atemp>{v:.1}</gpxtpx:atemp>")?;
+        }
+        if let Some(v) = hr {
+            writeln!(w, "            <gpxtpx:hr>{v}</gpxtpx:hr>")?;
+        }
+        if let Some(v) = cad {
+            writeln!(w, "            <gpxtpx:cad>{v}</gpxtpx:cad>")?;
+        }
+        if let Some(v) = p.speed.filter(|v| *v >= 0.0) {
+            writeln!(w, "            <gpxtpx:speed>{v:.3}</gpxtpx:speed>")?;
+        }
+        if let Some(v) = p.course.filter(|v| (0.0..360.0).contains(v)) {
+            writeln!(w, "            <gpxtpx:course>{v:.1}</gpxtpx:course>")?;
+        }
+        writeln!(w, "          </gpxtpx:TrackPointExtension>")?;
+        writeln!(w, "        </extensions>")?;
+    }
+
+    writeln!(w, "      </trkpt>")?;
+    Ok(())
+}
+
+fn write_waypoint<W: Write + ?Sized>(w: &mut W, wpt: &GpxWaypoint) -> Result<()> {
+    writeln!(w, r#"  <wpt lat="{:.7}" lon="{:.7}">"#, wpt.lat, wpt.lon)?;
+    if let Some(ele) = wpt.ele {
+        writeln!(w, "    <ele>{ele:.1}</ele>")?;
+    }
+    if let Some(t) = wpt.t {
+        writeln!(w, "    <time>{}</time>", gpx_time(t))?;
+    }
+    if let Some(name) = &wpt.name {
+        writeln!(w, "    <name>{}</name>", escape(name))?;
+    }
+    if let Some(desc) = &wpt.desc {
+        writeln!(w, "    <desc>{}</desc>", escape(desc))?;
+    }
+    if let Some(sym) = &wpt.sym {
+        writeln!(w, "    <sym>{}</sym>", escape(sym))?;
+    }
+    writeln!(w, "  </wpt>")?;
+    Ok(())
+}
+
+/// The workout's name, or one made up from its activity and start time.
+pub fn workout_name(workout: &StoredWorkout) -> String {
+    workout.name.clone().unwrap_or_else(|| {
+        format!(
+            "{} {}",
+            workout.activity,
+            workout.start.format("%Y-%m-%d %H:%M")
+        )
+    })
+}
+
+/// `<trk><type>` for a stored activity, as understood by Strava, Garmin Connect
+/// and most other tools.
+fn gpx_activity_type(activity: &str) -> &str {
+    match activity {
+        "outdoor_running" | "treadmill" => "running",
+        other => other,
+    }
+}
+
+fn gpx_time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
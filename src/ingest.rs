@@ -1,13 +1,31 @@
 use crate::database::read_base_activity_summary;
 use crate::dlog;
-use crate::gpx::parse_gpx_points;
-use crate::types::{GpxPoint, WorkoutSummary};
-use crate::utils::{duration_seconds_i32, e7_to_degrees, map_android_gpx_to_export};
+use crate::export::Export;
+use crate::file_index::{FileIndex, Resolution};
+use crate::fit::{is_fit, parse_fit};
+use crate::gpx::{GpxReader, log_time_repairs};
+use crate::orphans::{OrphanTrack, find_orphans, referenced_files};
+use crate::types::{
+    ACTIVITY_KIND_OUTDOOR_RUNNING, ACTIVITY_KIND_TREADMILL, GpxData, GpxPoint, GpxRoute,
+    GpxWaypoint, WorkoutRecords, WorkoutSample, WorkoutSummary,
+};
+use crate::utils::{duration_seconds_i32, e7_to_degrees, has_extension};
 use anyhow::{Context, Result, bail};
-use postgres::{Client, NoTls};
+use chrono::{DateTime, SecondsFormat, Utc};
+use postgres::{Client, GenericClient, NoTls};
+use std::collections::HashSet;
+use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
-pub fn ingest(export_dir: &Path, pg_url: &str) -> Result<()> {
+/// Optional ingest behaviour.
+#[derive(Debug, Clone, Default)]
+pub struct IngestOptions {
+    /// Also import GPX tracks (and their raw details) that no DB row
+    /// references, as workouts with `source = 'orphan_file'`.
+    pub import_orphans: bool,
+}
+
+pub fn ingest(export: &Export, pg_url: &str, opts: &IngestOptions) -> Result<()> {
     // Always store raw details + import points now.
     let store_raw_details = true;
     let with_points = true;
@@ -15,64 +33,248 @@ pub fn ingest(export_dir: &Path, pg_url: &str) -> Result<()> {
     let mut pg = connect_or_create_db(pg_url)?;
     ensure_pg_schema(&mut pg)?;
 
-    let summaries = read_base_activity_summary(export_dir, store_raw_details)?;
+    let summaries = read_base_activity_summary(export)?;
     let total = summaries.len();
     tracing::info!(summaries = total, "found workouts");
 
+    let files = FileIndex::build(export)?;
+    let referenced = opts
+        .import_orphans
+        .then(|| referenced_files(&files, &summaries));
+
     let mut inserted_or_updated = 0usize;
     let mut points_imported = 0usize;
     let mut workouts_with_points = 0usize;
+    let mut gpx_report = FileReport::default();
+    let mut raw_details_report = FileReport::default();
+    let mut orphans_imported = 0usize;
+    let mut orphans_already_stored = 0usize;
+    let mut waypoints_imported = 0usize;
+    let mut routes_imported = 0usize;
+    let mut points_interpolated = 0usize;
+    let mut points_dropped = 0usize;
+    let mut samples_imported = 0usize;
 
-    for s in summaries {
-        let Some(activity) = activity_label(s.activity_kind) else {
+    for mut s in summaries {
+        let label = activity_label(s.activity_kind);
+        // Rows of other activities are kept only when a FIT file tells their sport.
+        let fit_details = s
+            .raw_details_android
+            .as_deref()
+            .is_some_and(|p| has_extension(Path::new(p), "fit"));
+        if label.is_none() && !fit_details {
             continue; // ignore all other activities
+        }
+
+        if store_raw_details
+            && let Some(android_path) = s.raw_details_android.as_deref()
+            && let Some(rel) = resolve_file(export, &files, android_path, &mut raw_details_report)
+        {
+            match export.read_file(&rel) {
+                Ok(bytes) => s.raw_details = Some(bytes),
+                Err(e) => {
+                    tracing::warn!(path = %rel, err = format!("{e:#}"), "reading raw details failed")
+                }
+            }
+        }
+
+        // Garmin devices (and some Zepp OS ones) keep the activity as a FIT file
+        // in place of Huami raw details, often without a GPX track.
+        let fit = s
+            .raw_details
+            .as_deref()
+            .filter(|bytes| is_fit(bytes))
+            .and_then(|bytes| match parse_fit(bytes, s.source) {
+                Ok(activity) => activity,
+                Err(e) => {
+                    tracing::warn!(start = %s.start, err = format!("{e:#}"), "decoding fit raw details failed");
+                    None
+                }
+            });
+
+        let Some(activity) = label.or_else(|| fit.as_ref().map(|f| f.activity)) else {
+            continue;
         };
 
         let workout_id = upsert_workout(&mut pg, &s, activity)?;
         inserted_or_updated += 1;
 
         if with_points {
-            let Some(android_path) = s.gpx_track_android.as_deref() else {
-                continue;
-            };
+            let gpx_path = s.gpx_track_android.as_deref().and_then(|android_path| {
+                resolve_file(export, &files, android_path, &mut gpx_report)
+            });
 
-            let Some(gpx_path) = map_android_gpx_to_export(export_dir, android_path) else {
-                dlog!("gpx_map_failed android_path={android_path}");
-                continue;
-            };
+            let mut points = 0;
+            if let Some(gpx_path) = gpx_path {
+                let gpx = import_gpx_file(
+                    &mut pg,
+                    export,
+                    &gpx_path,
+                    workout_id,
+                    Some((s.start, s.end)),
+                )?;
+                points += gpx.points;
+                points_interpolated += gpx.data.interpolated_points;
+                points_dropped += gpx.data.dropped_points;
+                waypoints_imported += gpx.data.waypoints.len();
+                routes_imported += gpx.data.routes.len();
+            }
 
-            if !gpx_path.exists() {
-                tracing::warn!(path = %gpx_path.display(), "gpx file referenced by db is missing");
-                continue;
+            if let Some(fit) = fit {
+                // The GPX track, when there is one, stays the source of points.
+                let imported = import_records(&mut pg, workout_id, &fit.records, points == 0)?;
+                points += imported.points;
+                samples_imported += imported.samples;
             }
 
-            let pts = parse_gpx_points(&gpx_path)
-                .with_context(|| format!("Parsing GPX points: {}", gpx_path.display()))?;
+            if points > 0 {
+                workouts_with_points += 1;
+            }
+            points_imported += points;
+        }
+    }
 
-            if pts.is_empty() {
+    if let Some(referenced) = referenced {
+        let orphans = find_orphans(export, &files, &referenced)?;
+        for o in orphans.workouts {
+            if let Some((id, source)) = overlapping_workout(&mut pg, &o.summary)? {
+                tracing::info!(
+                    path = o
+                        .summary
+                        .gpx_track_android
+                        .as_deref()
+                        .or(o.summary.raw_details_android.as_deref()),
+                    start = %o.summary.start,
+                    workout = id,
+                    source,
+                    "skipping orphan track of a workout already stored"
+                );
+                orphans_already_stored += 1;
                 continue;
             }
+            let workout_id = upsert_workout(&mut pg, &o.summary, o.activity)?;
+            orphans_imported += 1;
+            match &o.track {
+                OrphanTrack::Gpx(gpx_path) => {
+                    let gpx = import_gpx_file(&mut pg, export, gpx_path, workout_id, None)?;
+                    workouts_with_points += 1;
+                    points_imported += gpx.points;
+                    points_interpolated += gpx.data.interpolated_points;
+                    points_dropped += gpx.data.dropped_points;
+                    waypoints_imported += gpx.data.waypoints.len();
+                    routes_imported += gpx.data.routes.len();
+                }
+                OrphanTrack::Fit(records) => {
+                    let imported = import_records(&mut pg, workout_id, records, true)?;
+                    if imported.points > 0 {
+                        workouts_with_points += 1;
+                    }
+                    points_imported += imported.points;
+                    samples_imported += imported.samples;
+                }
+            }
+        }
 
-            import_points_for_workout(&mut pg, workout_id, &pts)?;
-            workouts_with_points += 1;
-            points_imported += pts.len();
+        if !orphans.unmatched_raw_details.is_empty() {
+            tracing::warn!(
+                count = orphans.unmatched_raw_details.len(),
+                files = ?orphans.unmatched_raw_details,
+                "orphan raw-details files have no matching gpx track to time them; skipping"
+            );
         }
     }
+
     refresh_workout_distance_matview(&mut pg)?;
     tracing::info!(
         workouts_upserted = inserted_or_updated,
+        orphans_imported = orphans_imported,
+        orphans_already_stored = orphans_already_stored,
         workouts_with_points = workouts_with_points,
         points_imported = points_imported,
+        points_interpolated = points_interpolated,
+        points_dropped = points_dropped,
+        waypoints_imported = waypoints_imported,
+        routes_imported = routes_imported,
+        samples_imported = samples_imported,
+        gpx_missing = gpx_report.missing,
+        gpx_ambiguous = gpx_report.ambiguous,
+        raw_details_missing = raw_details_report.missing,
+        raw_details_ambiguous = raw_details_report.ambiguous,
         "ingest done"
     );
 
+    if !export.has_files() && (gpx_report.missing > 0 || raw_details_report.missing > 0) {
+        tracing::warn!(
+            gpx_tracks = gpx_report.missing,
+            raw_details = raw_details_report.missing,
+            "export is a bare database: GPX tracks and raw details were not imported \
+             (pass --files-dir to include them)"
+        );
+    }
+
     Ok(())
 }
 
+/// A stored workout, other than the orphan's own row from an earlier ingest,
+/// whose time range overlaps the orphan's: the track's DB row was lost, but the
+/// workout is still there under its real device.
+fn overlapping_workout(pg: &mut Client, orphan: &WorkoutSummary) -> Result<Option<(i64, String)>> {
+    let row = pg
+        .query_opt(
+            "SELECT id, source FROM workouts
+             WHERE start_time < $2 AND end_time > $1
+               AND NOT (source = $3 AND start_time = $1)
+             ORDER BY start_time
+             LIMIT 1",
+            &[&orphan.start, &orphan.end, &orphan.source],
+        )
+        .context("Looking for workouts overlapping an orphan track")?;
+    Ok(row.map(|r| (r.get(0), r.get(1))))
+}
+
+/// How many DB-referenced files of one kind could not be resolved.
+#[derive(Debug, Default)]
+pub(crate) struct FileReport {
+    pub(crate) missing: usize,
+    pub(crate) ambiguous: usize,
+}
+
+/// Resolve an Android path from the DB to an export file, recording failures in `report`.
+pub(crate) fn resolve_file(
+    export: &Export,
+    files: &FileIndex,
+    android_path: &str,
+    report: &mut FileReport,
+) -> Option<String> {
+    match files.resolve(android_path) {
+        Resolution::Found(rel) => {
+            dlog!("file_resolved android_path={android_path} rel={rel}");
+            Some(rel)
+        }
+        Resolution::Missing => {
+            report.missing += 1;
+            // A bare DB has no files at all; that is summarized once at the end.
+            if export.has_files() {
+                tracing::warn!(path = %android_path, "file referenced by db is missing from export");
+            }
+            None
+        }
+        Resolution::Ambiguous(candidates) => {
+            report.ambiguous += 1;
+            tracing::warn!(
+                path = %android_path,
+                candidates = ?candidates,
+                "file referenced by db matches several export files; skipping"
+            );
+            None
+        }
+    }
+}
+
 /// Connect to pg_url. If the database in the URL doesn't exist, create it and retry.
 ///
 /// This requires privileges to CREATE DATABASE.
-fn connect_or_create_db(pg_url: &str) -> Result<Client> {
+pub(crate) fn connect_or_create_db(pg_url: &str) -> Result<Client> {
     match Client::connect(pg_url, NoTls) {
         Ok(pg) => return Ok(pg),
         Err(e) => {
@@ -173,29 +375,49 @@ fn admin_urls_for_create_db(pg_url: &str) -> Result<(String, String, String)> {
 
     Ok((db_name.to_string(), admin_postgres, admin_template1))
 }
+/// Bumped whenever the definition of `workout_distance_m` changes; stored as the
+/// view's comment so older views are rebuilt on the next run.
+const WORKOUT_DISTANCE_MATVIEW_VERSION: &str = "roudenn:workout_distance_m:v3";
+
 fn ensure_workout_distance_matview(pg: &mut Client) -> Result<()> {
-    // Does the materialized view already exist?
-    let exists = pg
+    // Does the materialized view already exist, and in which version?
+    let existing: Option<Option<String>> = pg
         .query_opt(
             r#"
-            SELECT 1
-            FROM pg_matviews
-            WHERE schemaname = 'public'
-              AND matviewname = 'workout_distance_m'
+            SELECT obj_description(c.oid, 'pg_class')
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname = 'public'
+              AND c.relname = 'workout_distance_m'
+              AND c.relkind = 'm'
             "#,
             &[],
         )
         .context("Checking for materialized view public.workout_distance_m")?
-        .is_some();
+        .map(|row| row.get(0));
 
-    if exists {
-        tracing::info!("materialized view workout_distance_m already exists");
-        return Ok(());
+    match existing {
+        Some(Some(version)) if version == WORKOUT_DISTANCE_MATVIEW_VERSION => {
+            tracing::info!("materialized view workout_distance_m already exists");
+            return Ok(());
+        }
+        Some(version) => {
+            tracing::info!(
+                old = version.as_deref().unwrap_or("v1"),
+                new = WORKOUT_DISTANCE_MATVIEW_VERSION,
+                "recreating outdated materialized view workout_distance_m"
+            );
+            pg.batch_execute("DROP MATERIALIZED VIEW public.workout_distance_m;")
+                .context("Dropping outdated materialized view public.workout_distance_m")?;
+        }
+        None => {}
     }
 
     tracing::info!("creating materialized view workout_distance_m");
 
-    // Compute per-workout distance (meters) by summing haversine distances between consecutive points.
+    // Compute per-workout distance (meters) by summing haversine distances between
+    // consecutive points of the same track segment, so pauses don't count.
+    // Workouts merged into another one are left out, so totals count them once.
     pg.batch_execute(
         r#"
         CREATE MATERIALIZED VIEW public.workout_distance_m AS
@@ -205,9 +427,10 @@ fn ensure_workout_distance_matview(pg: &mut Client) -> Result<()> {
             idx,
             lat,
             lon,
-            LAG(lat) OVER (PARTITION BY workout_id ORDER BY idx) AS lat0,
-            LAG(lon) OVER (PARTITION BY workout_id ORDER BY idx) AS lon0
+            LAG(lat) OVER (PARTITION BY workout_id, trk, seg ORDER BY idx) AS lat0,
+            LAG(lon) OVER (PARTITION BY workout_id, trk, seg ORDER BY idx) AS lon0
           FROM public.workout_points
+          WHERE workout_id IN (SELECT id FROM public.workouts_effective)
         ),
         seg AS (
           SELECT
@@ -240,10 +463,18 @@ fn ensure_workout_distance_matview(pg: &mut Client) -> Result<()> {
     )
     .context("Creating unique index on public.workout_distance_m")?;
 
+    pg.execute(
+        &format!(
+            "COMMENT ON MATERIALIZED VIEW public.workout_distance_m IS '{WORKOUT_DISTANCE_MATVIEW_VERSION}'"
+        ),
+        &[],
+    )
+    .context("Tagging materialized view public.workout_distance_m")?;
+
     Ok(())
 }
 
-fn refresh_workout_distance_matview(pg: &mut Client) -> Result<()> {
+pub(crate) fn refresh_workout_distance_matview(pg: &mut Client) -> Result<()> {
     // Concurrent refresh avoids blocking reads in Grafana.
     // NOTE: This must not run inside an explicit transaction.
     pg.batch_execute("REFRESH MATERIALIZED VIEW CONCURRENTLY public.workout_distance_m;")
@@ -251,7 +482,7 @@ fn refresh_workout_distance_matview(pg: &mut Client) -> Result<()> {
     Ok(())
 }
 
-fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
+pub(crate) fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
     pg.batch_execute(
         r"
         CREATE TABLE IF NOT EXISTS workouts (
@@ -283,9 +514,7 @@ fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
           raw_details        bytea,
 
           created_at         timestamptz NOT NULL DEFAULT now(),
-          updated_at         timestamptz NOT NULL DEFAULT now(),
-
-          UNIQUE (device_id, start_time)
+          updated_at         timestamptz NOT NULL DEFAULT now()
         );
 
         CREATE INDEX IF NOT EXISTS workouts_start_time_idx ON workouts (start_time DESC);
@@ -302,6 +531,57 @@ fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
         );
 
         CREATE INDEX IF NOT EXISTS workout_points_t_idx ON workout_points (t);
+
+        CREATE TABLE IF NOT EXISTS workout_waypoints (
+          workout_id  bigint NOT NULL REFERENCES workouts(id) ON DELETE CASCADE,
+          idx         int NOT NULL,
+          t           timestamptz,
+          lat         double precision NOT NULL,
+          lon         double precision NOT NULL,
+          ele         double precision,
+          name        text,
+          description text,
+          symbol      text,
+          PRIMARY KEY (workout_id, idx)
+        );
+
+        CREATE TABLE IF NOT EXISTS routes (
+          id          bigserial PRIMARY KEY,
+          workout_id  bigint NOT NULL REFERENCES workouts(id) ON DELETE CASCADE,
+          idx         int NOT NULL,
+          name        text,
+          description text,
+          UNIQUE (workout_id, idx)
+        );
+
+        CREATE TABLE IF NOT EXISTS route_points (
+          route_id    bigint NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+          idx         int NOT NULL,
+          lat         double precision NOT NULL,
+          lon         double precision NOT NULL,
+          ele         double precision,
+          name        text,
+          description text,
+          symbol      text,
+          PRIMARY KEY (route_id, idx)
+        );
+
+        -- Sensor readings with or without a position, e.g. the records of FIT files.
+        CREATE TABLE IF NOT EXISTS workout_samples (
+          workout_id  bigint NOT NULL REFERENCES workouts(id) ON DELETE CASCADE,
+          idx         int NOT NULL,
+          t           timestamptz NOT NULL,
+          hr          int,
+          cad         int,
+          speed       double precision,
+          distance_m  double precision,
+          ele         double precision,
+          power       int,
+          atemp       double precision,
+          PRIMARY KEY (workout_id, idx)
+        );
+
+        CREATE INDEX IF NOT EXISTS workout_samples_t_idx ON workout_samples (t);
         ",
     )
     .context("Ensuring PostgreSQL schema")?;
@@ -320,6 +600,57 @@ fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
     WHERE activity = 'other';
 
     CREATE INDEX IF NOT EXISTS workouts_activity_idx ON workouts (activity);
+
+    ALTER TABLE workouts
+      ADD COLUMN IF NOT EXISTS source text NOT NULL DEFAULT 'gadgetbridge';
+
+    CREATE INDEX IF NOT EXISTS workouts_source_idx ON workouts (source);
+
+    -- Stable public identifier, e.g. for `roudenn export --workout`.
+    ALTER TABLE workouts
+      ADD COLUMN IF NOT EXISTS uuid uuid NOT NULL DEFAULT gen_random_uuid();
+
+    CREATE UNIQUE INDEX IF NOT EXISTS workouts_uuid_idx ON workouts (uuid);
+
+    -- Id of the workout where it came from, e.g. an imported file's path.
+    -- Re-imports match on it; workouts without one on device and start.
+    ALTER TABLE workouts
+      ADD COLUMN IF NOT EXISTS external_id text;
+
+    CREATE UNIQUE INDEX IF NOT EXISTS workouts_source_external_id_idx
+      ON workouts (source, external_id);
+
+    ALTER TABLE workouts DROP CONSTRAINT IF EXISTS workouts_device_id_start_time_key;
+
+    CREATE UNIQUE INDEX IF NOT EXISTS workouts_device_start_idx
+      ON workouts (device_id, start_time) WHERE external_id IS NULL;
+
+    ALTER TABLE workout_points
+      ADD COLUMN IF NOT EXISTS hr     int,
+      ADD COLUMN IF NOT EXISTS cad    int,
+      ADD COLUMN IF NOT EXISTS atemp  double precision,
+      ADD COLUMN IF NOT EXISTS speed  double precision,
+      ADD COLUMN IF NOT EXISTS course double precision,
+      ADD COLUMN IF NOT EXISTS trk    int NOT NULL DEFAULT 0,
+      ADD COLUMN IF NOT EXISTS seg    int NOT NULL DEFAULT 0;
+
+    -- The workouts a merged workout was built from, and which of its fields
+    -- (gps, hr, summary) each one gave. A workout is merged into one at most.
+    CREATE TABLE IF NOT EXISTS workout_links (
+      merged_workout_id  bigint NOT NULL REFERENCES workouts(id) ON DELETE CASCADE,
+      source_workout_id  bigint NOT NULL REFERENCES workouts(id) ON DELETE CASCADE,
+      fields             text[] NOT NULL,
+      PRIMARY KEY (merged_workout_id, source_workout_id)
+    );
+
+    CREATE UNIQUE INDEX IF NOT EXISTS workout_links_source_idx
+      ON workout_links (source_workout_id);
+
+    -- Workouts that count: all but those merged into another one. Recreated
+    -- after the ALTERs above so it picks up new workouts columns.
+    CREATE OR REPLACE VIEW workouts_effective AS
+      SELECT w.* FROM workouts w
+      WHERE NOT EXISTS (SELECT 1 FROM workout_links l WHERE l.source_workout_id = w.id);
     "#,
     )?;
 
@@ -328,25 +659,91 @@ fn ensure_pg_schema(pg: &mut Client) -> Result<()> {
     Ok(())
 }
 
-fn activity_label(kind: i32) -> Option<&'static str> {
+/// Tables and the newest of their columns that commands only reading the
+/// database rely on.
+const REQUIRED_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "workouts",
+        &[
+            "uuid",
+            "activity",
+            "source",
+            "external_id",
+            "summary_data_json",
+        ],
+    ),
+    (
+        "workout_points",
+        &["trk", "seg", "hr", "cad", "atemp", "speed", "course"],
+    ),
+    ("workout_waypoints", &["symbol"]),
+    ("workout_samples", &["hr", "distance_m", "power"]),
+    ("workout_links", &["source_workout_id", "fields"]),
+    ("workouts_effective", &["id", "external_id"]),
+];
+
+/// Check, without changing anything, that the database has the schema an
+/// ingest creates, so read-only commands (exports) work under a read-only role
+/// and never migrate a database behind the user's back.
+pub(crate) fn check_pg_schema(pg: &mut Client) -> Result<()> {
+    let present: HashSet<(String, String)> = pg
+        .query(
+            "SELECT table_name::text, column_name::text FROM information_schema.columns
+             WHERE table_schema = 'public'",
+            &[],
+        )
+        .context("Reading PostgreSQL schema")?
+        .iter()
+        .map(|r| (r.get(0), r.get(1)))
+        .collect();
+
+    let missing: Vec<String> = REQUIRED_COLUMNS
+        .iter()
+        .flat_map(|(table, columns)| columns.iter().map(move |column| (*table, *column)))
+        .filter(|(table, column)| !present.contains(&((*table).to_owned(), (*column).to_owned())))
+        .map(|(table, column)| format!("{table}.{column}"))
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "the database lacks {} (an older or empty roudenn schema); \
+             run an ingest to create or update it",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn activity_label(kind: i32) -> Option<&'static str> {
     match kind {
-        67109041 => Some("outdoor_running"),
-        256 => Some("treadmill"),
+        ACTIVITY_KIND_OUTDOOR_RUNNING => Some("outdoor_running"),
+        ACTIVITY_KIND_TREADMILL => Some("treadmill"),
         _ => None,
     }
 }
 
-fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result<i64> {
+/// Insert or update a workout, keyed on `(source, external_id)` or, without an
+/// external id, on `(device_id, start_time)`. Takes a client or a transaction.
+pub(crate) fn upsert_workout(
+    pg: &mut impl GenericClient,
+    s: &WorkoutSummary,
+    activity: &str,
+) -> Result<i64> {
     let duration_s_i32 = duration_seconds_i32(s.end - s.start);
     let (base_lon, base_lat) = e7_to_degrees(s.base_longitude_e7, s.base_latitude_e7);
 
     let summary_json = s.summary_data_json.as_ref();
     let raw_summary_data = s.raw_summary_data.as_deref();
     let raw_details = s.raw_details.as_deref();
+    let conflict = if s.external_id.is_some() {
+        "(source, external_id)"
+    } else {
+        "(device_id, start_time) WHERE external_id IS NULL"
+    };
 
     let row = pg
         .query_one(
-            r#"
+            &format!(
+                r#"
             INSERT INTO workouts (
               device_id, user_id, activity_kind, activity,
               start_time, end_time, duration_s,
@@ -356,6 +753,7 @@ fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result
               gpx_track_android, raw_details_android,
               summary_data_raw, summary_data_json,
               raw_summary_data, raw_details,
+              source, external_id,
               updated_at
             )
             VALUES (
@@ -367,9 +765,10 @@ fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result
               $14, $15,
               $16, $17,
               $18, $19,
+              $20, $21,
               now()
             )
-            ON CONFLICT (device_id, start_time) DO UPDATE SET
+            ON CONFLICT {conflict} DO UPDATE SET
               user_id = EXCLUDED.user_id,
               activity_kind = EXCLUDED.activity_kind,
               activity = EXCLUDED.activity,
@@ -386,10 +785,13 @@ fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result
               summary_data_raw = EXCLUDED.summary_data_raw,
               summary_data_json = EXCLUDED.summary_data_json,
               raw_summary_data = EXCLUDED.raw_summary_data,
-              raw_details = EXCLUDED.raw_details,
+              -- Keep previously imported raw details when this export lacks the file.
+              raw_details = COALESCE(EXCLUDED.raw_details, workouts.raw_details),
+              source = EXCLUDED.source,
               updated_at = now()
             RETURNING id
-            "#,
+            "#
+            ),
             &[
                 &s.device_id,           // $1
                 &s.user_id,             // $2
@@ -410,6 +812,8 @@ fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result
                 &summary_json,          // $17
                 &raw_summary_data,      // $18
                 &raw_details,           // $19
+                &s.source,              // $20
+                &s.external_id,         // $21
             ],
         )
         .context("Upserting workout")?;
@@ -417,7 +821,41 @@ fn upsert_workout(pg: &mut Client, s: &WorkoutSummary, activity: &str) -> Result
     Ok(row.get(0))
 }
 
-fn import_points_for_workout(pg: &mut Client, workout_id: i64, points: &[GpxPoint]) -> Result<()> {
+pub(crate) struct GpxImported {
+    pub(crate) points: usize,
+    /// Waypoints, routes and repair counts (no points, those were streamed).
+    pub(crate) data: GpxData,
+}
+
+/// Replace a workout's track points, waypoints and routes with those of the
+/// export's GPX file `gpx_path`. Points are streamed from the file straight into
+/// a `COPY`, so long tracks are never held in memory.
+///
+/// A file without any points, waypoints or routes leaves stored data untouched.
+pub(crate) fn import_gpx_file(
+    pg: &mut Client,
+    export: &Export,
+    gpx_path: &str,
+    workout_id: i64,
+    workout_bounds: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<GpxImported> {
+    export
+        .with_file(gpx_path, |r| {
+            import_gpx(pg, r, gpx_path, workout_id, workout_bounds)
+        })
+        .with_context(|| format!("Importing GPX: {gpx_path}"))
+}
+
+/// [`import_gpx_file`] for a GPX read from `r`; `path` is only logged.
+pub(crate) fn import_gpx(
+    pg: &mut Client,
+    r: &mut dyn Read,
+    path: &str,
+    workout_id: i64,
+    workout_bounds: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<GpxImported> {
+    let mut gpx = GpxReader::new(BufReader::new(r), workout_bounds);
+
     let mut tx = pg
         .transaction()
         .context("Starting transaction for points")?;
@@ -427,18 +865,217 @@ fn import_points_for_workout(pg: &mut Client, workout_id: i64, points: &[GpxPoin
         &[&workout_id],
     )
     .context("Deleting existing points")?;
+    tx.execute(
+        "DELETE FROM workout_waypoints WHERE workout_id=$1",
+        &[&workout_id],
+    )
+    .context("Deleting existing waypoints")?;
+    tx.execute("DELETE FROM routes WHERE workout_id=$1", &[&workout_id])
+        .context("Deleting existing routes")?;
+
+    let points = copy_points(&mut tx, workout_id, &mut gpx)?;
+    let data = gpx.finish();
+    log_time_repairs(path, &data);
+
+    if points == 0 && data.waypoints.is_empty() && data.routes.is_empty() {
+        // Dropping `tx` rolls the deletes back.
+        return Ok(GpxImported { points, data });
+    }
+
+    insert_waypoints(&mut tx, workout_id, &data.waypoints)?;
+    insert_routes(&mut tx, workout_id, &data.routes)?;
+
+    tx.commit().context("Committing points transaction")?;
+    Ok(GpxImported { points, data })
+}
+
+/// `COPY` the points into `workout_points`, returning how many were written.
+fn copy_points(
+    tx: &mut postgres::Transaction<'_>,
+    workout_id: i64,
+    points: impl Iterator<Item = Result<GpxPoint>>,
+) -> Result<usize> {
+    let mut w = tx
+        .copy_in(
+            "COPY workout_points (workout_id, idx, trk, seg, t, lat, lon, ele, hr, cad, atemp, speed, course)
+             FROM STDIN",
+        )
+        .context("Starting point COPY")?;
+
+    let mut n = 0usize;
+    for p in points {
+        let p = p?;
+        writeln!(
+            w,
+            "{workout_id}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            p.idx,
+            p.trk,
+            p.seg,
+            p.t.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            p.lat,
+            p.lon,
+            CopyValue(p.ele),
+            CopyValue(p.hr),
+            CopyValue(p.cad),
+            CopyValue(p.atemp),
+            CopyValue(p.speed),
+            CopyValue(p.course),
+        )
+        .context("Writing point to COPY")?;
+        n += 1;
+    }
 
+    w.finish().context("Finishing point COPY")?;
+    Ok(n)
+}
+
+pub(crate) struct RecordsImported {
+    pub(crate) points: usize,
+    pub(crate) samples: usize,
+}
+
+/// Replace a workout's samples with the records of a FIT or TCX file and, with
+/// `with_points`, its track points with the positioned records. Within a
+/// transaction, this runs in a savepoint of it.
+pub(crate) fn import_records(
+    pg: &mut impl GenericClient,
+    workout_id: i64,
+    records: &WorkoutRecords,
+    with_points: bool,
+) -> Result<RecordsImported> {
+    let mut tx = pg
+        .transaction()
+        .context("Starting transaction for samples")?;
+
+    tx.execute(
+        "DELETE FROM workout_samples WHERE workout_id=$1",
+        &[&workout_id],
+    )
+    .context("Deleting existing samples")?;
+    let samples = copy_samples(&mut tx, workout_id, &records.samples)?;
+
+    let mut points = 0;
+    if with_points && !records.points.is_empty() {
+        tx.execute(
+            "DELETE FROM workout_points WHERE workout_id=$1",
+            &[&workout_id],
+        )
+        .context("Deleting existing points")?;
+        points = copy_points(&mut tx, workout_id, records.points.iter().cloned().map(Ok))?;
+    }
+
+    tx.commit().context("Committing samples transaction")?;
+    Ok(RecordsImported { points, samples })
+}
+
+/// `COPY` the samples into `workout_samples`, returning how many were written.
+fn copy_samples(
+    tx: &mut postgres::Transaction<'_>,
+    workout_id: i64,
+    samples: &[WorkoutSample],
+) -> Result<usize> {
+    let mut w = tx
+        .copy_in(
+            "COPY workout_samples (workout_id, idx, t, hr, cad, speed, distance_m, ele, power, atemp)
+             FROM STDIN",
+        )
+        .context("Starting sample COPY")?;
+
+    for s in samples {
+        writeln!(
+            w,
+            "{workout_id}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            s.idx,
+            s.t.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            CopyValue(s.hr),
+            CopyValue(s.cad),
+            CopyValue(s.speed),
+            CopyValue(s.distance_m),
+            CopyValue(s.ele),
+            CopyValue(s.power),
+            CopyValue(s.atemp),
+        )
+        .context("Writing sample to COPY")?;
+    }
+
+    w.finish().context("Finishing sample COPY")?;
+    Ok(samples.len())
+}
+
+/// A nullable value in `COPY` text format.
+struct CopyValue<T>(Option<T>);
+
+impl<T: std::fmt::Display> std::fmt::Display for CopyValue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(v) => v.fmt(f),
+            None => f.write_str("\\N"),
+        }
+    }
+}
+
+fn insert_waypoints(
+    tx: &mut postgres::Transaction<'_>,
+    workout_id: i64,
+    waypoints: &[GpxWaypoint],
+) -> Result<()> {
     let stmt = tx
         .prepare(
-            "INSERT INTO workout_points (workout_id, idx, t, lat, lon, ele) VALUES ($1, $2, $3, $4, $5, $6)",
+            "INSERT INTO workout_waypoints (workout_id, idx, t, lat, lon, ele, name, description, symbol)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
         )
-        .context("Preparing point insert")?;
+        .context("Preparing waypoint insert")?;
 
-    for p in points {
-        tx.execute(&stmt, &[&workout_id, &p.idx, &p.t, &p.lat, &p.lon, &p.ele])
-            .context("Inserting point")?;
+    for (idx, w) in (0i32..).zip(waypoints) {
+        tx.execute(
+            &stmt,
+            &[
+                &workout_id,
+                &idx,
+                &w.t,
+                &w.lat,
+                &w.lon,
+                &w.ele,
+                &w.name,
+                &w.desc,
+                &w.sym,
+            ],
+        )
+        .context("Inserting waypoint")?;
     }
+    Ok(())
+}
 
-    tx.commit().context("Committing points transaction")?;
+fn insert_routes(
+    tx: &mut postgres::Transaction<'_>,
+    workout_id: i64,
+    routes: &[GpxRoute],
+) -> Result<()> {
+    let point_stmt = tx
+        .prepare(
+            "INSERT INTO route_points (route_id, idx, lat, lon, ele, name, description, symbol)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .context("Preparing route point insert")?;
+
+    for (idx, r) in (0i32..).zip(routes) {
+        let route_id: i64 = tx
+            .query_one(
+                "INSERT INTO routes (workout_id, idx, name, description) VALUES ($1, $2, $3, $4) RETURNING id",
+                &[&workout_id, &idx, &r.name, &r.desc],
+            )
+            .context("Inserting route")?
+            .get(0);
+
+        for (pidx, p) in (0i32..).zip(&r.points) {
+            tx.execute(
+                &point_stmt,
+                &[
+                    &route_id, &pidx, &p.lat, &p.lon, &p.ele, &p.name, &p.desc, &p.sym,
+                ],
+            )
+            .context("Inserting route point")?;
+        }
+    }
     Ok(())
 }
@@ -1,6 +1,25 @@
+pub mod apple_health;
+pub mod attach_hr;
 pub mod cli;
+pub mod columnar;
 pub mod database;
+pub mod export;
+pub mod file_import;
+pub mod file_index;
+pub mod fit;
+pub mod fit_writer;
+pub mod geojson_writer;
 pub mod gpx;
+pub mod gpx_writer;
 pub mod ingest;
+pub mod merge;
+pub mod orphans;
+pub mod stored;
+pub mod strava;
+pub mod table_export;
+pub mod tcx;
+pub mod tcx_writer;
 pub mod types;
 pub mod utils;
+pub mod watch;
+pub mod workout_export;
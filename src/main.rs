@@ -9,20 +9,51 @@
 
 use anyhow::Result;
 use clap::Parser;
-use roudenn::{cli, ingest, utils};
+use roudenn::cli::{Cli, Command};
+use roudenn::export::Export;
+use roudenn::{
+    apple_health, attach_hr, file_import, ingest, merge, strava, utils, watch, workout_export,
+};
+use std::time::Duration;
 extern crate roudenn;
 
 fn main() -> Result<()> {
-    let cli = cli::Cli::parse();
+    let cli = Cli::parse();
     utils::init_logging(cli.verbose, cli.quiet);
 
-    let export_handle = utils::open_export(&cli.export)?;
-    tracing::info!(
-        export = %export_handle.dir().display(),
-        pg_url = %cli.pg_url,
-        "starting ingest"
-    );
+    match &cli.command {
+        Some(Command::Watch(args)) => watch::watch(
+            &args.dir,
+            &cli.pg_url,
+            Duration::from_secs(args.settle_secs),
+            args.state_file.as_deref(),
+            &cli.export_options(),
+            &cli.ingest_options(),
+        ),
+        Some(Command::Export(args)) => {
+            workout_export::run(&cli.pg_url, &cli.export_options(), args)
+        }
+        Some(Command::ImportFiles(args)) => file_import::run(&cli.pg_url, args),
+        Some(Command::ImportStrava(args)) => strava::run(&cli.pg_url, &cli.export_options(), args),
+        Some(Command::ImportAppleHealth(args)) => {
+            apple_health::run(&cli.pg_url, &cli.export_options(), args)
+        }
+        Some(Command::MergeDuplicates(args)) => merge::run(&cli.pg_url, args),
+        Some(Command::AttachHr(args)) => attach_hr::run(&cli.pg_url, &cli.export_options(), args),
+        None => run_ingest(&cli),
+    }
+}
+
+fn run_ingest(cli: &Cli) -> Result<()> {
+    for export in utils::resolve_exports(&cli.export, cli.all_exports)? {
+        let export = Export::open(&export, &cli.export_options())?;
+        tracing::info!(
+            export = %export.location().display(),
+            pg_url = %cli.pg_url,
+            "starting ingest"
+        );
 
-    ingest::ingest(export_handle.dir(), &cli.pg_url)?;
+        ingest::ingest(&export, &cli.pg_url, &cli.ingest_options())?;
+    }
     Ok(())
 }
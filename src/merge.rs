@@ -0,0 +1,489 @@
+use crate::cli::MergeArgs;
+use crate::ingest::{
+    connect_or_create_db, ensure_pg_schema, import_records, refresh_workout_distance_matview,
+    upsert_workout,
+};
+use crate::stored::{read_points, read_samples};
+use crate::types::{
+    GpxPoint, SOURCE_APPLE_HEALTH, SOURCE_GADGETBRIDGE, SOURCE_IMPORTED_FILE, SOURCE_MERGED,
+    SOURCE_ORPHAN_FILE, SOURCE_STRAVA, WorkoutRecords, WorkoutSample, WorkoutSummary,
+};
+use crate::utils::{degrees_to_e7, haversine_m, interpolate_hr};
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use postgres::Client;
+use serde_json::Value as JsonValue;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+/// Points of two tracks at most this far apart in time are compared.
+const MAX_MATCH_GAP: TimeDelta = TimeDelta::seconds(30);
+
+/// Fewer matched points than this and the tracks are not compared at all.
+const MIN_MATCHED_POINTS: usize = 10;
+
+/// Points of the first track compared, at most.
+const MAX_COMPARED_POINTS: usize = 500;
+
+/// Heart rate is interpolated between readings at most this far apart.
+const MAX_HR_GAP: TimeDelta = TimeDelta::seconds(30);
+
+/// A part of a merged workout taken whole from one of its duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeField {
+    /// Track points: positions, elevation, speed, cadence.
+    Gps,
+    /// Heart rate, laid onto the track points, and the samples.
+    Hr,
+    /// Name, activity and totals (`summary_data_json`).
+    Summary,
+}
+
+impl MergeField {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Gps => "gps",
+            Self::Hr => "hr",
+            Self::Summary => "summary",
+        }
+    }
+}
+
+/// `--prefer FIELD=SOURCE,...`: the sources to take a field from, most
+/// preferred first.
+#[derive(Debug, Clone)]
+pub struct FieldPrecedence {
+    pub field: MergeField,
+    pub sources: Vec<String>,
+}
+
+pub fn parse_precedence(s: &str) -> Result<FieldPrecedence, String> {
+    let (field, sources) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FIELD=SOURCE[,SOURCE...], got {s:?}"))?;
+    let field = match field.trim() {
+        "gps" => MergeField::Gps,
+        "hr" => MergeField::Hr,
+        "summary" => MergeField::Summary,
+        other => {
+            return Err(format!(
+                "unknown field {other:?}, expected gps, hr or summary"
+            ));
+        }
+    };
+    let sources: Vec<String> = sources
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if sources.is_empty() {
+        return Err(format!("no sources given for {}", field.as_str()));
+    }
+    Ok(FieldPrecedence { field, sources })
+}
+
+/// Default precedence: tracks from the phone (its GPS is usually better than
+/// the watch's), heart rate and totals from the watch.
+fn default_sources(field: MergeField) -> Vec<String> {
+    let sources: &[&str] = match field {
+        MergeField::Gps => &[
+            SOURCE_IMPORTED_FILE,
+            SOURCE_STRAVA,
+            SOURCE_APPLE_HEALTH,
+            SOURCE_ORPHAN_FILE,
+            SOURCE_GADGETBRIDGE,
+        ],
+        MergeField::Hr | MergeField::Summary => &[
+            SOURCE_GADGETBRIDGE,
+            SOURCE_ORPHAN_FILE,
+            SOURCE_APPLE_HEALTH,
+            SOURCE_STRAVA,
+            SOURCE_IMPORTED_FILE,
+        ],
+    };
+    sources.iter().map(|&s| s.to_owned()).collect()
+}
+
+/// A workout found to duplicate another, with its points and samples.
+struct Member {
+    id: i64,
+    uuid: String,
+    source: String,
+    activity: String,
+    activity_kind: i32,
+    name: Option<String>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    device_id: i32,
+    user_id: i32,
+    summary_data_json: Option<JsonValue>,
+    points: Vec<GpxPoint>,
+    samples: Vec<WorkoutSample>,
+}
+
+impl Member {
+    fn has(&self, field: MergeField) -> bool {
+        match field {
+            MergeField::Gps => !self.points.is_empty(),
+            MergeField::Hr => {
+                self.samples.iter().any(|s| s.hr.is_some())
+                    || self.points.iter().any(|p| p.hr.is_some())
+            }
+            MergeField::Summary => true,
+        }
+    }
+
+    /// Heart rate readings by time: the samples', or else the points'.
+    fn hr_series(&self) -> Vec<(DateTime<Utc>, i32)> {
+        let mut series: Vec<_> = self
+            .samples
+            .iter()
+            .filter_map(|s| Some((s.t, s.hr?)))
+            .collect();
+        if series.is_empty() {
+            series = self
+                .points
+                .iter()
+                .filter_map(|p| Some((p.t, p.hr?)))
+                .collect();
+        }
+        series.sort_by_key(|&(t, _)| t);
+        series
+    }
+}
+
+/// Find workouts recorded more than once (watch and phone, an import of the
+/// same run) and merge each set into one workout with `source = 'merged'`,
+/// taking every field from the duplicate its precedence prefers.
+///
+/// Duplicates come from different sources and overlap in time by at least
+/// `--min-overlap` of the shorter one; when both have tracks, their points at
+/// the same moments must be within `--max-track-distance` of each other.
+///
+/// The duplicates stay, linked to the merged workout in `workout_links`. Every
+/// run rebuilds the merged workouts from them, so it is rerun after imports;
+/// merged workouts whose duplicates no longer qualify are deleted.
+pub fn run(pg_url: &str, args: &MergeArgs) -> Result<()> {
+    let mut pg = connect_or_create_db(pg_url)?;
+    ensure_pg_schema(&mut pg)?;
+
+    let mut precedence: HashMap<&str, Vec<String>> = HashMap::new();
+    for field in [MergeField::Gps, MergeField::Hr, MergeField::Summary] {
+        precedence.insert(field.as_str(), default_sources(field));
+    }
+    for p in &args.prefer {
+        precedence.insert(p.field.as_str(), p.sources.clone());
+    }
+
+    let pairs = overlapping_pairs(&mut pg, args.min_overlap)?;
+    tracing::info!(pairs = pairs.len(), "found overlapping workouts");
+
+    let mut members: HashMap<i64, Member> = HashMap::new();
+    let mut groups = UnionFind::default();
+    let mut pairs_rejected = 0usize;
+    for (a, b) in pairs {
+        for id in [a, b] {
+            if let Entry::Vacant(e) = members.entry(id) {
+                e.insert(read_member(&mut pg, id)?);
+            }
+        }
+        let distance = track_distance_m(&members[&a].points, &members[&b].points);
+        if let Some(d) = distance.filter(|&d| d > args.max_track_distance) {
+            tracing::debug!(
+                a,
+                b,
+                distance_m = d,
+                "overlapping workouts took different routes"
+            );
+            pairs_rejected += 1;
+            continue;
+        }
+        groups.union(a, b);
+    }
+
+    let mut merged_ids = Vec::new();
+    let mut groups_skipped = 0usize;
+    for ids in groups.sets() {
+        let group: Vec<&Member> = ids.iter().map(|id| &members[id]).collect();
+        let mut sources: Vec<&str> = group.iter().map(|m| m.source.as_str()).collect();
+        sources.sort_unstable();
+        if sources.windows(2).any(|w| w[0] == w[1]) {
+            tracing::warn!(workouts = ?ids, "skipping duplicates with several workouts of one source");
+            groups_skipped += 1;
+            continue;
+        }
+
+        let pick = |field: MergeField| pick_member(&group, field, &precedence[field.as_str()]);
+        let (gps, hr, summary) = (
+            pick(MergeField::Gps),
+            pick(MergeField::Hr),
+            pick(MergeField::Summary),
+        );
+        tracing::info!(
+            workouts = ?ids,
+            start = %group[0].start,
+            gps = gps.map(|m| m.source.as_str()),
+            hr = hr.map(|m| m.source.as_str()),
+            summary = summary.map(|m| m.source.as_str()),
+            "found duplicate workouts"
+        );
+        if args.dry_run {
+            continue;
+        }
+
+        let Some(summary) = summary else {
+            continue;
+        };
+        let merged_id = merge_group(&mut pg, &group, gps, hr, summary)?;
+        merged_ids.push(merged_id);
+    }
+
+    if args.dry_run {
+        tracing::info!(pairs_rejected, groups_skipped, "dry run, nothing merged");
+        return Ok(());
+    }
+
+    let stale = pg
+        .execute(
+            "DELETE FROM workouts WHERE source = $1 AND id <> ALL($2)",
+            &[&SOURCE_MERGED, &merged_ids],
+        )
+        .context("Deleting outdated merged workouts")?;
+
+    refresh_workout_distance_matview(&mut pg)?;
+    tracing::info!(
+        workouts_merged = merged_ids.len(),
+        pairs_rejected,
+        groups_skipped,
+        stale_merged_deleted = stale,
+        "merge done"
+    );
+    Ok(())
+}
+
+/// Pairs of workouts from different sources whose time ranges overlap by at
+/// least `min_overlap` of the shorter one. Merged workouts are left out.
+fn overlapping_pairs(pg: &mut Client, min_overlap: f64) -> Result<Vec<(i64, i64)>> {
+    let rows = pg
+        .query(
+            "SELECT a.id, b.id
+             FROM workouts a
+             JOIN workouts b
+               ON a.id < b.id
+              AND a.source <> b.source
+              AND a.start_time < b.end_time
+              AND b.start_time < a.end_time
+             WHERE a.source <> $1 AND b.source <> $1
+               AND least(a.end_time, b.end_time) - greatest(a.start_time, b.start_time)
+                   >= $2 * least(a.end_time - a.start_time, b.end_time - b.start_time)
+             ORDER BY a.id, b.id",
+            &[&SOURCE_MERGED, &min_overlap],
+        )
+        .context("Finding overlapping workouts")?;
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
+fn read_member(pg: &mut Client, id: i64) -> Result<Member> {
+    let row = pg
+        .query_one(
+            "SELECT uuid::text, source, activity, activity_kind, name, start_time, end_time,
+                    device_id, user_id, summary_data_json
+             FROM workouts
+             WHERE id = $1",
+            &[&id],
+        )
+        .with_context(|| format!("Reading workout {id}"))?;
+    Ok(Member {
+        id,
+        uuid: row.get(0),
+        source: row.get(1),
+        activity: row.get(2),
+        activity_kind: row.get(3),
+        name: row.get(4),
+        start: row.get(5),
+        end: row.get(6),
+        device_id: row.get(7),
+        user_id: row.get(8),
+        summary_data_json: row.get(9),
+        points: read_points(pg, id)?,
+        samples: read_samples(pg, id)?,
+    })
+}
+
+/// The median distance between points of `a` and the points of `b` recorded
+/// closest to them in time, or `None` if the tracks share too few moments to
+/// compare (no GPS on one side, a phone started late).
+fn track_distance_m(a: &[GpxPoint], b: &[GpxPoint]) -> Option<f64> {
+    let mut b: Vec<&GpxPoint> = b.iter().collect();
+    b.sort_by_key(|p| p.t);
+
+    let step = (a.len() / MAX_COMPARED_POINTS).max(1);
+    let mut distances: Vec<f64> = a
+        .iter()
+        .step_by(step)
+        .filter_map(|p| {
+            let i = b.partition_point(|q| q.t < p.t);
+            let nearest = [i.checked_sub(1), Some(i)]
+                .into_iter()
+                .flatten()
+                .filter_map(|i| b.get(i))
+                .min_by_key(|q| (q.t - p.t).abs())?;
+            ((nearest.t - p.t).abs() <= MAX_MATCH_GAP)
+                .then(|| haversine_m(p.lat, p.lon, nearest.lat, nearest.lon))
+        })
+        .collect();
+    if distances.len() < MIN_MATCHED_POINTS {
+        return None;
+    }
+    distances.sort_by(f64::total_cmp);
+    Some(distances[distances.len() / 2])
+}
+
+/// The member of `group` to take `field` from: the first having it whose source
+/// is listed in `sources`, else the oldest having it.
+fn pick_member<'a>(
+    group: &[&'a Member],
+    field: MergeField,
+    sources: &[String],
+) -> Option<&'a Member> {
+    let candidates = || group.iter().copied().filter(|m| m.has(field));
+    sources
+        .iter()
+        .find_map(|s| candidates().find(|m| m.source == *s))
+        .or_else(|| candidates().min_by_key(|m| m.id))
+}
+
+/// Upsert the merged workout of `group`, replace its points and samples, and
+/// link it to its members, all in one transaction. Returns its id.
+fn merge_group(
+    pg: &mut Client,
+    group: &[&Member],
+    gps: Option<&Member>,
+    hr: Option<&Member>,
+    summary: &Member,
+) -> Result<i64> {
+    // Keyed on the oldest duplicate, so reruns update the same workout.
+    let anchor = group
+        .iter()
+        .min_by_key(|m| m.id)
+        .copied()
+        .unwrap_or(summary);
+    let start = group.iter().map(|m| m.start).min().unwrap_or(summary.start);
+    let end = group.iter().map(|m| m.end).max().unwrap_or(summary.end);
+    let device = group
+        .iter()
+        .find(|m| m.device_id != 0)
+        .copied()
+        .unwrap_or(summary);
+
+    let mut points = gps.map(|m| m.points.clone()).unwrap_or_default();
+    if let (Some(gps), Some(hr)) = (gps, hr)
+        && gps.id != hr.id
+    {
+        let series = hr.hr_series();
+        for p in &mut points {
+            p.hr = interpolate_hr(&series, p.t, MAX_HR_GAP).or(p.hr);
+        }
+    }
+    let samples = hr
+        .filter(|m| !m.samples.is_empty())
+        .or(gps)
+        .map(|m| m.samples.clone())
+        .unwrap_or_default();
+
+    let base = points.first();
+    let merged = WorkoutSummary {
+        name: summary.name.clone(),
+        start,
+        end,
+        activity_kind: summary.activity_kind,
+
+        base_longitude_e7: base.map(|p| degrees_to_e7(p.lon)),
+        base_latitude_e7: base.map(|p| degrees_to_e7(p.lat)),
+        base_altitude: None,
+
+        gpx_track_android: None,
+        raw_details_android: None,
+
+        device_id: device.device_id,
+        user_id: device.user_id,
+
+        summary_data_raw: summary.summary_data_json.as_ref().map(ToString::to_string),
+        summary_data_json: summary.summary_data_json.clone(),
+        raw_summary_data: None,
+
+        raw_details: None,
+
+        source: SOURCE_MERGED,
+        external_id: Some(anchor.uuid.clone()),
+    };
+    // A failure must not leave a merged workout without its points or links.
+    let mut tx = pg.transaction().context("Starting transaction for merge")?;
+    let merged_id = upsert_workout(&mut tx, &merged, &summary.activity)?;
+    import_records(
+        &mut tx,
+        merged_id,
+        &WorkoutRecords { points, samples },
+        true,
+    )?;
+
+    let member_ids: Vec<i64> = group.iter().map(|m| m.id).collect();
+    tx.execute(
+        "DELETE FROM workout_links WHERE merged_workout_id = $1 OR source_workout_id = ANY($2)",
+        &[&merged_id, &member_ids],
+    )
+    .context("Deleting existing links")?;
+    for m in group {
+        let fields: Vec<&str> = [(MergeField::Gps, gps), (MergeField::Hr, hr)]
+            .into_iter()
+            .chain([(MergeField::Summary, Some(summary))])
+            .filter(|(_, from)| from.is_some_and(|f| f.id == m.id))
+            .map(|(field, _)| field.as_str())
+            .collect();
+        tx.execute(
+            "INSERT INTO workout_links (merged_workout_id, source_workout_id, fields)
+             VALUES ($1, $2, $3)",
+            &[&merged_id, &m.id, &fields],
+        )
+        .context("Inserting link")?;
+    }
+    tx.commit().context("Committing merge transaction")?;
+    Ok(merged_id)
+}
+
+/// Disjoint sets of workout ids.
+#[derive(Default)]
+struct UnionFind {
+    parent: BTreeMap<i64, i64>,
+}
+
+impl UnionFind {
+    fn find(&mut self, id: i64) -> i64 {
+        let parent = *self.parent.entry(id).or_insert(id);
+        if parent == id {
+            return id;
+        }
+        let root = self.find(parent);
+        self.parent.insert(id, root);
+        root
+    }
+
+    fn union(&mut self, a: i64, b: i64) {
+        let (ra, rb) = (self.find(a), self.find(b));
+        if ra != rb {
+            self.parent.insert(ra.max(rb), ra.min(rb));
+        }
+    }
+
+    /// The sets, each sorted, ordered by their smallest id.
+    fn sets(mut self) -> Vec<Vec<i64>> {
+        let ids: Vec<i64> = self.parent.keys().copied().collect();
+        let mut sets: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+        for id in ids {
+            let root = self.find(id);
+            sets.entry(root).or_default().push(id);
+        }
+        sets.into_values().collect()
+    }
+}
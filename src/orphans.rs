@@ -0,0 +1,206 @@
+use crate::export::Export;
+use crate::file_import::activity_kind;
+use crate::file_index::{FileIndex, Resolution};
+use crate::fit::parse_fit;
+use crate::gpx::{TrackScan, scan_track};
+use crate::types::{SOURCE_ORPHAN_FILE, WorkoutRecords, WorkoutSummary};
+use crate::utils::{degrees_to_e7, has_extension};
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::path::Path;
+
+/// Activity given to orphan GPX tracks. roudenn imports runs from the DB, and a
+/// GPS track that lost its DB row (phone migration) is an outdoor run. Orphan
+/// FIT files tell their own sport.
+const ORPHAN_GPX_ACTIVITY: &str = "outdoor_running";
+
+/// A workout rebuilt from a GPX or FIT file no `BASE_ACTIVITY_SUMMARY` row
+/// references.
+pub struct OrphanWorkout {
+    pub summary: WorkoutSummary,
+    /// `workouts.activity`.
+    pub activity: &'static str,
+    pub track: OrphanTrack,
+}
+
+pub enum OrphanTrack {
+    /// Export-relative path of the track, to import its points from.
+    Gpx(String),
+    /// The decoded records of a FIT activity.
+    Fit(WorkoutRecords),
+}
+
+pub struct Orphans {
+    pub workouts: Vec<OrphanWorkout>,
+    /// Unreferenced raw-details files without a matching orphan GPX track.
+    /// Their binary format carries no timestamps we can read, so they are skipped.
+    pub unmatched_raw_details: Vec<String>,
+}
+
+/// Export files referenced by any DB row, whatever its activity.
+///
+/// Ambiguous matches count as referenced too: one of them belongs to a row.
+pub fn referenced_files(files: &FileIndex, summaries: &[WorkoutSummary]) -> HashSet<String> {
+    let mut out = HashSet::new();
+    let paths = summaries
+        .iter()
+        .flat_map(|s| [&s.gpx_track_android, &s.raw_details_android])
+        .filter_map(|p| p.as_deref());
+
+    for android_path in paths {
+        match files.resolve(android_path) {
+            Resolution::Found(rel) => {
+                out.insert(rel);
+            }
+            Resolution::Ambiguous(candidates) => out.extend(candidates),
+            Resolution::Missing => {}
+        }
+    }
+    out
+}
+
+/// Build synthetic workouts from unreferenced GPX files, timed by their first
+/// and last track points. An unreferenced raw-details file is attached to the
+/// orphan track with the same file stem, if any.
+///
+/// Unreferenced FIT activities (Garmin watches, older devices) become workouts
+/// too, with the summary, sport and records of the file itself.
+pub fn find_orphans(
+    export: &Export,
+    files: &FileIndex,
+    referenced: &HashSet<String>,
+) -> Result<Orphans> {
+    let mut gpx_files = Vec::new();
+    let mut fit_files = Vec::new();
+    let mut raw_by_stem: HashMap<String, String> = HashMap::new();
+
+    for rel in files.files().filter(|f| !referenced.contains(*f)) {
+        if has_extension(Path::new(rel), "gpx") {
+            gpx_files.push(rel.to_owned());
+        } else if has_extension(Path::new(rel), "fit") {
+            fit_files.push(rel.to_owned());
+        } else if rel.split('/').any(|c| c.eq_ignore_ascii_case("rawDetails")) {
+            raw_by_stem.insert(file_stem(rel), rel.to_owned());
+        }
+    }
+    gpx_files.sort();
+    fit_files.sort();
+
+    let mut workouts = Vec::new();
+    for rel in gpx_files {
+        let scan = match export.with_file(&rel, |r| scan_track(BufReader::new(r))) {
+            Ok(scan) => scan,
+            Err(e) => {
+                tracing::warn!(path = %rel, err = format!("{e:#}"), "skipping unreadable orphan gpx");
+                continue;
+            }
+        };
+
+        let Some(TrackScan {
+            start,
+            end,
+            first_lat,
+            first_lon,
+            points,
+            ..
+        }) = scan
+        else {
+            tracing::warn!(path = %rel, "skipping orphan gpx without timestamped points");
+            continue;
+        };
+
+        let mut raw_details_rel = raw_by_stem.remove(&file_stem(&rel));
+        let raw_details = match raw_details_rel.as_deref().map(|raw| export.read_file(raw)) {
+            Some(Ok(bytes)) => Some(bytes),
+            Some(Err(e)) => {
+                tracing::warn!(
+                    path = raw_details_rel.as_deref(),
+                    err = format!("{e:#}"),
+                    "reading orphan raw details failed"
+                );
+                raw_details_rel = None;
+                None
+            }
+            None => None,
+        };
+
+        tracing::info!(path = %rel, %start, %end, points, "found orphan gpx track");
+
+        let summary = WorkoutSummary {
+            name: Path::new(&rel)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .map(str::to_owned),
+            activity_kind: activity_kind(ORPHAN_GPX_ACTIVITY),
+            base_longitude_e7: Some(degrees_to_e7(first_lon)),
+            base_latitude_e7: Some(degrees_to_e7(first_lat)),
+            gpx_track_android: Some(rel.clone()),
+            raw_details_android: raw_details_rel,
+            raw_details,
+            ..WorkoutSummary::standalone(SOURCE_ORPHAN_FILE, start, end)
+        };
+        workouts.push(OrphanWorkout {
+            summary,
+            activity: ORPHAN_GPX_ACTIVITY,
+            track: OrphanTrack::Gpx(rel),
+        });
+    }
+
+    for rel in fit_files {
+        let bytes = match export.read_file(&rel) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::warn!(path = %rel, err = format!("{e:#}"), "skipping unreadable orphan fit");
+                continue;
+            }
+        };
+        let activity = match parse_fit(&bytes, SOURCE_ORPHAN_FILE) {
+            Ok(Some(activity)) => activity,
+            Ok(None) => {
+                tracing::debug!(path = %rel, "skipping fit file that is not an activity");
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!(path = %rel, err = format!("{e:#}"), "skipping unreadable orphan fit");
+                continue;
+            }
+        };
+
+        let mut summary = activity.summary;
+        tracing::info!(
+            path = %rel,
+            start = %summary.start,
+            end = %summary.end,
+            records = activity.records.samples.len(),
+            "found orphan fit activity"
+        );
+        summary.name = Path::new(&rel)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .map(str::to_owned);
+        summary.raw_details_android = Some(rel);
+        summary.raw_details = Some(bytes);
+        workouts.push(OrphanWorkout {
+            summary,
+            activity: activity.activity,
+            track: OrphanTrack::Fit(activity.records),
+        });
+    }
+
+    let mut unmatched_raw_details: Vec<String> = raw_by_stem.into_values().collect();
+    unmatched_raw_details.sort();
+
+    Ok(Orphans {
+        workouts,
+        unmatched_raw_details,
+    })
+}
+
+fn file_stem(rel: &str) -> String {
+    Path::new(rel)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(rel)
+        .to_ascii_lowercase()
+}
@@ -0,0 +1,236 @@
+use crate::types::{GpxPoint, GpxWaypoint, WorkoutSample};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use postgres::Client;
+use postgres::types::ToSql;
+use serde_json::Value as JsonValue;
+
+/// A row of `workouts`, as needed to write it back out.
+#[derive(Debug, Clone)]
+pub struct StoredWorkout {
+    pub id: i64,
+    pub uuid: String,
+    pub name: Option<String>,
+    pub activity: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub duration_s: i32,
+    pub summary_data_json: Option<JsonValue>,
+    pub source: String,
+}
+
+impl StoredWorkout {
+    /// A numeric entry of Gadgetbridge's summary JSON, e.g. `caloriesBurnt`,
+    /// stored as `{"value": 300, "unit": "calories_unit"}`.
+    pub fn summary_value(&self, key: &str) -> Option<f64> {
+        self.summary_data_json
+            .as_ref()?
+            .get(key)?
+            .get("value")?
+            .as_f64()
+    }
+}
+
+/// A workout given on the command line, by `workouts.id` or `workouts.uuid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkoutRef {
+    Id(i64),
+    Uuid(String),
+}
+
+impl std::str::FromStr for WorkoutRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<i64>() {
+            return Ok(Self::Id(id));
+        }
+        let is_uuid = s.len() == 36
+            && s.char_indices().all(|(i, c)| {
+                if matches!(i, 8 | 13 | 18 | 23) {
+                    c == '-'
+                } else {
+                    c.is_ascii_hexdigit()
+                }
+            });
+        if is_uuid {
+            Ok(Self::Uuid(s.to_ascii_lowercase()))
+        } else {
+            Err(format!("expected a workout id or uuid, got {s:?}"))
+        }
+    }
+}
+
+impl std::fmt::Display for WorkoutRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Uuid(uuid) => f.write_str(uuid),
+        }
+    }
+}
+
+/// Which workouts to read. Empty means all of them, except those merged into
+/// another workout (all of `workouts_effective`); a single workout is read
+/// even if it was.
+#[derive(Debug, Clone, Default)]
+pub struct WorkoutFilter {
+    pub workout: Option<WorkoutRef>,
+    /// Workouts starting at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Workouts starting before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Workouts of any of these activities.
+    pub activities: Vec<String>,
+}
+
+/// Workouts matching `filter`, oldest first.
+pub fn select_workouts(pg: &mut Client, filter: &WorkoutFilter) -> Result<Vec<StoredWorkout>> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql + Sync>> = Vec::new();
+
+    match &filter.workout {
+        Some(WorkoutRef::Id(id)) => {
+            params.push(Box::new(*id));
+            conditions.push(format!("id = ${}", params.len()));
+        }
+        Some(WorkoutRef::Uuid(uuid)) => {
+            params.push(Box::new(uuid.clone()));
+            conditions.push(format!("uuid = ${}::text::uuid", params.len()));
+        }
+        None => {}
+    }
+    if let Some(since) = filter.since {
+        params.push(Box::new(since));
+        conditions.push(format!("start_time >= ${}", params.len()));
+    }
+    if let Some(until) = filter.until {
+        params.push(Box::new(until));
+        conditions.push(format!("start_time < ${}", params.len()));
+    }
+    if !filter.activities.is_empty() {
+        params.push(Box::new(filter.activities.clone()));
+        conditions.push(format!("activity = ANY(${})", params.len()));
+    }
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let table = if filter.workout.is_some() {
+        "workouts"
+    } else {
+        "workouts_effective"
+    };
+    let sql = format!(
+        "SELECT id, uuid::text, name, activity, start_time, end_time, duration_s,
+                summary_data_json, source
+         FROM {table}
+         {where_clause}
+         ORDER BY start_time, id"
+    );
+
+    let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(AsRef::as_ref).collect();
+    let rows = pg.query(&sql, &params).context("Selecting workouts")?;
+
+    Ok(rows
+        .iter()
+        .map(|r| StoredWorkout {
+            id: r.get(0),
+            uuid: r.get(1),
+            name: r.get(2),
+            activity: r.get(3),
+            start: r.get(4),
+            end: r.get(5),
+            duration_s: r.get(6),
+            summary_data_json: r.get(7),
+            source: r.get(8),
+        })
+        .collect())
+}
+
+/// Track points of a workout, in order.
+pub fn read_points(pg: &mut Client, workout_id: i64) -> Result<Vec<GpxPoint>> {
+    let rows = pg
+        .query(
+            "SELECT idx, trk, seg, t, lat, lon, ele, hr, cad, atemp, speed, course
+             FROM workout_points
+             WHERE workout_id = $1
+             ORDER BY idx",
+            &[&workout_id],
+        )
+        .with_context(|| format!("Reading points of workout {workout_id}"))?;
+
+    Ok(rows
+        .iter()
+        .map(|r| GpxPoint {
+            idx: r.get(0),
+            trk: r.get(1),
+            seg: r.get(2),
+            t: r.get(3),
+            lat: r.get(4),
+            lon: r.get(5),
+            ele: r.get(6),
+            hr: r.get(7),
+            cad: r.get(8),
+            atemp: r.get(9),
+            speed: r.get(10),
+            course: r.get(11),
+        })
+        .collect())
+}
+
+/// Samples of a workout, in order.
+pub fn read_samples(pg: &mut Client, workout_id: i64) -> Result<Vec<WorkoutSample>> {
+    let rows = pg
+        .query(
+            "SELECT idx, t, hr, cad, speed, distance_m, ele, power, atemp
+             FROM workout_samples
+             WHERE workout_id = $1
+             ORDER BY idx",
+            &[&workout_id],
+        )
+        .with_context(|| format!("Reading samples of workout {workout_id}"))?;
+
+    Ok(rows
+        .iter()
+        .map(|r| WorkoutSample {
+            idx: r.get(0),
+            t: r.get(1),
+            hr: r.get(2),
+            cad: r.get(3),
+            speed: r.get(4),
+            distance_m: r.get(5),
+            ele: r.get(6),
+            power: r.get(7),
+            atemp: r.get(8),
+        })
+        .collect())
+}
+
+/// Waypoints of a workout, in order.
+pub fn read_waypoints(pg: &mut Client, workout_id: i64) -> Result<Vec<GpxWaypoint>> {
+    let rows = pg
+        .query(
+            "SELECT lat, lon, ele, t, name, description, symbol
+             FROM workout_waypoints
+             WHERE workout_id = $1
+             ORDER BY idx",
+            &[&workout_id],
+        )
+        .with_context(|| format!("Reading waypoints of workout {workout_id}"))?;
+
+    Ok(rows
+        .iter()
+        .map(|r| GpxWaypoint {
+            lat: r.get(0),
+            lon: r.get(1),
+            ele: r.get(2),
+            t: r.get(3),
+            name: r.get(4),
+            desc: r.get(5),
+            sym: r.get(6),
+        })
+        .collect())
+}
@@ -0,0 +1,302 @@
+use crate::cli::ImportStravaArgs;
+use crate::export::{Export, ExportOptions, Layout};
+use crate::file_import::{activity_from_name, activity_kind};
+use crate::fit::parse_fit;
+use crate::ingest::{
+    connect_or_create_db, ensure_pg_schema, import_gpx, import_records,
+    refresh_workout_distance_matview, upsert_workout,
+};
+use crate::tcx::parse_tcx;
+use crate::types::{SOURCE_STRAVA, WorkoutRecords, WorkoutSummary};
+use crate::utils::{degrees_to_e7, summary_json};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use flate2::read::MultiGzDecoder;
+use std::io::Read;
+
+const ACTIVITIES_CSV: &str = "activities.csv";
+
+/// Activity files larger than this once decompressed are refused.
+const MAX_TRACK_BYTES: u64 = 512 * 1024 * 1024;
+
+/// One row of `activities.csv`.
+#[derive(Debug)]
+struct StravaActivity {
+    id: String,
+    start: DateTime<Utc>,
+    name: Option<String>,
+    activity_type: String,
+    elapsed_s: Option<f64>,
+    moving_s: Option<f64>,
+    distance_m: Option<f64>,
+    max_hr: Option<f64>,
+    avg_hr: Option<f64>,
+    calories: Option<f64>,
+    /// Archive-relative path of the recorded file, if any (manual entries
+    /// have none).
+    filename: Option<String>,
+}
+
+/// Import a Strava account archive ("Download your data": `activities.csv`
+/// and `activities/*.gpx|.fit.gz|.tcx.gz`), ZIP or extracted, offline.
+///
+/// Workouts are stored with `source = 'strava'` and the Strava activity id as
+/// `external_id`, so importing a newer archive updates them in place.
+pub fn run(pg_url: &str, export_opts: &ExportOptions, args: &ImportStravaArgs) -> Result<()> {
+    let archive = Export::open_as(&args.archive, export_opts, Layout::Strava)?;
+    let activities = archive
+        .with_file(ACTIVITIES_CSV, |r| read_activities(r))
+        .with_context(|| format!("reading {ACTIVITIES_CSV}"))?;
+    tracing::info!(activities = activities.len(), "found strava activities");
+
+    let mut pg = connect_or_create_db(pg_url)?;
+    ensure_pg_schema(&mut pg)?;
+
+    let mut workouts_upserted = 0usize;
+    let mut workouts_with_points = 0usize;
+    let mut points_imported = 0usize;
+    let mut samples_imported = 0usize;
+    let mut files_missing = 0usize;
+    let mut files_failed = 0usize;
+
+    for a in &activities {
+        let activity = activity_from_name(&a.activity_type);
+        let track = match a.filename.as_deref() {
+            Some(rel) if !archive.contains(rel) => {
+                tracing::warn!(id = %a.id, path = %rel, "activity file is missing from the archive");
+                files_missing += 1;
+                None
+            }
+            Some(rel) => match read_track(&archive, rel) {
+                Ok(track) => Some((rel, track)),
+                Err(e) => {
+                    tracing::warn!(id = %a.id, path = %rel, err = format!("{e:#}"), "reading activity file failed");
+                    files_failed += 1;
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let summary = workout_summary(a, &activity, track.as_ref().map(|(rel, t)| (*rel, t)));
+        let workout_id = upsert_workout(&mut pg, &summary, &activity)?;
+        workouts_upserted += 1;
+
+        let points = match &track {
+            Some((rel, Track::Gpx(bytes))) => {
+                let bounds = Some((summary.start, summary.end));
+                import_gpx(&mut pg, &mut bytes.as_slice(), rel, workout_id, bounds)
+                    .with_context(|| format!("Importing GPX: {rel}"))?
+                    .points
+            }
+            Some((_, Track::Records(records))) => {
+                let imported = import_records(&mut pg, workout_id, records, true)?;
+                samples_imported += imported.samples;
+                imported.points
+            }
+            None => 0,
+        };
+        if points > 0 {
+            workouts_with_points += 1;
+        }
+        points_imported += points;
+    }
+
+    refresh_workout_distance_matview(&mut pg)?;
+    tracing::info!(
+        workouts_upserted,
+        workouts_with_points,
+        points_imported,
+        samples_imported,
+        files_missing,
+        files_failed,
+        "strava import done"
+    );
+    Ok(())
+}
+
+/// Columns are looked up by name: Strava adds some now and then, and repeats
+/// a few (`Distance`, `Elapsed Time`, ...) in the metric units we want last.
+fn read_activities(r: &mut dyn Read) -> Result<Vec<StravaActivity>> {
+    let mut csv = csv::ReaderBuilder::new().flexible(true).from_reader(r);
+    let headers = csv.headers()?.clone();
+    let first = |name: &str| headers.iter().position(|h| h == name);
+    let last = |name: &str| {
+        headers
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| *h == name)
+            .map(|(i, _)| i)
+            .last()
+    };
+
+    let (Some(id_col), Some(date_col), Some(type_col)) = (
+        first("Activity ID"),
+        first("Activity Date"),
+        first("Activity Type"),
+    ) else {
+        bail!("missing Activity ID, Activity Date or Activity Type column");
+    };
+    let name_col = first("Activity Name");
+    let file_col = first("Filename");
+    let elapsed_col = last("Elapsed Time");
+    let moving_col = last("Moving Time");
+    let distance_col = last("Distance");
+    let max_hr_col = last("Max Heart Rate");
+    let avg_hr_col = last("Average Heart Rate");
+    let calories_col = last("Calories");
+
+    let mut out = Vec::new();
+    for (line, record) in (2..).zip(csv.records()) {
+        let record = record?;
+        let text = |col: Option<usize>| {
+            col.and_then(|c| record.get(c))
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+        };
+        let number = |col| text(col).and_then(|s| s.replace(',', "").parse::<f64>().ok());
+
+        let (Some(id), Some(date)) = (text(Some(id_col)), text(Some(date_col))) else {
+            tracing::warn!(line, "skipping strava activity without id or date");
+            continue;
+        };
+        let Some(start) = parse_strava_date(date) else {
+            tracing::warn!(
+                line,
+                id,
+                date,
+                "skipping strava activity with an unreadable date"
+            );
+            continue;
+        };
+
+        out.push(StravaActivity {
+            id: id.to_owned(),
+            start,
+            name: text(name_col).map(str::to_owned),
+            activity_type: text(Some(type_col)).unwrap_or_default().to_owned(),
+            elapsed_s: number(elapsed_col),
+            moving_s: number(moving_col),
+            distance_m: number(distance_col),
+            max_hr: number(max_hr_col),
+            avg_hr: number(avg_hr_col),
+            calories: number(calories_col),
+            filename: text(file_col).map(str::to_owned),
+        });
+    }
+    Ok(out)
+}
+
+/// `Activity Date` is UTC, written `Mar 14, 2019, 5:06:47 PM` (older archives:
+/// `2019-03-14 17:06:47`).
+fn parse_strava_date(s: &str) -> Option<DateTime<Utc>> {
+    ["%b %d, %Y, %I:%M:%S %p", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .map(|dt| dt.and_utc())
+}
+
+/// The recorded file of an activity, decompressed.
+enum Track {
+    /// Imported straight from the bytes, points streamed.
+    Gpx(Vec<u8>),
+    /// Decoded TCX or FIT records.
+    Records(WorkoutRecords),
+}
+
+fn read_track(archive: &Export, rel: &str) -> Result<Track> {
+    let (name, gzipped) = match rel.strip_suffix(".gz") {
+        Some(name) => (name, true),
+        None => (rel, false),
+    };
+    let bytes = archive.with_file(rel, |r| {
+        let mut buf = Vec::new();
+        if gzipped {
+            MultiGzDecoder::new(r)
+                .take(MAX_TRACK_BYTES + 1)
+                .read_to_end(&mut buf)?;
+        } else {
+            r.take(MAX_TRACK_BYTES + 1).read_to_end(&mut buf)?;
+        }
+        if buf.len() as u64 > MAX_TRACK_BYTES {
+            bail!("larger than {} MiB", MAX_TRACK_BYTES / (1024 * 1024));
+        }
+        Ok(buf)
+    })?;
+
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let records = match extension.as_deref() {
+        Some("gpx") => return Ok(Track::Gpx(bytes)),
+        // Strava's TCX files start with spaces before the XML declaration.
+        Some("tcx") => parse_tcx(bytes.trim_ascii_start())?.records,
+        Some("fit") => match parse_fit(&bytes, SOURCE_STRAVA)? {
+            Some(fit) => fit.records,
+            None => bail!("FIT file is not an activity"),
+        },
+        _ => bail!("not a GPX, TCX or FIT file"),
+    };
+    Ok(Track::Records(records))
+}
+
+fn workout_summary(
+    a: &StravaActivity,
+    activity: &str,
+    track: Option<(&str, &Track)>,
+) -> WorkoutSummary {
+    let end = a.elapsed_s.map_or(a.start, |s| {
+        a.start + TimeDelta::milliseconds((s * 1000.0) as i64)
+    });
+    let base = match track {
+        Some((_, Track::Records(records))) => records.points.first().map(|p| (p.lat, p.lon)),
+        _ => None,
+    };
+    let summary_data_json = summary_json(&[
+        ("distanceMeters", a.distance_m, "meters"),
+        ("activeSeconds", a.moving_s, "seconds"),
+        ("caloriesBurnt", a.calories, "calories_unit"),
+        ("averageHR", a.avg_hr, "bpm"),
+        ("maxHR", a.max_hr, "bpm"),
+    ]);
+
+    WorkoutSummary {
+        name: a.name.clone(),
+        activity_kind: activity_kind(activity),
+
+        base_longitude_e7: base.map(|(_, lon)| degrees_to_e7(lon)),
+        base_latitude_e7: base.map(|(lat, _)| degrees_to_e7(lat)),
+
+        gpx_track_android: match track {
+            Some((rel, Track::Gpx(_))) => Some(rel.to_owned()),
+            _ => None,
+        },
+        raw_details_android: match track {
+            Some((rel, Track::Records(..))) => Some(rel.to_owned()),
+            _ => None,
+        },
+
+        summary_data_raw: summary_data_json.as_ref().map(ToString::to_string),
+        summary_data_json,
+
+        external_id: Some(a.id.clone()),
+        ..WorkoutSummary::standalone(SOURCE_STRAVA, a.start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parses_both_activity_date_formats() {
+        let expected = Utc.with_ymd_and_hms(2019, 3, 14, 17, 6, 47).unwrap();
+        assert_eq!(
+            parse_strava_date("Mar 14, 2019, 5:06:47 PM"),
+            Some(expected)
+        );
+        assert_eq!(parse_strava_date("2019-03-14 17:06:47"), Some(expected));
+        assert_eq!(parse_strava_date("14/03/2019"), None);
+    }
+}
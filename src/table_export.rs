@@ -0,0 +1,369 @@
+use crate::cli::TablesExportArgs;
+use crate::columnar::{Column, ColumnKind, PartitionedTable, TableFormat, Value};
+use crate::database::{
+    MAX_SECONDS_TIMESTAMP, open_database, read_base_activity_summary, sample_tables,
+};
+use crate::export::{Export, ExportOptions};
+use crate::file_index::FileIndex;
+use crate::gpx::{GpxReader, log_time_repairs};
+use crate::ingest::{FileReport, activity_label, check_pg_schema, resolve_file};
+use crate::utils::{duration_seconds_i32, e7_to_degrees, quote_ident};
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls};
+use rusqlite::types::ValueRef;
+use std::io::BufReader;
+use std::path::Path;
+
+pub fn run(pg_url: &str, export_opts: &ExportOptions, args: &TablesExportArgs) -> Result<()> {
+    match &args.from_export {
+        Some(path) => {
+            let export = Export::open(path, export_opts)?;
+            export_from_gadgetbridge(&export, &args.output, args.format)
+        }
+        None => export_from_pg(pg_url, &args.output, args.format),
+    }
+}
+
+/// `workouts`, `workout_points` and every `*samples` table with a `t` column,
+/// with all their columns but binary ones. Workouts merged into another one
+/// (not in `workouts_effective`) are left out, and so are their rows.
+fn export_from_pg(pg_url: &str, out: &Path, format: TableFormat) -> Result<()> {
+    let mut pg = Client::connect(pg_url, NoTls).context("Connecting to PostgreSQL")?;
+    check_pg_schema(&mut pg)?;
+
+    let mut tables = vec![
+        ("workouts".to_owned(), "start_time"),
+        ("workout_points".to_owned(), "t"),
+    ];
+    for row in pg.query(
+        r"SELECT table_name::text FROM information_schema.columns
+          WHERE table_schema = 'public' AND table_name LIKE '%samples' AND column_name = 't'
+          ORDER BY table_name",
+        &[],
+    )? {
+        tables.push((row.get(0), "t"));
+    }
+
+    for (table, time_column) in &tables {
+        let rows = export_pg_table(&mut pg, out, format, table, time_column)
+            .with_context(|| format!("Exporting table {table}"))?;
+        tracing::info!(table = table.as_str(), rows, "table exported");
+    }
+    tracing::info!(tables = tables.len(), dir = %out.display(), "export done");
+    Ok(())
+}
+
+fn export_pg_table(
+    pg: &mut Client,
+    out: &Path,
+    format: TableFormat,
+    table: &str,
+    time_column: &str,
+) -> Result<usize> {
+    let mut columns = Vec::new();
+    let mut select = Vec::new();
+    let mut per_workout = false;
+    for row in pg.query(
+        r"SELECT column_name::text, data_type::text FROM information_schema.columns
+          WHERE table_schema = 'public' AND table_name = $1
+          ORDER BY ordinal_position",
+        &[&table],
+    )? {
+        let name: String = row.get(0);
+        let data_type: String = row.get(1);
+        per_workout |= name == "workout_id";
+        let ident = quote_ident(&name);
+        let (kind, expr) = match data_type.as_str() {
+            "bytea" => continue,
+            "smallint" | "integer" | "bigint" => (ColumnKind::Int, format!("{ident}::int8")),
+            "real" | "double precision" | "numeric" => {
+                (ColumnKind::Float, format!("{ident}::float8"))
+            }
+            "boolean" => (ColumnKind::Bool, ident),
+            "timestamp with time zone" => (ColumnKind::Timestamp, ident),
+            "timestamp without time zone" => {
+                (ColumnKind::Timestamp, format!("{ident} AT TIME ZONE 'UTC'"))
+            }
+            _ => (ColumnKind::Text, format!("{ident}::text")),
+        };
+        columns.push(Column::new(name, kind));
+        select.push(expr);
+    }
+
+    let kinds: Vec<ColumnKind> = columns.iter().map(|c| c.kind).collect();
+    let mut writer = PartitionedTable::create(out, table, format, columns, time_column)?;
+
+    let from = if table == "workouts" {
+        "workouts_effective".to_owned()
+    } else if per_workout {
+        format!(
+            "{} WHERE workout_id IN (SELECT id FROM workouts_effective)",
+            quote_ident(table)
+        )
+    } else {
+        quote_ident(table)
+    };
+    let sql = format!(
+        "SELECT {} FROM {from} ORDER BY {}",
+        select.join(", "),
+        quote_ident(time_column)
+    );
+    let mut rows = pg.query_raw(&sql, std::iter::empty::<i32>())?;
+    while let Some(row) = rows.next()? {
+        let values = kinds
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| -> Result<Value> {
+                Ok(match kind {
+                    ColumnKind::Int => row.try_get::<_, Option<i64>>(i)?.into(),
+                    ColumnKind::Float => row.try_get::<_, Option<f64>>(i)?.into(),
+                    ColumnKind::Bool => row
+                        .try_get::<_, Option<bool>>(i)?
+                        .map_or(Value::Null, Value::Bool),
+                    ColumnKind::Text => row.try_get::<_, Option<String>>(i)?.into(),
+                    ColumnKind::Timestamp => row.try_get::<_, Option<DateTime<Utc>>>(i)?.into(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        writer.push(values)?;
+    }
+    writer.finish()
+}
+
+/// The same tables, read from an export without going through PostgreSQL.
+/// Workout ids are numbered from 1 in start order, so they only link rows of
+/// one exported dataset. Gadgetbridge's `*_SAMPLE` tables are written as they
+/// are, with `TIMESTAMP` as a real timestamp.
+fn export_from_gadgetbridge(export: &Export, out: &Path, format: TableFormat) -> Result<()> {
+    let mut summaries: Vec<_> = read_base_activity_summary(export)?
+        .into_iter()
+        .filter_map(|s| Some((activity_label(s.activity_kind)?, s)))
+        .collect();
+    summaries.sort_by_key(|(_, s)| s.start);
+
+    let mut workouts = PartitionedTable::create(
+        out,
+        "workouts",
+        format,
+        vec![
+            Column::new("id", ColumnKind::Int),
+            Column::new("device_id", ColumnKind::Int),
+            Column::new("user_id", ColumnKind::Int),
+            Column::new("activity_kind", ColumnKind::Int),
+            Column::new("activity", ColumnKind::Text),
+            Column::new("start_time", ColumnKind::Timestamp),
+            Column::new("end_time", ColumnKind::Timestamp),
+            Column::new("duration_s", ColumnKind::Int),
+            Column::new("name", ColumnKind::Text),
+            Column::new("base_lon", ColumnKind::Float),
+            Column::new("base_lat", ColumnKind::Float),
+            Column::new("base_altitude", ColumnKind::Int),
+            Column::new("summary_data_json", ColumnKind::Text),
+            Column::new("source", ColumnKind::Text),
+        ],
+        "start_time",
+    )?;
+    let mut points = PartitionedTable::create(
+        out,
+        "workout_points",
+        format,
+        vec![
+            Column::new("workout_id", ColumnKind::Int),
+            Column::new("idx", ColumnKind::Int),
+            Column::new("t", ColumnKind::Timestamp),
+            Column::new("lat", ColumnKind::Float),
+            Column::new("lon", ColumnKind::Float),
+            Column::new("ele", ColumnKind::Float),
+            Column::new("hr", ColumnKind::Int),
+            Column::new("cad", ColumnKind::Int),
+            Column::new("atemp", ColumnKind::Float),
+            Column::new("speed", ColumnKind::Float),
+            Column::new("course", ColumnKind::Float),
+            Column::new("trk", ColumnKind::Int),
+            Column::new("seg", ColumnKind::Int),
+        ],
+        "t",
+    )?;
+
+    let files = FileIndex::build(export)?;
+    let mut gpx_report = FileReport::default();
+    let mut gpx_unreadable = 0usize;
+
+    for (id, (activity, s)) in (1i64..).zip(&summaries) {
+        let (base_lon, base_lat) = e7_to_degrees(s.base_longitude_e7, s.base_latitude_e7);
+        workouts.push(vec![
+            id.into(),
+            s.device_id.into(),
+            s.user_id.into(),
+            s.activity_kind.into(),
+            (*activity).into(),
+            s.start.into(),
+            s.end.into(),
+            duration_seconds_i32(s.end - s.start).into(),
+            s.name.clone().into(),
+            base_lon.into(),
+            base_lat.into(),
+            s.base_altitude.into(),
+            s.summary_data_json.as_ref().map(ToString::to_string).into(),
+            s.source.into(),
+        ])?;
+
+        let Some(gpx_path) = s
+            .gpx_track_android
+            .as_deref()
+            .and_then(|android_path| resolve_file(export, &files, android_path, &mut gpx_report))
+        else {
+            continue;
+        };
+        let mut write_failed = false;
+        let data = export.with_file(&gpx_path, |r| {
+            let mut gpx = GpxReader::new(BufReader::new(r), Some((s.start, s.end)));
+            for p in gpx.by_ref() {
+                let p = p?;
+                points
+                    .push(vec![
+                        id.into(),
+                        p.idx.into(),
+                        p.t.into(),
+                        p.lat.into(),
+                        p.lon.into(),
+                        p.ele.into(),
+                        p.hr.into(),
+                        p.cad.into(),
+                        p.atemp.into(),
+                        p.speed.into(),
+                        p.course.into(),
+                        p.trk.into(),
+                        p.seg.into(),
+                    ])
+                    .inspect_err(|_| write_failed = true)?;
+            }
+            Ok(gpx.finish())
+        });
+        match data {
+            Ok(data) => log_time_repairs(&gpx_path, &data),
+            Err(e) if write_failed => return Err(e),
+            Err(e) => {
+                // Points read before the error stay in the table.
+                tracing::warn!(path = %gpx_path, err = format!("{e:#}"), "reading gpx failed");
+                gpx_unreadable += 1;
+            }
+        }
+    }
+
+    let workout_rows = workouts.finish()?;
+    tracing::info!(table = "workouts", rows = workout_rows, "table exported");
+    let point_rows = points.finish()?;
+    tracing::info!(
+        table = "workout_points",
+        rows = point_rows,
+        gpx_missing = gpx_report.missing,
+        gpx_ambiguous = gpx_report.ambiguous,
+        gpx_unreadable,
+        "table exported"
+    );
+
+    let mut tables = 2;
+    if let Some(conn) = open_database(export)? {
+        for table in sample_tables(&conn)? {
+            let rows = export_sample_table(&conn, out, format, &table)
+                .with_context(|| format!("Exporting table {table}"))?;
+            tracing::info!(table = table.as_str(), rows, "table exported");
+            tables += 1;
+        }
+    }
+    tracing::info!(tables, dir = %out.display(), "export done");
+    Ok(())
+}
+
+/// A Gadgetbridge sample table, with lower-cased table and column names.
+fn export_sample_table(
+    conn: &rusqlite::Connection,
+    out: &Path,
+    format: TableFormat,
+    table: &str,
+) -> Result<usize> {
+    let ident = quote_ident(table);
+
+    let mut columns = Vec::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT name, type FROM pragma_table_info({ident})"
+    ))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let decl: String = row.get(1)?;
+        let decl = decl.to_ascii_uppercase();
+        let kind = if name == "TIMESTAMP" {
+            ColumnKind::Timestamp
+        } else if decl.contains("INT") {
+            ColumnKind::Int
+        } else if decl.contains("REAL") || decl.contains("FLOA") || decl.contains("DOUB") {
+            ColumnKind::Float
+        } else if decl.contains("BLOB") {
+            continue;
+        } else {
+            ColumnKind::Text
+        };
+        columns.push((name, kind));
+    }
+
+    let max_timestamp: Option<i64> =
+        conn.query_row(&format!("SELECT max(TIMESTAMP) FROM {ident}"), [], |r| {
+            r.get(0)
+        })?;
+    let millis = max_timestamp.is_some_and(|t| t > MAX_SECONDS_TIMESTAMP);
+
+    let select: Vec<String> = columns.iter().map(|(name, _)| quote_ident(name)).collect();
+    let kinds: Vec<ColumnKind> = columns.iter().map(|(_, kind)| *kind).collect();
+    let mut writer = PartitionedTable::create(
+        out,
+        &table.to_ascii_lowercase(),
+        format,
+        columns
+            .into_iter()
+            .map(|(name, kind)| Column::new(name.to_ascii_lowercase(), kind))
+            .collect(),
+        "timestamp",
+    )?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {ident} ORDER BY TIMESTAMP",
+        select.join(", ")
+    ))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let values = kinds
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| -> Result<Value> {
+                let v = row.get_ref(i)?;
+                Ok(match (kind, v) {
+                    (_, ValueRef::Null) => Value::Null,
+                    (ColumnKind::Timestamp, ValueRef::Integer(t)) => {
+                        let t = if millis {
+                            Utc.timestamp_millis_opt(t)
+                        } else {
+                            Utc.timestamp_opt(t, 0)
+                        };
+                        t.single().into()
+                    }
+                    (ColumnKind::Int, ValueRef::Integer(v)) => Value::Int(v),
+                    (ColumnKind::Float, ValueRef::Real(v)) => Value::Float(v),
+                    (ColumnKind::Float, ValueRef::Integer(v)) => Value::Float(v as f64),
+                    (ColumnKind::Text, ValueRef::Text(v)) => {
+                        Value::Text(String::from_utf8_lossy(v).into_owned())
+                    }
+                    (ColumnKind::Text, ValueRef::Integer(v)) => Value::Text(v.to_string()),
+                    (ColumnKind::Text, ValueRef::Real(v)) => Value::Text(v.to_string()),
+                    // SQLite doesn't enforce declared types; drop what doesn't fit.
+                    _ => Value::Null,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        writer.push(values)?;
+    }
+    writer.finish()
+}
@@ -0,0 +1,396 @@
+use crate::gpx::{attribute, non_empty, parse_f64, parse_gpx_time, parse_i32};
+use crate::types::{GpxPoint, WorkoutRecords, WorkoutSample};
+use crate::utils::summary_json;
+use anyhow::{Result, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use serde_json::Value as JsonValue;
+use std::io::BufRead;
+
+/// The first `<Activity>` of a TCX file.
+#[derive(Debug, Clone)]
+pub struct TcxActivity {
+    /// `Sport` attribute: `Running`, `Biking` or `Other`.
+    pub sport: Option<String>,
+    pub notes: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Lap totals in Gadgetbridge's summary JSON layout.
+    pub summary_data_json: Option<JsonValue>,
+    pub records: WorkoutRecords,
+}
+
+/// Parse the first activity of a TCX file (Garmin Connect, Strava, ...). Laps
+/// give the totals; every `<Trackpoint>` becomes a sample, and those with a
+/// `<Position>` track points too.
+///
+/// Watches start a new `<Track>` within a lap when the recording is paused, so
+/// that starts a new segment. A new lap does not.
+pub fn parse_tcx<R: BufRead>(reader: R) -> Result<TcxActivity> {
+    let mut xml = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut st = TcxState::default();
+    loop {
+        buf.clear();
+        match xml.read_event_into(&mut buf) {
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => st.start(&e),
+            Ok(Event::Empty(e)) => {
+                st.start(&e);
+                st.end();
+            }
+            Ok(Event::End(_)) => st.end(),
+            Ok(Event::Text(e)) => {
+                if let Ok(s) = e.xml_content() {
+                    st.text.push_str(&s);
+                }
+            }
+            Ok(Event::CData(e)) => {
+                if let Ok(s) = e.decode() {
+                    st.text.push_str(&s);
+                }
+            }
+            Ok(Event::GeneralRef(e)) => {
+                if let Ok(Some(c)) = e.resolve_char_ref() {
+                    st.text.push(c);
+                } else if let Ok(name) = e.decode()
+                    && let Some(s) = resolve_predefined_entity(&name)
+                {
+                    st.text.push_str(s);
+                }
+            }
+            Err(e) => bail!("TCX XML parse error: {e}"),
+            _ => {}
+        }
+    }
+    st.finish()
+}
+
+#[derive(Default)]
+struct TcxState {
+    /// Local names of the open elements.
+    path: Vec<Vec<u8>>,
+    text: String,
+    /// `<Activity>` elements seen; only the first one is read.
+    activities: usize,
+
+    sport: Option<String>,
+    id: Option<DateTime<Utc>>,
+    notes: Option<String>,
+    laps: Vec<Lap>,
+    tracks_in_lap: usize,
+    seg: i32,
+    cur: Trackpoint,
+    records: WorkoutRecords,
+}
+
+#[derive(Default)]
+struct Lap {
+    start: Option<DateTime<Utc>>,
+    total_time_s: Option<f64>,
+    distance_m: Option<f64>,
+    calories: Option<f64>,
+    avg_hr: Option<f64>,
+    max_hr: Option<f64>,
+}
+
+#[derive(Default)]
+struct Trackpoint {
+    time: Option<DateTime<Utc>>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    ele: Option<f64>,
+    distance_m: Option<f64>,
+    hr: Option<i32>,
+    cad: Option<i32>,
+    speed: Option<f64>,
+    power: Option<i32>,
+}
+
+impl TcxState {
+    fn start(&mut self, e: &BytesStart<'_>) {
+        let name = e.local_name().as_ref().to_vec();
+        self.text.clear();
+        if name == b"Activity" {
+            self.activities += 1;
+        }
+        if self.activities == 1 {
+            match name.as_slice() {
+                b"Activity" => self.sport = attribute(e, b"Sport"),
+                b"Lap" => {
+                    self.laps.push(Lap {
+                        start: attribute(e, b"StartTime").and_then(|s| parse_gpx_time(&s)),
+                        ..Lap::default()
+                    });
+                    self.tracks_in_lap = 0;
+                }
+                b"Track" => {
+                    if self.tracks_in_lap > 0 && !self.records.points.is_empty() {
+                        self.seg += 1;
+                    }
+                    self.tracks_in_lap += 1;
+                }
+                b"Trackpoint" => self.cur = Trackpoint::default(),
+                _ => {}
+            }
+        }
+        self.path.push(name);
+    }
+
+    fn end(&mut self) {
+        let text = std::mem::take(&mut self.text);
+        let s = text.trim();
+        let n = self.path.len();
+        if self.activities == 1 && n >= 2 {
+            let parent = self.path[n - 2].as_slice();
+            let grandparent = n.checked_sub(3).map(|i| self.path[i].as_slice());
+            let lap = self.laps.last_mut();
+            let cur = &mut self.cur;
+            match (parent, self.path[n - 1].as_slice()) {
+                (b"Activity", b"Id") => self.id = parse_gpx_time(s),
+                (b"Activity", b"Notes") => self.notes = non_empty(s),
+                (b"Lap", field) => {
+                    if let Some(lap) = lap {
+                        match field {
+                            b"TotalTimeSeconds" => lap.total_time_s = parse_f64(s),
+                            b"DistanceMeters" => lap.distance_m = parse_f64(s),
+                            b"Calories" => lap.calories = parse_f64(s),
+                            _ => {}
+                        }
+                    }
+                }
+                (b"AverageHeartRateBpm", b"Value") if grandparent == Some(b"Lap") => {
+                    if let Some(lap) = lap {
+                        lap.avg_hr = parse_f64(s);
+                    }
+                }
+                (b"MaximumHeartRateBpm", b"Value") if grandparent == Some(b"Lap") => {
+                    if let Some(lap) = lap {
+                        lap.max_hr = parse_f64(s);
+                    }
+                }
+                (b"Trackpoint", b"Time") => cur.time = parse_gpx_time(s),
+                (b"Trackpoint", b"AltitudeMeters") => cur.ele = parse_f64(s),
+                (b"Trackpoint", b"DistanceMeters") => cur.distance_m = parse_f64(s),
+                (b"Trackpoint", b"Cadence") => cur.cad = parse_i32(s),
+                (b"HeartRateBpm", b"Value") => cur.hr = parse_i32(s),
+                (b"Position", b"LatitudeDegrees") => cur.lat = parse_f64(s),
+                (b"Position", b"LongitudeDegrees") => cur.lon = parse_f64(s),
+                (b"TPX", b"Speed") => cur.speed = parse_f64(s),
+                // Running cadence lives here; <Cadence> is the bike's.
+                (b"TPX", b"RunCadence") => cur.cad = cur.cad.or_else(|| parse_i32(s)),
+                (b"TPX", b"Watts") => cur.power = parse_i32(s),
+                (_, b"Trackpoint") => self.push_trackpoint(),
+                _ => {}
+            }
+        }
+        self.path.pop();
+    }
+
+    fn push_trackpoint(&mut self) {
+        let cur = std::mem::take(&mut self.cur);
+        // Some exporters write a last, empty trackpoint; nothing to place it at.
+        let Some(t) = cur.time else {
+            return;
+        };
+
+        let idx = i32::try_from(self.records.samples.len()).unwrap_or(i32::MAX);
+        self.records.samples.push(WorkoutSample {
+            idx,
+            t,
+            hr: cur.hr,
+            cad: cur.cad,
+            speed: cur.speed,
+            distance_m: cur.distance_m,
+            ele: cur.ele,
+            power: cur.power,
+            atemp: None,
+        });
+
+        let (Some(lat), Some(lon)) = (cur.lat, cur.lon) else {
+            return;
+        };
+        let idx = i32::try_from(self.records.points.len()).unwrap_or(i32::MAX);
+        self.records.points.push(GpxPoint {
+            idx,
+            trk: 0,
+            seg: self.seg,
+            t,
+            lat,
+            lon,
+            ele: cur.ele,
+            hr: cur.hr,
+            cad: cur.cad,
+            atemp: None,
+            speed: cur.speed,
+            course: None,
+        });
+    }
+
+    fn finish(self) -> Result<TcxActivity> {
+        if self.activities == 0 {
+            bail!("TCX file without an activity");
+        }
+
+        let first_sample = self.records.samples.first().map(|s| s.t);
+        let last_sample = self.records.samples.iter().map(|s| s.t).max();
+        let start = self
+            .laps
+            .iter()
+            .filter_map(|l| l.start)
+            .min()
+            .or(self.id)
+            .or(first_sample);
+        let laps_end = self
+            .laps
+            .iter()
+            .filter_map(|l| {
+                Some(l.start? + TimeDelta::milliseconds((l.total_time_s? * 1000.0) as i64))
+            })
+            .max();
+        let (Some(start), Some(end)) = (start, laps_end.max(last_sample).or(start)) else {
+            bail!("TCX activity without timestamps");
+        };
+
+        let sum = |f: fn(&Lap) -> Option<f64>| self.laps.iter().filter_map(f).reduce(|a, b| a + b);
+        // Laps' average heart rates, weighted by their duration.
+        let hr_time = self
+            .laps
+            .iter()
+            .filter_map(|l| Some((l.avg_hr?, l.total_time_s?)))
+            .fold((0.0, 0.0), |(hr, time), (h, t)| (hr + h * t, time + t));
+        let avg_hr = (hr_time.1 > 0.0).then(|| hr_time.0 / hr_time.1);
+
+        let summary_data_json = summary_json(&[
+            ("distanceMeters", sum(|l| l.distance_m), "meters"),
+            ("activeSeconds", sum(|l| l.total_time_s), "seconds"),
+            ("caloriesBurnt", sum(|l| l.calories), "calories_unit"),
+            ("averageHR", avg_hr, "bpm"),
+            (
+                "maxHR",
+                self.laps.iter().filter_map(|l| l.max_hr).reduce(f64::max),
+                "bpm",
+            ),
+        ]);
+
+        Ok(TcxActivity {
+            sport: self.sport,
+            notes: self.notes,
+            start,
+            end: end.max(start),
+            summary_data_json,
+            records: self.records,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2"
+    xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2">
+  <Activities>
+    <Activity Sport="Running">
+      <Id>2025-05-01T08:00:00Z</Id>
+      <Lap StartTime="2025-05-01T08:00:00Z">
+        <TotalTimeSeconds>60</TotalTimeSeconds>
+        <DistanceMeters>200</DistanceMeters>
+        <Calories>15</Calories>
+        <AverageHeartRateBpm><Value>140</Value></AverageHeartRateBpm>
+        <MaximumHeartRateBpm><Value>150</Value></MaximumHeartRateBpm>
+        <Track>
+          <Trackpoint>
+            <Time>2025-05-01T08:00:00Z</Time>
+            <Position><LatitudeDegrees>48.0</LatitudeDegrees><LongitudeDegrees>-4.0</LongitudeDegrees></Position>
+            <HeartRateBpm><Value>138</Value></HeartRateBpm>
+            <Extensions><ns3:TPX><ns3:Speed>3.1</ns3:Speed><ns3:RunCadence>86</ns3:RunCadence></ns3:TPX></Extensions>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2025-05-01T08:01:00Z</Time>
+            <Position><LatitudeDegrees>48.001</LatitudeDegrees><LongitudeDegrees>-4.0</LongitudeDegrees></Position>
+          </Trackpoint>
+        </Track>
+      </Lap>
+      <Lap StartTime="2025-05-01T08:01:00Z">
+        <TotalTimeSeconds>120</TotalTimeSeconds>
+        <DistanceMeters>300</DistanceMeters>
+        <Calories>25</Calories>
+        <AverageHeartRateBpm><Value>155</Value></AverageHeartRateBpm>
+        <MaximumHeartRateBpm><Value>170</Value></MaximumHeartRateBpm>
+        <Track>
+          <Trackpoint>
+            <Time>2025-05-01T08:01:30Z</Time>
+            <Position><LatitudeDegrees>48.002</LatitudeDegrees><LongitudeDegrees>-4.0</LongitudeDegrees></Position>
+          </Trackpoint>
+          <Trackpoint>
+            <Time>2025-05-01T08:01:40Z</Time>
+            <HeartRateBpm><Value>160</Value></HeartRateBpm>
+          </Trackpoint>
+        </Track>
+        <Track>
+          <Trackpoint>
+            <Time>2025-05-01T08:02:40Z</Time>
+            <Position><LatitudeDegrees>48.003</LatitudeDegrees><LongitudeDegrees>-4.0</LongitudeDegrees></Position>
+          </Trackpoint>
+          <Trackpoint/>
+        </Track>
+      </Lap>
+    </Activity>
+    <Activity Sport="Biking">
+      <Id>2025-05-02T08:00:00Z</Id>
+    </Activity>
+  </Activities>
+</TrainingCenterDatabase>
+"#;
+
+    fn parse() -> TcxActivity {
+        parse_tcx(TCX.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn new_track_within_a_lap_starts_a_segment_but_new_lap_does_not() {
+        let tcx = parse();
+        let segs: Vec<i32> = tcx.records.points.iter().map(|p| p.seg).collect();
+        assert_eq!(segs, [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn every_timed_trackpoint_is_a_sample() {
+        let tcx = parse();
+        // The position-less one is a sample only; the empty one is dropped.
+        assert_eq!(tcx.records.samples.len(), 5);
+        assert_eq!(tcx.records.points.len(), 4);
+        assert_eq!(tcx.records.samples[3].hr, Some(160));
+
+        let first = &tcx.records.points[0];
+        assert_eq!(first.hr, Some(138));
+        assert_eq!(first.cad, Some(86));
+        assert_eq!(first.speed, Some(3.1));
+    }
+
+    #[test]
+    fn laps_give_times_and_totals() {
+        let tcx = parse();
+        assert_eq!(tcx.sport.as_deref(), Some("Running"));
+        assert_eq!(tcx.start, parse_gpx_time("2025-05-01T08:00:00Z").unwrap());
+        assert_eq!(tcx.end, parse_gpx_time("2025-05-01T08:03:00Z").unwrap());
+
+        let summary = tcx.summary_data_json.unwrap();
+        assert_eq!(summary["distanceMeters"]["value"], 500.0);
+        assert_eq!(summary["activeSeconds"]["value"], 180.0);
+        assert_eq!(summary["caloriesBurnt"]["value"], 40.0);
+        // Weighted by lap time: (140 * 60 + 155 * 120) / 180.
+        assert_eq!(summary["averageHR"]["value"], 150.0);
+        assert_eq!(summary["maxHR"]["value"], 170.0);
+    }
+
+    #[test]
+    fn file_without_activity_fails() {
+        let xml = r#"<TrainingCenterDatabase><Activities/></TrainingCenterDatabase>"#;
+        assert!(parse_tcx(xml.as_bytes()).is_err());
+    }
+}
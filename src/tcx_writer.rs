@@ -0,0 +1,378 @@
+use crate::gpx_writer::workout_name;
+use crate::stored::StoredWorkout;
+use crate::types::{GpxPoint, GpxWaypoint};
+use crate::utils::haversine_m;
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use quick_xml::escape::escape;
+use std::io::Write;
+use std::ops::Range;
+
+const TCX_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase
+  xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2"
+  xmlns:ns3="http://www.garmin.com/xmlschemas/ActivityExtension/v2"
+  xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"
+  xsi:schemaLocation="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2 http://www.garmin.com/xmlschemas/TrainingCenterDatabasev2.xsd">
+"#;
+
+/// How a TCX activity is split into laps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LapMode {
+    /// A lap every kilometer.
+    #[default]
+    Km,
+    /// The laps marked on the watch, stored as timed waypoints. Falls back to
+    /// kilometer laps when the workout has none.
+    Device,
+}
+
+/// Write a stored workout as a TCX activity.
+///
+/// Calories (`caloriesBurnt` in the summary JSON) are shared between laps by
+/// duration. Lap heart rate comes from the track points, or from the summary
+/// when they have none. A stored track segment change inside a lap starts a new
+/// `<Track>`, as Garmin devices do after a pause.
+pub fn write_tcx<W: Write + ?Sized>(
+    w: &mut W,
+    workout: &StoredWorkout,
+    points: &[GpxPoint],
+    waypoints: &[GpxWaypoint],
+    mode: LapMode,
+) -> Result<()> {
+    let running = matches!(workout.activity.as_str(), "outdoor_running" | "treadmill");
+    let sport = tcx_sport(&workout.activity);
+
+    let (distances, times) = cumulative_distance_and_time(points);
+    let (ranges, trigger) = match mode {
+        LapMode::Device => match device_laps(points, waypoints) {
+            Some(ranges) => (ranges, "Manual"),
+            None => {
+                tracing::warn!(
+                    workout = workout.id,
+                    "no lap markers stored; using kilometer laps"
+                );
+                (km_laps(&distances), "Distance")
+            }
+        },
+        LapMode::Km => (km_laps(&distances), "Distance"),
+    };
+
+    let laps: Vec<Lap> = if points.is_empty() {
+        vec![Lap::without_points(workout)]
+    } else {
+        ranges
+            .into_iter()
+            .map(|r| Lap::new(points, &distances, &times, r))
+            .collect()
+    };
+
+    let total_calories = workout.summary_value("caloriesBurnt");
+    let total_time: f64 = laps.iter().map(|l| l.time_s).sum();
+    let single_lap = laps.len() == 1;
+
+    w.write_all(TCX_HEADER.as_bytes())?;
+    writeln!(w, "  <Activities>")?;
+    writeln!(w, r#"    <Activity Sport="{sport}">"#)?;
+    writeln!(w, "      <Id>{}</Id>", tcx_time(workout.start))?;
+
+    for lap in &laps {
+        let calories = total_calories
+            .map(|c| {
+                if total_time > 0.0 {
+                    c * lap.time_s / total_time
+                } else {
+                    c
+                }
+            })
+            .unwrap_or(0.0);
+        let avg_hr = lap.avg_hr().or_else(|| {
+            single_lap
+                .then(|| workout.summary_value("averageHR"))
+                .flatten()
+        });
+        let max_hr = lap
+            .max_hr
+            .map(f64::from)
+            .or_else(|| single_lap.then(|| workout.summary_value("maxHR")).flatten());
+
+        writeln!(w, r#"      <Lap StartTime="{}">"#, tcx_time(lap.start))?;
+        writeln!(
+            w,
+            "        <TotalTimeSeconds>{:.1}</TotalTimeSeconds>",
+            lap.time_s
+        )?;
+        writeln!(
+            w,
+            "        <DistanceMeters>{:.1}</DistanceMeters>",
+            lap.distance_m
+        )?;
+        writeln!(
+            w,
+            "        <Calories>{}</Calories>",
+            calories.round().clamp(0.0, 65535.0)
+        )?;
+        if let Some(hr) = avg_hr {
+            writeln!(
+                w,
+                "        <AverageHeartRateBpm><Value>{}</Value></AverageHeartRateBpm>",
+                hr.round()
+            )?;
+        }
+        if let Some(hr) = max_hr {
+            writeln!(
+                w,
+                "        <MaximumHeartRateBpm><Value>{}</Value></MaximumHeartRateBpm>",
+                hr.round()
+            )?;
+        }
+        writeln!(w, "        <Intensity>Active</Intensity>")?;
+        writeln!(w, "        <TriggerMethod>{trigger}</TriggerMethod>")?;
+
+        let lap_points = &points[lap.range.clone()];
+        let lap_distances = &distances[lap.range.clone()];
+        let mut offset = 0;
+        for track in lap_points.chunk_by(same_segment) {
+            writeln!(w, "        <Track>")?;
+            for (p, d) in track.iter().zip(&lap_distances[offset..]) {
+                write_trackpoint(w, p, *d, running)?;
+            }
+            writeln!(w, "        </Track>")?;
+            offset += track.len();
+        }
+
+        if lap.time_s > 0.0 && lap.distance_m > 0.0 {
+            writeln!(w, "        <Extensions>")?;
+            writeln!(w, "          <ns3:LX>")?;
+            writeln!(
+                w,
+                "            <ns3:AvgSpeed>{:.3}</ns3:AvgSpeed>",
+                lap.distance_m / lap.time_s
+            )?;
+            writeln!(w, "          </ns3:LX>")?;
+            writeln!(w, "        </Extensions>")?;
+        }
+        writeln!(w, "      </Lap>")?;
+    }
+
+    writeln!(w, "      <Notes>{}</Notes>", escape(workout_name(workout)))?;
+    writeln!(w, "    </Activity>")?;
+    writeln!(w, "  </Activities>")?;
+    writeln!(w, "</TrainingCenterDatabase>")?;
+    Ok(())
+}
+
+fn write_trackpoint<W: Write + ?Sized>(
+    w: &mut W,
+    p: &GpxPoint,
+    distance_m: f64,
+    running: bool,
+) -> Result<()> {
+    writeln!(w, "          <Trackpoint>")?;
+    writeln!(w, "            <Time>{}</Time>", tcx_time(p.t))?;
+    writeln!(w, "            <Position>")?;
+    writeln!(
+        w,
+        "              <LatitudeDegrees>{:.7}</LatitudeDegrees>",
+        p.lat
+    )?;
+    writeln!(
+        w,
+        "              <LongitudeDegrees>{:.7}</LongitudeDegrees>",
+        p.lon
+    )?;
+    writeln!(w, "            </Position>")?;
+    if let Some(ele) = p.ele {
+        writeln!(w, "            <AltitudeMeters>{ele:.1}</AltitudeMeters>")?;
+    }
+    writeln!(
+        w,
+        "            <DistanceMeters>{distance_m:.1}</DistanceMeters>"
+    )?;
+    if let Some(hr) = p.hr.filter(|v| (1..=255).contains(v)) {
+        writeln!(
+            w,
+            "            <HeartRateBpm><Value>{hr}</Value></HeartRateBpm>"
+        )?;
+    }
+
+    let cad = p.cad.filter(|v| (0..=254).contains(v));
+    // <Cadence> is bike cadence; running cadence goes in the TPX extension.
+    if let (Some(cad), false) = (cad, running) {
+        writeln!(w, "            <Cadence>{cad}</Cadence>")?;
+    }
+    let run_cadence = cad.filter(|_| running);
+    if run_cadence.is_some() || p.speed.is_some() {
+        writeln!(w, "            <Extensions>")?;
+        writeln!(w, "              <ns3:TPX>")?;
+        if let Some(v) = p.speed.filter(|v| *v >= 0.0) {
+            writeln!(w, "                <ns3:Speed>{v:.3}</ns3:Speed>")?;
+        }
+        if let Some(cad) = run_cadence {
+            writeln!(w, "                <ns3:RunCadence>{cad}</ns3:RunCadence>")?;
+        }
+        writeln!(w, "              </ns3:TPX>")?;
+        writeln!(w, "            </Extensions>")?;
+    }
+    writeln!(w, "          </Trackpoint>")?;
+    Ok(())
+}
+
+/// Totals of one lap.
+struct Lap {
+    range: Range<usize>,
+    start: DateTime<Utc>,
+    /// Moving time: pauses between track segments don't count.
+    time_s: f64,
+    distance_m: f64,
+    hr_sum: f64,
+    hr_count: u32,
+    max_hr: Option<i32>,
+}
+
+impl Lap {
+    /// `distances` and `times` are cumulative; a lap runs from the last point of
+    /// the previous lap to its own last point.
+    fn new(points: &[GpxPoint], distances: &[f64], times: &[f64], range: Range<usize>) -> Self {
+        let lap_points = &points[range.clone()];
+        let before = range.start.checked_sub(1);
+        let mut lap = Self {
+            start: lap_points[0].t,
+            time_s: times[range.end - 1] - before.map_or(0.0, |i| times[i]),
+            distance_m: distances[range.end - 1] - before.map_or(0.0, |i| distances[i]),
+            hr_sum: 0.0,
+            hr_count: 0,
+            max_hr: None,
+            range,
+        };
+
+        for hr in lap_points.iter().filter_map(|p| p.hr) {
+            lap.hr_sum += f64::from(hr);
+            lap.hr_count += 1;
+            lap.max_hr = lap.max_hr.max(Some(hr));
+        }
+        lap
+    }
+
+    fn without_points(workout: &StoredWorkout) -> Self {
+        Self {
+            range: 0..0,
+            start: workout.start,
+            time_s: f64::from(workout.duration_s),
+            distance_m: workout.summary_value("distanceMeters").unwrap_or(0.0),
+            hr_sum: 0.0,
+            hr_count: 0,
+            max_hr: None,
+        }
+    }
+
+    fn avg_hr(&self) -> Option<f64> {
+        (self.hr_count > 0).then(|| self.hr_sum / f64::from(self.hr_count))
+    }
+}
+
+fn same_segment(a: &GpxPoint, b: &GpxPoint) -> bool {
+    a.trk == b.trk && a.seg == b.seg
+}
+
+/// Distance (m) and moving time (s) from the first point to each point, not
+/// counting the jumps and pauses between track segments.
+fn cumulative_distance_and_time(points: &[GpxPoint]) -> (Vec<f64>, Vec<f64>) {
+    let (mut distance, mut time) = (0.0, 0.0);
+    let mut distances = Vec::with_capacity(points.len());
+    let mut times = Vec::with_capacity(points.len());
+    for (i, p) in points.iter().enumerate() {
+        if let Some(prev) = i.checked_sub(1).map(|j| &points[j])
+            && same_segment(prev, p)
+        {
+            distance += haversine_m(prev.lat, prev.lon, p.lat, p.lon);
+            time += (p.t - prev.t).num_milliseconds() as f64 / 1000.0;
+        }
+        distances.push(distance);
+        times.push(time);
+    }
+    (distances, times)
+}
+
+/// A lap ends at the first point reaching each full kilometer.
+fn km_laps(distances: &[f64]) -> Vec<Range<usize>> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut next_km = 1000.0;
+    for (i, d) in distances.iter().enumerate() {
+        if *d >= next_km {
+            out.push(start..i + 1);
+            start = i + 1;
+            while next_km <= *d {
+                next_km += 1000.0;
+            }
+        }
+    }
+    if start < distances.len() {
+        out.push(start..distances.len());
+    }
+    out
+}
+
+/// A new lap starts at the first point at or after each timed waypoint.
+fn device_laps(points: &[GpxPoint], waypoints: &[GpxWaypoint]) -> Option<Vec<Range<usize>>> {
+    let first = points.first()?.t;
+    let mut marks: Vec<DateTime<Utc>> = waypoints
+        .iter()
+        .filter_map(|w| w.t)
+        .filter(|t| *t > first)
+        .collect();
+    marks.sort();
+    if marks.is_empty() {
+        return None;
+    }
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut marks = marks.into_iter().peekable();
+    for (i, p) in points.iter().enumerate() {
+        let mut split = false;
+        while marks.next_if(|m| p.t >= *m).is_some() {
+            split = true;
+        }
+        if split && i > start {
+            out.push(start..i);
+            start = i;
+        }
+    }
+    out.push(start..points.len());
+    Some(out)
+}
+
+fn tcx_time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// TCX only knows `Running`, `Biking` and `Other`.
+fn tcx_sport(activity: &str) -> &'static str {
+    match activity {
+        "outdoor_running" | "treadmill" => "Running",
+        "cycling" => "Biking",
+        _ => "Other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn km_laps_end_at_the_point_reaching_each_kilometer() {
+        let distances = [0.0, 400.0, 999.0, 1000.0, 1500.0, 2600.0, 2800.0];
+        assert_eq!(km_laps(&distances), [0..4, 4..6, 6..7]);
+        assert_eq!(km_laps(&[0.0, 500.0]).first(), Some(&(0..2)));
+        assert!(km_laps(&[]).is_empty());
+    }
+
+    #[test]
+    fn sports_map_to_tcx_sports() {
+        assert_eq!(tcx_sport("treadmill"), "Running");
+        assert_eq!(tcx_sport("cycling"), "Biking");
+        assert_eq!(tcx_sport("swimming"), "Other");
+    }
+}
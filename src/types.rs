@@ -1,6 +1,28 @@
 use chrono::{DateTime, Utc};
 use serde_json::Value as JsonValue;
 
+/// `workouts.source` of workouts read from the export DB (`BASE_ACTIVITY_SUMMARY`).
+pub const SOURCE_GADGETBRIDGE: &str = "gadgetbridge";
+/// `workouts.source` of workouts rebuilt from export files no DB row references.
+pub const SOURCE_ORPHAN_FILE: &str = "orphan_file";
+/// `workouts.source` of workouts imported from standalone GPX, TCX or FIT files
+/// (`roudenn import-files`).
+pub const SOURCE_IMPORTED_FILE: &str = "imported_file";
+/// `workouts.source` of workouts imported from a Strava account archive.
+pub const SOURCE_STRAVA: &str = "strava";
+/// `workouts.source` of workouts imported from an Apple Health export.
+pub const SOURCE_APPLE_HEALTH: &str = "apple_health";
+/// `workouts.source` of workouts merged from duplicates recorded by several
+/// sources (`roudenn merge-duplicates`); `workout_links` names them.
+pub const SOURCE_MERGED: &str = "merged";
+
+/// Gadgetbridge `ACTIVITY_KIND` of outdoor runs.
+pub const ACTIVITY_KIND_OUTDOOR_RUNNING: i32 = 67109041;
+/// Gadgetbridge `ACTIVITY_KIND` of treadmill runs.
+pub const ACTIVITY_KIND_TREADMILL: i32 = 256;
+/// Gadgetbridge's `ACTIVITY_KIND` for activities it can't tell apart.
+pub const ACTIVITY_KIND_UNKNOWN: i32 = 0;
+
 #[derive(Debug, Clone)]
 pub struct WorkoutSummary {
     pub name: Option<String>,
@@ -23,13 +45,144 @@ pub struct WorkoutSummary {
     pub raw_summary_data: Option<Vec<u8>>,
 
     pub raw_details: Option<Vec<u8>>,
+
+    /// Where the workout came from; stored in `workouts.source`.
+    pub source: &'static str,
+    /// Id of the workout in `source` (e.g. an imported file's path). Workouts
+    /// with one are matched on it when re-imported, instead of on their device
+    /// and start time.
+    pub external_id: Option<String>,
+}
+
+impl WorkoutSummary {
+    /// A workout no Gadgetbridge row describes (a file, another service's
+    /// export), with only its source and times set. It has no device or user:
+    /// 0 is never a Gadgetbridge id.
+    pub const fn standalone(
+        source: &'static str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            name: None,
+            start,
+            end,
+            activity_kind: ACTIVITY_KIND_UNKNOWN,
+
+            base_longitude_e7: None,
+            base_latitude_e7: None,
+            base_altitude: None,
+
+            gpx_track_android: None,
+            raw_details_android: None,
+
+            device_id: 0,
+            user_id: 0,
+
+            summary_data_raw: None,
+            summary_data_json: None,
+            raw_summary_data: None,
+
+            raw_details: None,
+
+            source,
+            external_id: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct GpxPoint {
+    pub idx: i32,
+    /// Index of the `<trk>` in the file.
+    pub trk: i32,
+    /// Index of the `<trkseg>` within its track. A new segment usually means the
+    /// recording was paused, so consecutive points across segments aren't joined.
+    pub seg: i32,
+    pub t: DateTime<Utc>,
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f64>,
+
+    // Garmin TrackPointExtension (`gpxtpx:`) fields, when the track has them.
+    /// Heart rate, bpm.
+    pub hr: Option<i32>,
+    /// Cadence, rpm (steps per minute for most watches).
+    pub cad: Option<i32>,
+    /// Ambient temperature, °C.
+    pub atemp: Option<f64>,
+    /// Speed, m/s.
+    pub speed: Option<f64>,
+    /// Course over ground, degrees from true north.
+    pub course: Option<f64>,
+}
+
+/// One sensor reading of a workout, with or without a position, as FIT
+/// `record` messages carry them.
+#[derive(Debug, Clone)]
+pub struct WorkoutSample {
     pub idx: i32,
     pub t: DateTime<Utc>,
+    /// Heart rate, bpm.
+    pub hr: Option<i32>,
+    /// Cadence, rpm.
+    pub cad: Option<i32>,
+    /// Speed, m/s.
+    pub speed: Option<f64>,
+    /// Distance covered since the start, m.
+    pub distance_m: Option<f64>,
+    /// Elevation, m.
+    pub ele: Option<f64>,
+    /// Power, W.
+    pub power: Option<i32>,
+    /// Temperature, °C.
+    pub atemp: Option<f64>,
+}
+
+/// The track points and samples of a workout read from a FIT or TCX file.
+#[derive(Debug, Clone, Default)]
+pub struct WorkoutRecords {
+    /// Records with a position. A timer restart after a stop starts a new
+    /// segment, as a new `<trkseg>` does in GPX.
+    pub points: Vec<GpxPoint>,
+    /// Every record, positioned or not (treadmill runs have no position).
+    pub samples: Vec<WorkoutSample>,
+}
+
+/// A `<wpt>` (lap marker, photo spot, ...) or a `<rtept>` of a planned route.
+#[derive(Debug, Clone)]
+pub struct GpxWaypoint {
     pub lat: f64,
     pub lon: f64,
     pub ele: Option<f64>,
+    pub t: Option<DateTime<Utc>>,
+    pub name: Option<String>,
+    pub desc: Option<String>,
+    pub sym: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GpxRoute {
+    pub name: Option<String>,
+    pub desc: Option<String>,
+    pub points: Vec<GpxWaypoint>,
+}
+
+/// Everything roudenn reads from a GPX file.
+#[derive(Debug, Clone, Default)]
+pub struct GpxData {
+    pub points: Vec<GpxPoint>,
+    pub waypoints: Vec<GpxWaypoint>,
+    pub routes: Vec<GpxRoute>,
+
+    /// `<name>` of the first `<trk>`.
+    pub track_name: Option<String>,
+    /// `<type>` of the first `<trk>`: the activity, free-form (`running`,
+    /// `Cycling`, Strava's numeric codes, ...).
+    pub track_type: Option<String>,
+
+    /// Track points whose time was interpolated.
+    pub interpolated_points: usize,
+    /// Track points dropped: no position, or no time and nothing to interpolate from.
+    pub dropped_points: usize,
 }
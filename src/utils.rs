@@ -1,9 +1,8 @@
 use anyhow::{Context, Result, bail};
-use chrono::Duration;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use serde_json::{Map, Value as JsonValue, json};
 use std::fs::{self, File};
-use std::io;
 use std::path::{Path, PathBuf};
-use tempfile::TempDir;
 use tracing_subscriber::{EnvFilter, fmt};
 use zip::ZipArchive;
 
@@ -38,7 +37,9 @@ pub fn init_logging(verbose: u8, quiet: u8) {
 
     let show_src = matches!(level, "debug" | "trace");
 
+    // Logs go to stderr: `export` writes the exported file to stdout.
     fmt()
+        .with_writer(std::io::stderr)
         .with_env_filter(filter)
         .with_ansi(true)
         .with_timer(tracing_subscriber::fmt::time::ChronoLocal::rfc_3339())
@@ -50,118 +51,128 @@ pub fn init_logging(verbose: u8, quiet: u8) {
         .init();
 }
 
-/// Handle that keeps a tempdir alive if we extracted a ZIP.
-pub struct ExportHandle {
-    dir: PathBuf,
-    _tmp: Option<TempDir>,
+/// An export ZIP found in a directory of exports, with the time its DB was written.
+#[derive(Debug, Clone)]
+pub struct ExportCandidate {
+    pub path: PathBuf,
+    pub timestamp: DateTime<Utc>,
 }
 
-impl ExportHandle {
-    pub fn dir(&self) -> &Path {
-        &self.dir
-    }
-}
-
-/// Accepts either:
-/// - a directory containing `files/`, `database/`, etc.
-/// - a `.zip` file which we extract to a temp dir
-pub fn open_export(path: &Path) -> Result<ExportHandle> {
-    if path.is_dir() {
-        tracing::info!(path = %path.display(), "using export directory");
-        return Ok(ExportHandle {
-            dir: path.to_path_buf(),
-            _tmp: None,
-        });
+/// Resolve the `EXPORT` argument to the export(s) to ingest, oldest first.
+///
+/// A single export (ZIP or extracted dir) is returned as-is. A directory that is
+/// not itself an export is treated as a folder of export ZIPs (e.g. Gadgetbridge
+/// auto-export or backups): only the newest is returned unless `all` is set.
+pub fn resolve_exports(path: &Path, all: bool) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() || looks_like_export(path) {
+        return Ok(vec![path.to_path_buf()]);
     }
 
-    if path
-        .extension()
-        .and_then(|s| s.to_str())
-        .map(|s| s.eq_ignore_ascii_case("zip"))
-        != Some(true)
-    {
+    let mut candidates = find_export_zips(path)?;
+    if candidates.is_empty() {
         bail!(
-            "Export path must be a directory or a .zip file: {}",
+            "Directory is neither a Gadgetbridge export nor contains export ZIPs: {}",
             path.display()
         );
     }
 
-    let zip_file = File::open(path).with_context(|| format!("opening zip: {}", path.display()))?;
-    let mut zip =
-        ZipArchive::new(zip_file).with_context(|| format!("reading zip: {}", path.display()))?;
-
-    let tmp = tempfile::tempdir().context("creating tempdir for export zip")?;
     tracing::info!(
-        zip = %path.display(),
-        tmp = %tmp.path().display(),
-        entries = zip.len(),
-        "extracting export zip"
+        dir = %path.display(),
+        exports = candidates.len(),
+        newest = %candidates[candidates.len() - 1].path.display(),
+        "found export ZIPs"
     );
 
-    for i in 0..zip.len() {
-        let mut f = zip.by_index(i).context("reading zip entry")?;
-
-        // Prevent Zip Slip / path traversal.
-        let Some(rel) = f.enclosed_name() else {
-            tracing::warn!(name = %f.name(), "skipping unsafe zip entry path");
-            continue;
-        };
-
-        let out_path = tmp.path().join(&rel);
+    if !all {
+        candidates.drain(..candidates.len() - 1);
+    }
+    Ok(candidates.into_iter().map(|c| c.path).collect())
+}
 
-        if f.is_dir() {
-            fs::create_dir_all(&out_path)
-                .with_context(|| format!("creating dir: {}", out_path.display()))?;
+/// List the export ZIPs directly inside `dir`, sorted by embedded DB timestamp (oldest first).
+///
+/// ZIPs without a Gadgetbridge database are skipped.
+pub fn find_export_zips(dir: &Path) -> Result<Vec<ExportCandidate>> {
+    let mut out = Vec::new();
+    for e in fs::read_dir(dir).with_context(|| format!("reading dir: {}", dir.display()))? {
+        let path = e?.path();
+        if !path.is_file() || !has_extension(&path, "zip") {
             continue;
         }
 
-        if let Some(parent) = out_path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("creating dir: {}", parent.display()))?;
+        match embedded_db_timestamp(&path) {
+            Ok(Some(timestamp)) => out.push(ExportCandidate { path, timestamp }),
+            Ok(None) => {
+                tracing::warn!(zip = %path.display(), "skipping zip without Gadgetbridge database");
+            }
+            Err(e) => {
+                tracing::warn!(zip = %path.display(), err = format!("{e:#}"), "skipping unreadable zip");
+            }
         }
-
-        let mut out = File::create(&out_path)
-            .with_context(|| format!("creating file: {}", out_path.display()))?;
-        io::copy(&mut f, &mut out)
-            .with_context(|| format!("extracting file: {}", out_path.display()))?;
     }
 
-    let mut root = tmp.path().to_path_buf();
-    if !looks_like_export(&root) {
-        // Common case: zip contains a single top-level dir.
-        let mut dirs = Vec::new();
-        for e in fs::read_dir(&root).context("reading extracted root dir")? {
-            let e = e?;
-            if e.file_type()?.is_dir() {
-                dirs.push(e.path());
-            }
-        }
+    out.sort_by(|a, b| {
+        a.timestamp
+            .cmp(&b.timestamp)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    Ok(out)
+}
 
-        if dirs.len() == 1 && looks_like_export(&dirs[0]) {
-            root = dirs.pop().unwrap();
-        } else {
-            bail!(
-                "ZIP extracted but doesn't look like a Gadgetbridge export root: {}",
-                tmp.path().display()
-            );
-        }
+/// Modification time of the `database/Gadgetbridge` entry inside an export ZIP.
+///
+/// ZIP timestamps carry no time zone; they are compared as-is, which is fine for
+/// ordering exports written by the same phone. Falls back to the ZIP's own mtime
+/// when the entry has no usable timestamp.
+fn embedded_db_timestamp(path: &Path) -> Result<Option<DateTime<Utc>>> {
+    let zip_file = File::open(path).with_context(|| format!("opening zip: {}", path.display()))?;
+    let mut zip =
+        ZipArchive::new(zip_file).with_context(|| format!("reading zip: {}", path.display()))?;
+
+    let Some(db_index) = zip
+        .file_names()
+        .find(|n| n.trim_end_matches('/').ends_with("database/Gadgetbridge"))
+        .and_then(|n| zip.index_for_name(n))
+    else {
+        return Ok(None);
+    };
+
+    // Raw access: only metadata is needed, which also works for encrypted exports.
+    let entry = zip.by_index_raw(db_index).context("reading zip entry")?;
+    if let Some(dt) = entry
+        .last_modified()
+        .and_then(|d| NaiveDateTime::try_from(d).ok())
+    {
+        return Ok(Some(dt.and_utc()));
     }
 
-    tracing::info!(export_root = %root.display(), "export ready");
+    let mtime = fs::metadata(path)?.modified()?;
+    Ok(Some(DateTime::<Utc>::from(mtime)))
+}
 
-    Ok(ExportHandle {
-        dir: root,
-        _tmp: Some(tmp),
-    })
+pub(crate) fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|s| s.eq_ignore_ascii_case(ext))
 }
 
-fn looks_like_export(dir: &Path) -> bool {
+pub(crate) fn looks_like_export(dir: &Path) -> bool {
     dir.join("files").is_dir()
         || dir.join("database").is_dir()
         || dir.join("gadgetbridge.json").is_file()
         || dir.join("database").join("Gadgetbridge").is_file()
 }
 
+/// Parse a date given on the command line: `YYYY-MM-DD` (midnight UTC) or RFC 3339.
+pub fn parse_datetime_arg(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_time(NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|_| format!("expected YYYY-MM-DD or an RFC 3339 time, got {s:?}"))
+}
+
 pub fn format_duration(d: Duration) -> String {
     let secs = d.num_seconds().unsigned_abs();
     let h = secs / 3600;
@@ -170,16 +181,6 @@ pub fn format_duration(d: Duration) -> String {
     format!("{h:02}:{m:02}:{s:02}")
 }
 
-pub fn map_android_gpx_to_export(export_dir: &Path, android_path: &str) -> Option<PathBuf> {
-    let file_name = Path::new(android_path).file_name()?.to_str()?;
-    Some(export_dir.join("files").join(file_name))
-}
-
-pub fn map_android_raw_details_to_export(export_dir: &Path, android_path: &str) -> Option<PathBuf> {
-    let file_name = Path::new(android_path).file_name()?.to_str()?;
-    Some(export_dir.join("files").join("rawDetails").join(file_name))
-}
-
 pub fn duration_seconds_i32(d: Duration) -> i32 {
     let secs = d.num_seconds().abs();
     i32::try_from(secs).unwrap_or(i32::MAX)
@@ -198,3 +199,104 @@ pub fn e7_to_degrees(lon_e7: Option<i64>, lat_e7: Option<i64>) -> (Option<f64>,
 
     (lon, lat)
 }
+
+pub fn degrees_to_e7(deg: f64) -> i64 {
+    (deg * 10_000_000.0).round() as i64
+}
+
+/// `(key, value, unit)` totals in Gadgetbridge's summary JSON layout, so
+/// workouts read from FIT or TCX files query and export like the others.
+/// Missing values are left out; `None` if all are.
+pub(crate) fn summary_json(entries: &[(&str, Option<f64>, &str)]) -> Option<JsonValue> {
+    let map: Map<String, JsonValue> = entries
+        .iter()
+        .filter_map(|&(key, value, unit)| {
+            Some((key.to_owned(), json!({ "value": value?, "unit": unit })))
+        })
+        .collect();
+    (!map.is_empty()).then_some(JsonValue::Object(map))
+}
+
+/// A double-quoted SQL identifier; the same syntax works in PostgreSQL and SQLite.
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Great-circle distance in meters, with the same Earth radius as the
+/// `workout_distance_m` view.
+pub fn haversine_m(lat0: f64, lon0: f64, lat1: f64, lon1: f64) -> f64 {
+    let (dlat, dlon) = ((lat1 - lat0).to_radians(), (lon1 - lon0).to_radians());
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat0.to_radians().cos() * lat1.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * 6_371_000.0 * a.sqrt().asin()
+}
+
+/// Heart rate at `t` from readings sorted by time: interpolated between the
+/// readings around it when they are at most `max_gap` apart, else the nearest
+/// one if within half of that.
+pub(crate) fn interpolate_hr(
+    series: &[(DateTime<Utc>, i32)],
+    t: DateTime<Utc>,
+    max_gap: TimeDelta,
+) -> Option<i32> {
+    let i = series.partition_point(|&(st, _)| st < t);
+    let before = i.checked_sub(1).and_then(|i| series.get(i)).copied();
+    let after = series.get(i).copied();
+    match (before, after) {
+        (_, Some((t1, hr1))) if t1 == t => Some(hr1),
+        (Some((t0, hr0)), Some((t1, hr1))) if t1 - t0 <= max_gap => {
+            let f = (t - t0).as_seconds_f64() / (t1 - t0).as_seconds_f64();
+            Some((f64::from(hr1 - hr0).mul_add(f, f64::from(hr0))).round() as i32)
+        }
+        _ => [before, after]
+            .into_iter()
+            .flatten()
+            .filter(|&(st, _)| (st - t).abs() * 2 <= max_gap)
+            .min_by_key(|&(st, _)| (st - t).abs())
+            .map(|(_, hr)| hr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(s: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_750_000_000 + s, 0).unwrap()
+    }
+
+    const GAP: TimeDelta = TimeDelta::seconds(30);
+
+    #[test]
+    fn interpolates_between_close_readings() {
+        let series = [(time(0), 100), (time(20), 140)];
+        assert_eq!(interpolate_hr(&series, time(0), GAP), Some(100));
+        assert_eq!(interpolate_hr(&series, time(5), GAP), Some(110));
+        assert_eq!(interpolate_hr(&series, time(20), GAP), Some(140));
+    }
+
+    #[test]
+    fn falls_back_to_nearest_reading_within_half_the_gap() {
+        // 60 s apart: too far to interpolate.
+        let series = [(time(0), 100), (time(60), 160)];
+        assert_eq!(interpolate_hr(&series, time(10), GAP), Some(100));
+        assert_eq!(interpolate_hr(&series, time(45), GAP), Some(160));
+        assert_eq!(interpolate_hr(&series, time(30), GAP), None);
+    }
+
+    #[test]
+    fn nearest_reading_within_half_the_gap_outside_the_series() {
+        let series = [(time(0), 100), (time(10), 110)];
+        assert_eq!(interpolate_hr(&series, time(-15), GAP), Some(100));
+        assert_eq!(interpolate_hr(&series, time(25), GAP), Some(110));
+    }
+
+    #[test]
+    fn nothing_further_outside_the_series() {
+        let series = [(time(0), 100), (time(10), 110)];
+        assert_eq!(interpolate_hr(&series, time(-16), GAP), None);
+        assert_eq!(interpolate_hr(&series, time(26), GAP), None);
+        assert_eq!(interpolate_hr(&[], time(0), GAP), None);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::export::{Export, ExportOptions, is_archive_path};
+use crate::ingest::{IngestOptions, ingest};
+use crate::utils::looks_like_export;
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use serde_json::{Map, Value as JsonValue, json};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+const STATE_FILE_NAME: &str = ".roudenn-watch.json";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size + latest modification time of an export (file or directory tree).
+///
+/// Used both to detect "still being written" and to recognize exports that
+/// were already ingested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fingerprint {
+    size: u64,
+    mtime_ms: u128,
+}
+
+struct Pending {
+    last_event: Instant,
+    last_seen: Option<Fingerprint>,
+}
+
+/// Watch `dir` for new or updated exports (archives or extracted directories) and
+/// ingest each one once it has stopped changing for `settle`.
+///
+/// Already processed exports are remembered in `state_file` (default:
+/// `dir/.roudenn-watch.json`), so restarting the watcher does not re-ingest them.
+pub fn watch(
+    dir: &Path,
+    pg_url: &str,
+    settle: Duration,
+    state_file: Option<&Path>,
+    opts: &ExportOptions,
+    ingest_opts: &IngestOptions,
+) -> Result<()> {
+    if !dir.is_dir() {
+        anyhow::bail!("Watch path must be a directory: {}", dir.display());
+    }
+
+    // Nobody is there to answer a password prompt.
+    let opts = &ExportOptions {
+        non_interactive: true,
+        ..opts.clone()
+    };
+
+    let state_path = state_file.map_or_else(|| dir.join(STATE_FILE_NAME), Path::to_path_buf);
+    let mut processed = load_state(&state_path)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Creating filesystem watcher")?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Watching directory: {}", dir.display()))?;
+
+    tracing::info!(
+        dir = %dir.display(),
+        state = %state_path.display(),
+        known = processed.len(),
+        "watching for exports"
+    );
+
+    // Exports that appeared while we were not running.
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    for e in fs::read_dir(dir).with_context(|| format!("Reading directory: {}", dir.display()))? {
+        let path = e?.path();
+        if is_candidate(&path) {
+            mark_pending(&mut pending, path);
+        }
+    }
+
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            // Our own fingerprinting opens files; only react to actual changes.
+            Ok(Ok(event)) if !event.kind.is_access() => {
+                for p in event.paths {
+                    if let Some(candidate) = candidate_for(dir, &p, &state_path) {
+                        mark_pending(&mut pending, candidate);
+                    }
+                }
+            }
+            Ok(Err(e)) => tracing::warn!(err = %e, "filesystem watch error"),
+            Ok(Ok(_)) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("Filesystem watcher stopped unexpectedly");
+            }
+        }
+
+        for (path, fp) in take_settled(&mut pending, settle) {
+            let key = path.display().to_string();
+            if processed.get(&key) == Some(&fp) {
+                crate::dlog!("watch_skip_already_processed path={key}");
+                continue;
+            }
+
+            tracing::info!(export = %path.display(), "new export detected");
+            match Export::open(&path, opts).and_then(|e| ingest(&e, pg_url, ingest_opts)) {
+                Ok(()) => {
+                    processed.insert(key, fp);
+                    save_state(&state_path, &processed)?;
+                }
+                Err(e) => {
+                    // Keep it unrecorded so the next change retries it.
+                    tracing::error!(export = %path.display(), err = format!("{e:#}"), "ingest failed");
+                }
+            }
+        }
+    }
+}
+
+fn mark_pending(pending: &mut HashMap<PathBuf, Pending>, path: PathBuf) {
+    let p = pending.entry(path).or_insert(Pending {
+        last_event: Instant::now(),
+        last_seen: None,
+    });
+    p.last_event = Instant::now();
+}
+
+/// Remove and return the pending exports that are unchanged since the last
+/// poll and saw no event for `settle`. Deleted or unreadable ones are dropped.
+fn take_settled(
+    pending: &mut HashMap<PathBuf, Pending>,
+    settle: Duration,
+) -> Vec<(PathBuf, Fingerprint)> {
+    let mut ready = Vec::new();
+    pending.retain(|path, p| {
+        let Some(fp) = fingerprint(path) else {
+            return false; // deleted or unreadable
+        };
+        let stable = p.last_seen == Some(fp) && p.last_event.elapsed() >= settle;
+        p.last_seen = Some(fp);
+        if stable {
+            ready.push((path.clone(), fp));
+        }
+        !stable
+    });
+    ready
+}
+
+/// Map any changed path below `dir` to the export it belongs to: the direct child of `dir`.
+fn candidate_for(dir: &Path, changed: &Path, state_path: &Path) -> Option<PathBuf> {
+    if changed == state_path {
+        return None;
+    }
+    let rel = changed.strip_prefix(dir).ok()?;
+    let first = rel.components().next()?;
+    let candidate = dir.join(first);
+    is_candidate(&candidate).then_some(candidate)
+}
+
+fn is_candidate(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .is_some_and(|s| s.starts_with('.'));
+    if hidden {
+        return false;
+    }
+
+    if path.is_dir() {
+        return looks_like_export(path);
+    }
+
+    path.is_file() && is_archive_path(path)
+}
+
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime_ms = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_millis();
+
+    if !meta.is_dir() {
+        return Some(Fingerprint {
+            size: meta.len(),
+            mtime_ms,
+        });
+    }
+
+    let mut fp = Fingerprint { size: 0, mtime_ms };
+    for e in fs::read_dir(path).ok()? {
+        let child = fingerprint(&e.ok()?.path())?;
+        fp.size += child.size;
+        fp.mtime_ms = fp.mtime_ms.max(child.mtime_ms);
+    }
+    Some(fp)
+}
+
+fn load_state(path: &Path) -> Result<HashMap<String, Fingerprint>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let display = path.display();
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Reading watch state: {display}"))?;
+    let json: JsonValue =
+        serde_json::from_str(&text).with_context(|| format!("Parsing watch state: {display}"))?;
+
+    let mut out = HashMap::new();
+    if let Some(exports) = json.get("exports").and_then(JsonValue::as_object) {
+        for (k, v) in exports {
+            let size = v.get("size").and_then(JsonValue::as_u64);
+            let mtime_ms = v
+                .get("mtime_ms")
+                .and_then(JsonValue::as_u64)
+                .map(u128::from);
+            if let (Some(size), Some(mtime_ms)) = (size, mtime_ms) {
+                out.insert(k.clone(), Fingerprint { size, mtime_ms });
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn save_state(path: &Path, processed: &HashMap<String, Fingerprint>) -> Result<()> {
+    let mut exports = Map::new();
+    for (k, fp) in processed {
+        let mtime_ms = u64::try_from(fp.mtime_ms).unwrap_or(u64::MAX);
+        exports.insert(k.clone(), json!({ "size": fp.size, "mtime_ms": mtime_ms }));
+    }
+    let text = serde_json::to_string_pretty(&json!({ "exports": exports }))?;
+
+    // Write + rename so a crash never leaves a truncated state file.
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).with_context(|| format!("Writing watch state: {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Writing watch state: {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn ready_paths(pending: &mut HashMap<PathBuf, Pending>, settle: Duration) -> Vec<PathBuf> {
+        take_settled(pending, settle)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    #[test]
+    fn export_is_ready_once_unchanged_between_two_polls() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip = dir.path().join("export.zip");
+        fs::write(&zip, b"PK").unwrap();
+
+        let mut pending = HashMap::new();
+        mark_pending(&mut pending, zip.clone());
+        assert!(ready_paths(&mut pending, Duration::ZERO).is_empty());
+
+        // Still being written: the fingerprint changed since the last poll.
+        let mut f = OpenOptions::new().append(true).open(&zip).unwrap();
+        f.write_all(b"more").unwrap();
+        assert!(ready_paths(&mut pending, Duration::ZERO).is_empty());
+
+        assert_eq!(ready_paths(&mut pending, Duration::ZERO), [zip]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn export_waits_for_the_settle_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip = dir.path().join("export.zip");
+        fs::write(&zip, b"PK").unwrap();
+
+        let mut pending = HashMap::new();
+        mark_pending(&mut pending, zip);
+        let settle = Duration::from_secs(3600);
+        assert!(ready_paths(&mut pending, settle).is_empty());
+        assert!(ready_paths(&mut pending, settle).is_empty());
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn deleted_export_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip = dir.path().join("export.zip");
+        fs::write(&zip, b"PK").unwrap();
+
+        let mut pending = HashMap::new();
+        mark_pending(&mut pending, zip.clone());
+        fs::remove_file(&zip).unwrap();
+        assert!(ready_paths(&mut pending, Duration::ZERO).is_empty());
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn changes_map_to_the_export_below_the_watched_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let state = root.join(STATE_FILE_NAME);
+        fs::write(root.join("export.zip"), b"PK").unwrap();
+        fs::write(root.join("notes.txt"), b"").unwrap();
+        fs::write(root.join(".export.zip"), b"PK").unwrap();
+        fs::create_dir_all(root.join("extracted/files")).unwrap();
+        fs::write(root.join("extracted/files/track.gpx"), b"").unwrap();
+
+        let candidate = |p: &str| candidate_for(root, &root.join(p), &state);
+        assert_eq!(candidate("export.zip"), Some(root.join("export.zip")));
+        assert_eq!(
+            candidate("extracted/files/track.gpx"),
+            Some(root.join("extracted"))
+        );
+        assert_eq!(candidate("notes.txt"), None);
+        assert_eq!(candidate(".export.zip"), None);
+        assert_eq!(candidate(STATE_FILE_NAME), None);
+        assert_eq!(
+            candidate_for(root, Path::new("/elsewhere/x.zip"), &state),
+            None
+        );
+    }
+
+    #[test]
+    fn directory_fingerprint_covers_its_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("files")).unwrap();
+        fs::write(dir.path().join("files/a.gpx"), b"12345").unwrap();
+        fs::write(dir.path().join("gadgetbridge.json"), b"{}").unwrap();
+        assert_eq!(fingerprint(dir.path()).unwrap().size, 7);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join(STATE_FILE_NAME);
+        assert!(load_state(&state).unwrap().is_empty());
+
+        let mut processed = HashMap::new();
+        let fp = Fingerprint {
+            size: 42,
+            mtime_ms: 1_750_000_000_000,
+        };
+        processed.insert("/exports/a.zip".to_owned(), fp);
+        save_state(&state, &processed).unwrap();
+        assert_eq!(load_state(&state).unwrap(), processed);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::cli::{ExportArgs, ExportFormat, ExportTarget, GeoJsonExportArgs};
+use crate::export::ExportOptions;
+use crate::fit_writer::write_fit;
+use crate::geojson_writer::GeoJsonWriter;
+use crate::gpx_writer::write_gpx;
+use crate::ingest::check_pg_schema;
+use crate::stored::{StoredWorkout, read_points, read_samples, read_waypoints, select_workouts};
+use crate::table_export;
+use crate::tcx_writer::write_tcx;
+use anyhow::{Context, Result, bail};
+use postgres::{Client, NoTls};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes one workout to `w`, reading whatever else it needs from PostgreSQL.
+type WriteWorkout<'a> = &'a dyn Fn(&mut dyn Write, &mut Client, &StoredWorkout) -> Result<()>;
+
+pub fn run(pg_url: &str, export_opts: &ExportOptions, args: &ExportArgs) -> Result<()> {
+    match &args.format {
+        ExportFormat::Gpx(target) => export_workouts(pg_url, target, "gpx", &write_gpx_workout),
+        ExportFormat::Fit(target) => export_workouts(pg_url, target, "fit", &write_fit_workout),
+        ExportFormat::Tcx(args) => {
+            export_workouts(pg_url, &args.target, "tcx", &|w, pg, workout| {
+                let points = read_points(pg, workout.id)?;
+                let waypoints = read_waypoints(pg, workout.id)?;
+                write_tcx(w, workout, &points, &waypoints, args.laps)
+            })
+        }
+        ExportFormat::GeoJson(args) => export_geojson(pg_url, args),
+        ExportFormat::Tables(args) => table_export::run(pg_url, export_opts, args),
+    }
+}
+
+fn write_gpx_workout(w: &mut dyn Write, pg: &mut Client, workout: &StoredWorkout) -> Result<()> {
+    let points = read_points(pg, workout.id)?;
+    let waypoints = read_waypoints(pg, workout.id)?;
+    write_gpx(w, workout, &points, &waypoints)
+}
+
+fn write_fit_workout(w: &mut dyn Write, pg: &mut Client, workout: &StoredWorkout) -> Result<()> {
+    let points = read_points(pg, workout.id)?;
+    // Samples only matter when there is no track to write as records.
+    let samples = if points.is_empty() {
+        read_samples(pg, workout.id)?
+    } else {
+        Vec::new()
+    };
+    write_fit(w, workout, &points, &samples)
+}
+
+/// With `--workout`, write it to the output file or stdout. Otherwise write one
+/// file per selected workout into the output directory.
+fn export_workouts(
+    pg_url: &str,
+    target: &ExportTarget,
+    extension: &str,
+    write_workout: WriteWorkout,
+) -> Result<()> {
+    let mut pg = Client::connect(pg_url, NoTls).context("Connecting to PostgreSQL")?;
+    check_pg_schema(&mut pg)?;
+
+    let workouts = select_workouts(&mut pg, &target.selection.filter())?;
+
+    if let Some(workout_ref) = &target.selection.workout {
+        let Some(workout) = workouts.first() else {
+            bail!("no workout {workout_ref} in the database");
+        };
+        match &target.output {
+            Some(path) => write_file(path, |w| write_workout(w, &mut pg, workout))?,
+            None => {
+                let mut w = BufWriter::new(io::stdout().lock());
+                write_workout(&mut w, &mut pg, workout)?;
+                w.flush()?;
+            }
+        }
+        return Ok(());
+    }
+
+    let dir = target.output.clone().unwrap_or_else(|| PathBuf::from("."));
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    for workout in &workouts {
+        let path = dir.join(workout_file_name(workout, extension));
+        write_file(&path, |w| write_workout(w, &mut pg, workout))?;
+    }
+
+    tracing::info!(workouts = workouts.len(), dir = %dir.display(), "export done");
+    Ok(())
+}
+
+/// All selected workouts with a track, as one FeatureCollection.
+fn export_geojson(pg_url: &str, args: &GeoJsonExportArgs) -> Result<()> {
+    let mut pg = Client::connect(pg_url, NoTls).context("Connecting to PostgreSQL")?;
+    check_pg_schema(&mut pg)?;
+
+    let workouts = select_workouts(&mut pg, &args.filter())?;
+
+    let mut write = |w: &mut dyn Write| -> Result<()> {
+        let mut geojson = GeoJsonWriter::new(w, args.polyline)?;
+        for workout in &workouts {
+            let points = read_points(&mut pg, workout.id)?;
+            if points.is_empty() {
+                tracing::debug!(workout = workout.id, "no track points; skipped");
+                continue;
+            }
+            geojson.write_workout(workout, &points)?;
+        }
+        let features = geojson.finish()?;
+        tracing::info!(workouts = workouts.len(), features, "export done");
+        Ok(())
+    };
+
+    match &args.output {
+        Some(path) => write_file(path, write),
+        None => {
+            let mut w = BufWriter::new(io::stdout().lock());
+            write(&mut w)?;
+            w.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn write_file(path: &Path, f: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    f(&mut w).with_context(|| format!("writing {}", path.display()))?;
+    w.flush()
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+/// e.g. `2025-05-01T080000Z_outdoor_running_42.gpx`
+///
+/// Activities come from imported files too, so anything but a plain label
+/// (`[a-z0-9_]`) is written as `other` rather than joined into the path.
+fn workout_file_name(workout: &StoredWorkout, extension: &str) -> String {
+    let activity = workout.activity.as_str();
+    let activity = if !activity.is_empty()
+        && activity
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    {
+        activity
+    } else {
+        "other"
+    };
+    format!(
+        "{}_{activity}_{}.{extension}",
+        workout.start.format("%Y-%m-%dT%H%M%SZ"),
+        workout.id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn workout(activity: &str) -> StoredWorkout {
+        let start = DateTime::parse_from_rfc3339("2025-05-01T08:00:00Z")
+            .unwrap()
+            .to_utc();
+        StoredWorkout {
+            id: 42,
+            uuid: String::new(),
+            name: None,
+            activity: activity.to_owned(),
+            start,
+            end: start,
+            duration_s: 0,
+            summary_data_json: None,
+            source: String::new(),
+        }
+    }
+
+    #[test]
+    fn file_name_only_takes_plain_activity_labels() {
+        assert_eq!(
+            workout_file_name(&workout("outdoor_running"), "gpx"),
+            "2025-05-01T080000Z_outdoor_running_42.gpx"
+        );
+        for activity in ["../../etc/x", "a/b", "Running", ""] {
+            assert_eq!(
+                workout_file_name(&workout(activity), "tcx"),
+                "2025-05-01T080000Z_other_42.tcx",
+                "{activity}"
+            );
+        }
+    }
+}